@@ -0,0 +1,165 @@
+use std::{collections::HashMap, fmt::Write};
+
+use crate::{
+    conditioning::Mode,
+    midi::{Instrument, Tempo},
+    notation::{Event, Item, Notation, Part}
+};
+
+
+pub const ABC_CONTENT_TYPE: &str = "text/vnd.abc; charset=utf-8";
+
+// Bars written on one line of music
+const BARS_PER_LINE: usize = 4;
+// Below this average pitch a voice is written in the bass clef
+const BASS_CLEF_BELOW: f32 = 60.0;
+
+// Writes the laid out score as an ABC 2.1 tune with one voice per voice of every instrument.
+// Programs and the drum channel are kept as abcMIDI directives.
+pub fn encode(notation: &Notation) -> Vec<u8> {
+    let whole = notation.ticks_per_beat as u32 * 4;
+    let unit = if whole.is_multiple_of(16) { whole / 16 } else { 1 };
+
+    let mut out = String::new();
+    out.push_str("X:1\nT:Untitled\n");
+    if let Some(signature) = notation.measures.first().and_then(|m| m.signature) {
+        let _ = writeln!(out, "M:{}/{}", signature.numerator, signature.denominator);
+    }
+    let _ = writeln!(out, "L:1/{}", whole / unit);
+    let (initial, changes) = notation.tempos.split_at(notation.tempos.partition_point(|t| t.tick == 0));
+    if let Some(tempo) = initial.last() {
+        let _ = writeln!(out, "Q:1/4={}", tempo.bpm.round());
+    }
+    let _ = writeln!(out, "K:{}", key_name(notation));
+
+    let mut id = 0;
+    for part in &notation.parts {
+        let voices = part.measures.iter().map(Vec::len).max().unwrap_or(1);
+        for voice in 0..voices {
+            id += 1;
+            let clef = match (part.instrument, part.mean_pitch()) {
+                (Instrument::Drums, _) => "perc",
+                (_, Some(pitch)) if pitch < BASS_CLEF_BELOW => "bass",
+                _ => "treble",
+            };
+            let name = if voice == 0 { part.name.to_string() } else { format!("{} {}", part.name, voice + 1) };
+            let _ = writeln!(out, "V:{} name=\"{}\" clef={}", id, name, clef);
+            match part.instrument {
+                Instrument::Program(program) => { let _ = writeln!(out, "%%MIDI program {}", program); }
+                Instrument::Drums => out.push_str("%%MIDI channel 10\n"),
+            }
+            // tempo changes are written in the first voice
+            write_voice(&mut out, notation, part, voice, unit, if id == 1 { changes } else { &[] });
+        }
+    }
+    out.into_bytes()
+}
+
+fn write_voice(out: &mut String, notation: &Notation, part: &Part, voice: usize, unit: u32, tempos: &[Tempo]) {
+    let mut tempos = tempos.iter().peekable();
+    for (number, (measure, voices)) in notation.measures.iter().zip(&part.measures).enumerate() {
+        if let Some(signature) = measure.signature.filter(|_| number > 0) {
+            let _ = write!(out, "[M:{}/{}]", signature.numerator, signature.denominator);
+        }
+        // accidentals hold until the barline
+        let mut accidentals: HashMap<(char, i32), i8> = HashMap::new();
+        let mut tick = measure.start;
+        match voices.get(voice) {
+            Some(events) => {
+                for event in events {
+                    while let Some(tempo) = tempos.next_if(|t| t.tick <= tick) {
+                        let _ = write!(out, "[Q:1/4={}]", tempo.bpm.round());
+                    }
+                    write_event(out, notation, event, unit, &mut accidentals);
+                    tick += event.duration;
+                }
+            }
+            None => { let _ = write!(out, "x{}", length(measure.len, unit)); }
+        }
+        let last = number + 1 == notation.measures.len();
+        out.push_str(match (last, (number + 1) % BARS_PER_LINE == 0) {
+            (true, _) => " |]\n",
+            (false, true) => " |\n",
+            (false, false) => " | ",
+        });
+    }
+}
+
+fn write_event(out: &mut String, notation: &Notation, event: &Event, unit: u32, accidentals: &mut HashMap<(char, i32), i8>) {
+    let length = length(event.duration, unit);
+    let tones = match &event.item {
+        Item::Chord(tones) => tones,
+        Item::Rest => {
+            let _ = write!(out, "z{}", length);
+            return;
+        }
+        Item::Space => {
+            let _ = write!(out, "x{}", length);
+            return;
+        }
+    };
+    if tones.len() > 1 {
+        out.push('[');
+    }
+    for tone in tones {
+        let (step, alter, octave) = notation.spell(tone.pitch);
+        let in_effect = accidentals.get(&(step, octave)).copied().unwrap_or_else(|| notation.signature_alter(step));
+        if alter != in_effect {
+            out.push_str(match alter {
+                1 => "^",
+                -1 => "_",
+                _ => "=",
+            });
+            accidentals.insert((step, octave), alter);
+        }
+        // octave 4 is upper case, octave 5 lower case, further octaves add commas and apostrophes
+        if octave >= 5 {
+            out.push(step.to_ascii_lowercase());
+            out.extend(std::iter::repeat_n('\'', (octave - 5) as usize));
+        } else {
+            out.push(step);
+            out.extend(std::iter::repeat_n(',', (4 - octave).max(0) as usize));
+        }
+        out.push_str(&length);
+        if tone.tie_start {
+            out.push('-');
+        }
+    }
+    if tones.len() > 1 {
+        out.push(']');
+    }
+}
+
+// A duration in unit note lengths, empty for exactly one
+fn length(duration: u32, unit: u32) -> String {
+    let divisor = gcd(duration, unit);
+    match (duration / divisor, unit / divisor) {
+        (1, 1) => String::new(),
+        (n, 1) => n.to_string(),
+        (n, d) => format!("{}/{}", n, d),
+    }
+}
+
+fn gcd(a: u32, b: u32) -> u32 {
+    if b == 0 { a.max(1) } else { gcd(b, a % b) }
+}
+
+// The tonic spelled like the notes, then the mode, e.g. "Bbm" or "Ddor"
+fn key_name(notation: &Notation) -> String {
+    let (step, alter, _) = notation.spell(60 + notation.key.tonic);
+    let accidental = match alter {
+        1 => "#",
+        -1 => "b",
+        _ => "",
+    };
+    let mode = match notation.key.mode {
+        Mode::Major => "",
+        Mode::Minor => "m",
+        Mode::Dorian => "dor",
+        Mode::Phrygian => "phr",
+        Mode::Lydian => "lyd",
+        Mode::Mixolydian => "mix",
+        Mode::Locrian => "loc",
+    };
+    format!("{}{}{}", step, accidental, mode)
+}
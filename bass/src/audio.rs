@@ -0,0 +1,707 @@
+use std::{
+    borrow::Cow,
+    path::Path,
+    sync::{Arc, atomic::{AtomicUsize, Ordering}},
+    time::Instant
+};
+
+use anyhow::bail;
+use clap::Args;
+use futures::{Stream, StreamExt, future::{self, Either}, stream};
+use ort::{
+    session::{RunOptions, Session, SessionInputValue},
+    tensor::TensorElementType,
+    value::{DynValue, Tensor}
+};
+use rand::{SeedableRng, rngs::StdRng};
+use serde::Deserialize;
+use tokio::sync::{Mutex, MutexGuard};
+
+use crate::{
+    config::Config,
+    decoder::DecodeState,
+    diffusion::{Denoising, DiffusionSampler, NoiseSchedule},
+    dsp,
+    error::ApiError,
+    events::{Artifact, CandidateSummary, GenerationEvent, Timings},
+    flac,
+    format::OutputFormat,
+    manifest::{AudioSpec, DenoiserSpec, GeneratorKind, TextEncoderSpec},
+    mastering::Mastering,
+    model::{self, CancelGuard, Generation},
+    registry::{self, Model},
+    sampler::Sampler,
+    wav::{self, SampleFormat}
+};
+
+
+// Most denoising steps a request can ask for
+const MAX_STEPS: usize = 500;
+// Strongest classifier-free guidance a request can ask for
+const MAX_CFG_SCALE: f32 = 30.0;
+// Sample rates audio can be resampled to
+const SAMPLE_RATES: std::ops::RangeInclusive<u32> = 8_000..=192_000;
+
+#[derive(Deserialize, Args, Clone, Debug, Default)]
+#[serde(default)]
+pub struct AudioParams {
+    /// What the audio should not sound like, classifier-free guidance steers away from it
+    #[arg(long)]
+    pub negative_prompt: Option<String>,
+    /// Length of the audio in seconds, the model's default when missing
+    #[arg(long)]
+    pub duration_secs: Option<f32>,
+    /// Denoising steps of a diffusion model, the model's default when missing
+    #[arg(long)]
+    pub steps: Option<usize>,
+    /// Classifier-free guidance scale, 1.0 ignores the negative prompt
+    #[arg(long)]
+    pub cfg_scale: Option<f32>,
+    /// Sampler of a diffusion model, the model's default when missing
+    #[arg(long, value_enum)]
+    pub sampler: Option<DiffusionSampler>,
+    /// Sample format of WAV and FLAC output, FLAC takes only pcm16 and pcm24
+    #[arg(long, value_enum, default_value_t)]
+    pub sample_format: SampleFormat,
+    /// Sample rate of the audio, the model's when missing
+    #[arg(long)]
+    pub sample_rate: Option<u32>,
+}
+
+impl AudioParams {
+    // Whether the request sets none of the fields only audio models take. `negative_prompt` isn't
+    // one of them, the plugin sends it with every request and other models ignore it.
+    fn is_empty(&self) -> bool {
+        self.duration_secs.is_none()
+            && self.steps.is_none()
+            && self.cfg_scale.is_none()
+            && self.sampler.is_none()
+            && self.sample_rate.is_none()
+    }
+}
+
+// A validated audio request
+pub struct AudioRequest {
+    pub generator: GeneratorKind,
+    pub prompt: String,
+    pub negative_prompt: String,
+    // the negative prompt's tokens, the unconditional sequence of an autoregressive generator
+    pub uncond_tokens: Vec<i64>,
+    // latent frames or codes to generate
+    pub frames: usize,
+    // frames of the waveform, which is cut or padded to the requested duration
+    pub samples: usize,
+    pub steps: usize,
+    pub sampler: DiffusionSampler,
+    pub guidance_scale: f32,
+    // the model's, the audio is resampled to `output_sample_rate`
+    pub sample_rate: u32,
+    pub output_sample_rate: u32,
+    pub channels: u16,
+    pub format: OutputFormat,
+    pub sample_format: SampleFormat,
+}
+
+// Validates the audio fields of a request against the model. `prompt_tokens` is the length of the
+// tokenized prompt, the conditional sequence of an autoregressive generator.
+pub fn request(
+    model: &Model,
+    params: AudioParams,
+    format: OutputFormat,
+    prompt: &str,
+    prompt_tokens: usize
+) -> Result<Option<AudioRequest>, ApiError> {
+    let Some(spec) = &model.manifest.audio else {
+        if params.is_empty() {
+            if params.negative_prompt.is_some() {
+                tracing::debug!("model {} does not generate audio, ignoring negative_prompt", model.name);
+            }
+            return Ok(None);
+        }
+        return Err(ApiError::InvalidRequest(format!(
+            "model {} does not generate audio, duration_secs, steps, cfg_scale, sampler and sample_rate need an audio model",
+            model.name
+        )));
+    };
+
+    let duration = params.duration_secs.unwrap_or(spec.default_duration_secs);
+    if !(duration > 0.0 && duration <= spec.max_duration_secs) {
+        return Err(ApiError::InvalidRequest(format!("duration_secs must be above 0 and at most {}", spec.max_duration_secs)));
+    }
+    let guidance_scale = guidance_scale(params.cfg_scale, spec.guidance_scale)?;
+    let steps = denoising_steps(&model.name, spec.denoiser.as_ref(), params.steps, spec.steps)?;
+    let output_sample_rate = params.sample_rate.unwrap_or(spec.sample_rate);
+    if !SAMPLE_RATES.contains(&output_sample_rate) {
+        return Err(ApiError::InvalidRequest(format!(
+            "sample_rate must be between {} and {}", SAMPLE_RATES.start(), SAMPLE_RATES.end()
+        )));
+    }
+    if format == OutputFormat::Flac && params.sample_format == SampleFormat::Float32 {
+        return Err(ApiError::InvalidRequest(String::from("flac output takes sample_format pcm16 or pcm24")));
+    }
+    if params.sampler.is_some() && spec.generator != GeneratorKind::Diffusion {
+        return Err(ApiError::InvalidRequest(format!("model {} is not a diffusion model, it takes no sampler", model.name)));
+    }
+
+    let negative_prompt = params.negative_prompt.unwrap_or_default();
+    let uncond_tokens = match spec.generator {
+        GeneratorKind::Diffusion => Vec::new(),
+        GeneratorKind::Autoregressive => model::encode_prompt(&model.tokenizer, &model.manifest, &negative_prompt)
+            .map_err(|e| ApiError::Internal(format!("{:#}", e)))?,
+    };
+
+    let frames = (duration * spec.frames_per_second).ceil() as usize;
+    // the whole duration has to fit, the codes can't be cut short like text
+    if spec.generator == GeneratorKind::Autoregressive
+        && let Some(context_length) = model.manifest.context_length {
+        let tokens = prompt_tokens.max(uncond_tokens.len()) + frames;
+        if tokens > context_length {
+            return Err(ApiError::ContextOverflow { tokens, context_length });
+        }
+    }
+
+    Ok(Some(AudioRequest {
+        generator: spec.generator,
+        prompt: prompt.to_string(),
+        negative_prompt,
+        uncond_tokens,
+        frames,
+        samples: (duration * spec.sample_rate as f32).round() as usize,
+        steps,
+        sampler: params.sampler.unwrap_or(spec.sampler),
+        guidance_scale,
+        sample_rate: spec.sample_rate,
+        output_sample_rate,
+        channels: spec.channels,
+        format,
+        sample_format: params.sample_format,
+    }))
+}
+
+fn guidance_scale(requested: Option<f32>, default: f32) -> Result<f32, ApiError> {
+    let scale = requested.unwrap_or(default);
+    if !(1.0..=MAX_CFG_SCALE).contains(&scale) {
+        return Err(ApiError::InvalidRequest(format!("cfg_scale must be between 1.0 and {}", MAX_CFG_SCALE)));
+    }
+    Ok(scale)
+}
+
+// Only a diffusion model takes steps, at most as many as it was trained on
+fn denoising_steps(model: &str, denoiser: Option<&DenoiserSpec>, requested: Option<usize>, default: usize) -> Result<usize, ApiError> {
+    match (denoiser, requested) {
+        (None, Some(_)) => Err(ApiError::InvalidRequest(format!("model {} has no denoising steps", model))),
+        (Some(denoiser), Some(steps)) if steps == 0 || steps > MAX_STEPS.min(denoiser.train_timesteps) => {
+            Err(ApiError::InvalidRequest(format!("steps must be between 1 and {}", MAX_STEPS.min(denoiser.train_timesteps))))
+        }
+        (_, steps) => Ok(steps.unwrap_or(default)),
+    }
+}
+
+// The sessions of one stage, taken in turn by the requests running through it
+struct Stage {
+    sessions: Vec<Mutex<Session>>,
+    next: AtomicUsize,
+}
+
+impl Stage {
+    fn new(sessions: Vec<Session>) -> Self {
+        Self { sessions: sessions.into_iter().map(Mutex::new).collect(), next: AtomicUsize::new(0) }
+    }
+
+    // Loads the stage's graph and checks it has the named inputs and output
+    fn load<'a>(path: &Path, config: &Config, inputs: impl IntoIterator<Item = &'a String>, output: &str) -> anyhow::Result<Self> {
+        let sessions = registry::load_sessions(path, config)?;
+        let session = &sessions[0];
+        for name in inputs {
+            if !session.inputs.iter().any(|i| &i.name == name) {
+                bail!("{} has no input named {}", path.display(), name);
+            }
+        }
+        if !session.outputs.iter().any(|o| o.name == output) {
+            bail!("{} has no output named {}", path.display(), output);
+        }
+        Ok(Self::new(sessions))
+    }
+
+    async fn session(&self) -> MutexGuard<'_, Session> {
+        let i = self.next.fetch_add(1, Ordering::Relaxed) % self.sessions.len();
+        self.sessions[i].lock().await
+    }
+}
+
+// Hidden states of the text encoder for one prompt, with their attention mask
+struct Encoded {
+    dims: Vec<i64>,
+    hidden: Vec<f32>,
+    mask: Vec<i64>,
+}
+
+// The graphs of an audio model besides its token decoder. An autoregressive generator runs on
+// the model's decoder and only needs the vocoder from here.
+pub struct AudioPipeline {
+    text_encoder: Option<Stage>,
+    denoiser: Option<Stage>,
+    vocoder: Stage,
+    // the denoiser takes int64 timesteps rather than float32 ones
+    integer_timesteps: bool,
+    // prompt and negative prompt can share a denoiser run
+    batchable: bool,
+}
+
+impl AudioPipeline {
+    // Loads the stages next to the model file. `denoiser` holds the model file's sessions for a
+    // diffusion model and is empty otherwise.
+    pub fn load(model_path: &Path, spec: &AudioSpec, denoiser: Vec<Session>, config: &Config) -> anyhow::Result<Self> {
+        let dir = model_path.parent().unwrap_or(Path::new(""));
+        let text_encoder = match &spec.text_encoder {
+            Some(encoder) => {
+                let inputs = [&encoder.input_ids].into_iter().chain(&encoder.attention_mask);
+                Some(Stage::load(&dir.join(&encoder.model), config, inputs, &encoder.output)?)
+            }
+            None => None,
+        };
+
+        let vocoder_path = dir.join(&spec.vocoder.model);
+        let mut vocoder = Stage::load(&vocoder_path, config, [&spec.vocoder.input], &spec.vocoder.output)?;
+        let expected = match spec.generator {
+            GeneratorKind::Diffusion => TensorElementType::Float32,
+            GeneratorKind::Autoregressive => TensorElementType::Int64,
+        };
+        let input = vocoder.sessions[0].get_mut().inputs.iter().find(|i| i.name == spec.vocoder.input)
+            .and_then(|i| i.input_type.tensor_type());
+        if input != Some(expected) {
+            bail!("vocoder input {} must be a {} tensor after a {:?} generator", spec.vocoder.input, expected, spec.generator);
+        }
+
+        let input = |name: &str| denoiser.first().and_then(|s| s.inputs.iter().find(|i| i.name == name));
+        let (integer_timesteps, batchable) = match &spec.denoiser {
+            Some(spec) => (
+                input(&spec.timestep).and_then(|i| i.input_type.tensor_type()) == Some(TensorElementType::Int64),
+                input(&spec.sample).and_then(|i| i.input_type.tensor_shape()).and_then(|s| s.first().copied()) == Some(-1),
+            ),
+            None => (false, false),
+        };
+        let denoiser = (!denoiser.is_empty()).then(|| Stage::new(denoiser));
+
+        Ok(Self { text_encoder, denoiser, vocoder, integer_timesteps, batchable })
+    }
+
+    // Pads or cuts the text to the encoder's length and runs it through the encoder
+    async fn encode_text(&self, model: &Model, spec: &TextEncoderSpec, text: &str) -> ort::Result<Encoded> {
+        let Some(stage) = &self.text_encoder else {
+            return Err(ort::Error::new("model has no text encoder"));
+        };
+        let encoding = model.tokenizer.encode(text, true)
+            .map_err(|e| ort::Error::new(format!("tokenizer error: {}", e)))?;
+        let mut ids: Vec<i64> = encoding.get_ids().iter().take(spec.max_length).map(|&id| id as i64).collect();
+        let mut mask = vec![1i64; ids.len()];
+        ids.resize(spec.max_length, model.manifest.tokens.pad.unwrap_or_default() as i64);
+        mask.resize(spec.max_length, 0);
+
+        let shape = vec![1, spec.max_length as i64];
+        let mut inputs: Vec<(Cow<'_, str>, SessionInputValue<'_>)> = Vec::new();
+        inputs.push((spec.input_ids.as_str().into(), Tensor::from_array((shape.clone(), ids))?.into()));
+        if let Some(name) = &spec.attention_mask {
+            inputs.push((name.as_str().into(), Tensor::from_array((shape, mask.clone()))?.into()));
+        }
+
+        let mut session = stage.session().await;
+        let options = RunOptions::new()?;
+        let outputs = session.run_async(inputs, &options)?.await?;
+        let (dims, data) = outputs[spec.output.as_str()].try_extract_tensor::<f32>()?;
+        Ok(Encoded { dims: dims.to_vec(), hidden: data.to_vec(), mask })
+    }
+
+    // The denoiser's output for `latents` at timestep `t`, guided towards the first text and away
+    // from the second when there are two
+    async fn predict(
+        &self,
+        spec: &DenoiserSpec,
+        latents: &[f32],
+        shape: &[i64],
+        t: usize,
+        texts: &[Encoded],
+        guidance_scale: f32
+    ) -> ort::Result<Vec<f32>> {
+        let mut outputs = if self.batchable && texts.len() > 1 {
+            let joined = self.run_denoiser(spec, &latents.repeat(texts.len()), shape, t, texts).await?;
+            joined.chunks(latents.len()).map(<[f32]>::to_vec).collect()
+        } else {
+            let mut outputs = Vec::with_capacity(texts.len());
+            for text in texts {
+                outputs.push(self.run_denoiser(spec, latents, shape, t, std::slice::from_ref(text)).await?);
+            }
+            outputs
+        };
+
+        let mut output = outputs.remove(0);
+        if let Some(uncond) = outputs.first() {
+            guide(&mut output, uncond, guidance_scale);
+        }
+        Ok(output)
+    }
+
+    // One denoiser run with a batch row per text
+    async fn run_denoiser(&self, spec: &DenoiserSpec, latents: &[f32], shape: &[i64], t: usize, texts: &[Encoded]) -> ort::Result<Vec<f32>> {
+        let Some(stage) = &self.denoiser else {
+            return Err(ort::Error::new("model has no denoiser"));
+        };
+        let batch = texts.len();
+        let mut sample_shape = shape.to_vec();
+        sample_shape[0] = batch as i64;
+        let mut hidden_shape = texts[0].dims.clone();
+        hidden_shape[0] = batch as i64;
+        let hidden: Vec<f32> = texts.iter().flat_map(|e| e.hidden.iter().copied()).collect();
+        let timestep = if self.integer_timesteps {
+            Tensor::from_array((vec![batch as i64], vec![t as i64; batch]))?.into_dyn()
+        } else {
+            Tensor::from_array((vec![batch as i64], vec![t as f32; batch]))?.into_dyn()
+        };
+
+        let mut inputs: Vec<(Cow<'_, str>, SessionInputValue<'_>)> = Vec::new();
+        inputs.push((spec.sample.as_str().into(), Tensor::from_array((sample_shape, latents.to_vec()))?.into()));
+        inputs.push((spec.timestep.as_str().into(), timestep.into()));
+        inputs.push((spec.encoder_hidden_states.as_str().into(), Tensor::from_array((hidden_shape, hidden))?.into()));
+        if let Some(name) = &spec.encoder_attention_mask {
+            let mask: Vec<i64> = texts.iter().flat_map(|e| e.mask.iter().copied()).collect();
+            let shape = vec![batch as i64, texts[0].mask.len() as i64];
+            inputs.push((name.as_str().into(), Tensor::from_array((shape, mask))?.into()));
+        }
+
+        let mut session = stage.session().await;
+        let options = RunOptions::new()?;
+        let outputs = session.run_async(inputs, &options)?.await?;
+        let (_, data) = outputs[spec.output.as_str()].try_extract_tensor::<f32>()?;
+        Ok(data.to_vec())
+    }
+
+    // Renders latents or codes to interleaved samples, cut or padded to `frames` frames
+    async fn vocode(&self, spec: &AudioSpec, input: DynValue, frames: usize) -> ort::Result<Vec<f32>> {
+        let inputs: Vec<(Cow<'_, str>, SessionInputValue<'_>)> = vec![(spec.vocoder.input.as_str().into(), input.into())];
+        let mut session = self.vocoder.session().await;
+        let options = RunOptions::new()?;
+        let outputs = session.run_async(inputs, &options)?.await?;
+        let (dims, data) = outputs[spec.vocoder.output.as_str()].try_extract_tensor::<f32>()?;
+
+        // the vocoder writes each channel after the other, WAV interleaves them
+        let channels = spec.channels as usize;
+        let length = dims.last().copied().unwrap_or_default() as usize;
+        if data.len() < channels * length {
+            return Err(ort::Error::new(format!("vocoder output {:?} has fewer than {} channels", dims, channels)));
+        }
+        let mut samples = vec![0.0; frames * channels];
+        for channel in 0..channels {
+            for i in 0..length.min(frames) {
+                samples[i * channels + channel] = data[channel * length + i];
+            }
+        }
+        Ok(samples)
+    }
+}
+
+// Classifier-free guidance, pushes the conditional output away from the unconditional one
+fn guide(cond: &mut [f32], uncond: &[f32], scale: f32) {
+    for (c, &u) in cond.iter_mut().zip(uncond) {
+        *c = u + scale * (*c - u);
+    }
+}
+
+// What a candidate reports as its generation goes
+enum Step {
+    // a denoising step from this timestep finished
+    Denoised { timestep: usize },
+    // the generator picked a code
+    Code { id: i64, logprob: f32 },
+    // the rendered waveform, interleaved
+    Audio(Vec<f32>),
+}
+
+// Denoises latents from seeded noise, guided by the prompt and away from the negative prompt,
+// then renders them
+fn diffusion_stream(model: Arc<Model>, request: Arc<AudioRequest>, seed: u64) -> impl Stream<Item = ort::Result<Step>> + Send {
+    async_stream_lite::try_async_stream(|yielder| async move {
+        let (Some(spec), Some(pipeline)) = (&model.manifest.audio, &model.audio) else {
+            return Err(ort::Error::new("model has no audio pipeline"));
+        };
+        let (Some(encoder), Some(denoiser)) = (&spec.text_encoder, &spec.denoiser) else {
+            return Err(ort::Error::new("model has no text encoder or denoiser"));
+        };
+        tracing::info!("generating audio with model {}, {:?} sampler and seed {}", model.name, request.sampler, seed);
+
+        let mut texts = vec![pipeline.encode_text(&model, encoder, &request.prompt).await?];
+        if request.guidance_scale > 1.0 {
+            texts.push(pipeline.encode_text(&model, encoder, &request.negative_prompt).await?);
+        }
+
+        let shape = vec![1, spec.latent_channels as i64, request.frames as i64];
+        let rng = StdRng::seed_from_u64(seed);
+        let len = spec.latent_channels * request.frames;
+        let mut denoising = Denoising::new(NoiseSchedule::new(denoiser), request.sampler, request.steps, len, rng);
+        while let Some(timestep) = denoising.timestep() {
+            let input = denoising.model_input();
+            let output = pipeline.predict(denoiser, &input, &shape, timestep, &texts, request.guidance_scale).await?;
+            denoising.step(&output);
+            yielder.r#yield(Step::Denoised { timestep }).await;
+        }
+
+        let mut latents = denoising.into_latents();
+        for x in &mut latents {
+            *x /= denoiser.scaling_factor;
+        }
+        let latents = Tensor::from_array((shape, latents))?.into_dyn();
+        let samples = pipeline.vocode(spec, latents, request.samples).await?;
+        yielder.r#yield(Step::Audio(samples)).await;
+        Ok(())
+    })
+}
+
+// Samples codes on the model's decoder, each step guided by the prompt's sequence and away from
+// the negative prompt's, then renders them
+fn autoregressive_stream(
+    model: Arc<Model>,
+    request: Arc<AudioRequest>,
+    tokens: Vec<i64>,
+    mut sampler: Sampler
+) -> impl Stream<Item = ort::Result<Step>> + Send {
+    async_stream_lite::try_async_stream(|yielder| async move {
+        let (Some(spec), Some(pipeline), Some(decoder)) = (&model.manifest.audio, &model.audio, &model.decoder) else {
+            return Err(ort::Error::new("model has no audio pipeline"));
+        };
+        tracing::info!("generating audio with model {} and seed {}", model.name, sampler.seed());
+        let stop_tokens: Vec<i64> = model.manifest.tokens.eos.iter().map(|&id| id as i64).collect();
+
+        let mut cond = DecodeState::new(tokens);
+        let mut uncond = (request.guidance_scale > 1.0).then(|| DecodeState::new(request.uncond_tokens.clone()));
+        let mut codes = Vec::with_capacity(request.frames);
+        while codes.len() < request.frames {
+            // both sequences step together, so the scheduler can batch them when the graph pads
+            // rows of different lengths
+            let (next, logits) = match uncond.take() {
+                Some(state) => {
+                    let (c, u) = future::join(decoder.scheduler.step(cond), decoder.scheduler.step(state)).await;
+                    let ((next, mut logits), (next_uncond, uncond_logits)) = (c?, u?);
+                    guide(&mut logits, &uncond_logits, request.guidance_scale);
+                    uncond = Some(next_uncond);
+                    (next, logits)
+                }
+                None => decoder.scheduler.step(cond).await?,
+            };
+            cond = next;
+
+            let Some((token, logprob)) = sampler.sample(&logits, &cond.tokens) else {
+                return Err(ort::Error::new(format!("no code is left to sample after {} codes", codes.len())));
+            };
+            if stop_tokens.contains(&token) {
+                tracing::debug!("stop token {} reached after {} codes", token, codes.len());
+                break;
+            }
+            cond.tokens.push(token);
+            if let Some(state) = uncond.as_mut() {
+                state.tokens.push(token);
+            }
+            codes.push(token - spec.vocoder.code_offset);
+            yielder.r#yield(Step::Code { id: token, logprob }).await;
+        }
+
+        if codes.is_empty() {
+            return Err(ort::Error::new("stop token sampled before any audio codes"));
+        }
+        let codes = Tensor::from_array((vec![1, codes.len() as i64], codes))?.into_dyn();
+        let samples = pipeline.vocode(spec, codes, request.samples).await?;
+        yielder.r#yield(Step::Audio(samples)).await;
+        Ok(())
+    })
+}
+
+// Encodes a candidate's waveform at the requested sample rate in the requested format, mastered
+// at that rate when the request asked for it. Runs on the blocking pool, resampling, limiting and
+// encoding a long clip take seconds.
+async fn render(request: Arc<AudioRequest>, mastering: Option<Mastering>, samples: Vec<f32>) -> anyhow::Result<Artifact> {
+    tokio::task::spawn_blocking(move || {
+        let mut samples = dsp::resample(&samples, request.channels, request.sample_rate, request.output_sample_rate);
+        if let Some(mastering) = &mastering {
+            samples = mastering.apply(samples, request.channels, request.output_sample_rate);
+        }
+        let bytes = match request.format {
+            OutputFormat::Wav => wav::encode(&samples, request.channels, request.output_sample_rate, request.sample_format),
+            OutputFormat::Flac => flac::encode(&samples, request.channels, request.output_sample_rate, request.sample_format)?,
+            format => bail!("audio can't be rendered as {:?}", format),
+        };
+        Ok(Artifact::binary(request.format.content_type(), &bytes))
+    }).await?
+}
+
+// The event protocol of a token generation, for audio: `started`, `denoise` and `progress` per
+// denoising step or `token` and `progress` per code, each candidate's WAV or FLAC `result` or
+// `error`, then `summary` and `done`
+pub fn events(request_id: String, generation: Generation, request: AudioRequest) -> impl Stream<Item = GenerationEvent> + Send {
+    let Generation { model, tokens, samplers, mastering, .. } = generation;
+    async_stream_lite::async_stream(|yielder| async move {
+        let start = Instant::now();
+        let prompt_tokens = tokens.len();
+        let seeds: Vec<u64> = samplers.iter().map(Sampler::seed).collect();
+        tracing::info!("request {} started with {} audio candidates", request_id, seeds.len());
+        let mut guard = CancelGuard { request_id: request_id.clone(), step: 0, finished: false };
+        yielder.r#yield(GenerationEvent::Started {
+            request_id: request_id.clone(),
+            model: model.name.clone(),
+            seed: seeds.first().copied().unwrap_or_default(),
+        }).await;
+
+        let diffusion = request.generator == GeneratorKind::Diffusion;
+        let request = Arc::new(request);
+        let streams = samplers.into_iter().enumerate().map(|(candidate, sampler)| {
+            let stream = if diffusion {
+                Either::Left(diffusion_stream(Arc::clone(&model), Arc::clone(&request), sampler.seed()))
+            } else {
+                Either::Right(autoregressive_stream(Arc::clone(&model), Arc::clone(&request), tokens.clone(), sampler))
+            };
+            Box::pin(stream.map(move |item| (candidate, item)))
+        });
+        let mut stream = stream::select_all(streams);
+
+        let total_steps = if diffusion { request.steps } else { request.frames } * seeds.len();
+        let mut generated = vec![0usize; seeds.len()];
+        let mut denoised = vec![0usize; seeds.len()];
+        let mut logprobs = vec![0.0f32; seeds.len()];
+        let mut failed = vec![false; seeds.len()];
+        let mut steps = 0;
+        let mut first_step = None;
+        while let Some((candidate, item)) = stream.next().await {
+            let event = match item {
+                Ok(Step::Code { id, logprob }) => {
+                    let step = generated[candidate];
+                    generated[candidate] += 1;
+                    logprobs[candidate] += logprob;
+                    let text = model.tokenizer.decode(&[id as u32], true).unwrap_or_default();
+                    GenerationEvent::Token { candidate, id, text, step, logprob }
+                }
+                Ok(Step::Denoised { timestep }) => {
+                    denoised[candidate] += 1;
+                    GenerationEvent::Denoise { candidate, step: denoised[candidate], steps: request.steps, timestep }
+                }
+                Ok(Step::Audio(samples)) => {
+                    match render(Arc::clone(&request), mastering.clone(), samples).await {
+                        Ok(artifact) => yielder.r#yield(GenerationEvent::Result { candidate, artifact, preview: None }).await,
+                        Err(e) => {
+                            tracing::error!("request {} candidate {} failed: {:#}", request_id, candidate, e);
+                            failed[candidate] = true;
+                            yielder.r#yield(GenerationEvent::Error {
+                                candidate: Some(candidate),
+                                code: "decode_failed",
+                                message: format!("{:#}", e),
+                            }).await;
+                        }
+                    }
+                    continue;
+                }
+                Err(e) => {
+                    tracing::error!("request {} candidate {} failed: {}", request_id, candidate, e);
+                    failed[candidate] = true;
+                    yielder.r#yield(GenerationEvent::Error {
+                        candidate: Some(candidate),
+                        code: "inference_failed",
+                        message: e.to_string(),
+                    }).await;
+                    continue;
+                }
+            };
+
+            first_step.get_or_insert_with(|| start.elapsed());
+            yielder.r#yield(event).await;
+            steps += 1;
+            guard.step = steps;
+            yielder.r#yield(GenerationEvent::Progress {
+                fraction: steps as f32 / total_steps as f32,
+            }).await;
+        }
+
+        let candidates = generated.iter().zip(&logprobs).zip(&failed).zip(&seeds).enumerate()
+            .map(|(candidate, (((&tokens, &logprob), &failed), &seed))| CandidateSummary {
+                candidate,
+                seed,
+                generated_tokens: tokens,
+                mean_logprob: (tokens > 0).then(|| logprob / tokens as f32),
+                ok: !failed,
+            })
+            .collect();
+        yielder.r#yield(GenerationEvent::Summary { candidates }).await;
+
+        let elapsed = start.elapsed();
+        let generated_tokens = generated.iter().sum();
+        let timings = Timings {
+            prompt_tokens,
+            generated_tokens,
+            elapsed_ms: elapsed.as_millis() as u64,
+            time_to_first_token_ms: first_step.map(|t| t.as_millis() as u64),
+            tokens_per_second: generated_tokens as f32 / elapsed.as_secs_f32().max(f32::EPSILON),
+        };
+        guard.finished = true;
+        tracing::info!("request {} done, {} steps in {} ms", request_id, steps, timings.elapsed_ms);
+        yielder.r#yield(GenerationEvent::Done { timings }).await;
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{format::OutputFormat, model::PromptRequest};
+
+    use super::*;
+
+    #[test]
+    fn plugin_requests_take_no_audio_fields() {
+        // what treble's agent posts to /generate
+        let body: PromptRequest = serde_json::from_str(r#"{
+            "prompt": "a calm piano melody",
+            "negative_prompt": "Low quality, average quality",
+            "client_output_path": "",
+            "output_format": "midi",
+            "stream": false
+        }"#).unwrap();
+        assert_eq!(body.output_format, Some(OutputFormat::Midi));
+        assert_eq!(body.audio.negative_prompt.as_deref(), Some("Low quality, average quality"));
+        assert!(body.audio.is_empty());
+    }
+
+    #[test]
+    fn guidance_scales_outside_the_range_are_rejected() {
+        assert_eq!(guidance_scale(None, 3.0).unwrap(), 3.0);
+        assert_eq!(guidance_scale(Some(1.0), 3.0).unwrap(), 1.0);
+        assert_eq!(guidance_scale(Some(MAX_CFG_SCALE), 3.0).unwrap(), MAX_CFG_SCALE);
+        for scale in [0.5, MAX_CFG_SCALE + 0.1, f32::NAN] {
+            assert!(matches!(guidance_scale(Some(scale), 3.0), Err(ApiError::InvalidRequest(_))));
+        }
+    }
+
+    #[test]
+    fn steps_outside_the_range_are_rejected() {
+        let mut denoiser: DenoiserSpec = toml::from_str(r#"
+            sample = "sample"
+            timestep = "timestep"
+            encoder_hidden_states = "encoder_hidden_states"
+            output = "output"
+        "#).unwrap();
+        assert_eq!(denoising_steps("model", Some(&denoiser), None, 50).unwrap(), 50);
+        assert_eq!(denoising_steps("model", Some(&denoiser), Some(MAX_STEPS), 50).unwrap(), MAX_STEPS);
+        for steps in [0, MAX_STEPS + 1] {
+            assert!(matches!(denoising_steps("model", Some(&denoiser), Some(steps), 50), Err(ApiError::InvalidRequest(_))));
+        }
+        // fewer training timesteps than the cap
+        denoiser.train_timesteps = 100;
+        assert!(denoising_steps("model", Some(&denoiser), Some(101), 50).is_err());
+        // autoregressive models take none
+        assert_eq!(denoising_steps("model", None, None, 0).unwrap(), 0);
+        assert!(denoising_steps("model", None, Some(10), 0).is_err());
+    }
+
+    #[test]
+    fn guidance_pushes_away_from_the_unconditional_output() {
+        let mut cond = [1.0, -2.0, 0.5];
+        guide(&mut cond, &[0.5, 0.0, 0.5], 3.0);
+        assert_eq!(cond, [2.0, -6.0, 0.5]);
+        // a scale of 1 leaves the conditional output as it is
+        let mut cond = [1.0, -2.0];
+        guide(&mut cond, &[4.0, 4.0], 1.0);
+        assert_eq!(cond, [1.0, -2.0]);
+    }
+}
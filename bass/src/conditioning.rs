@@ -0,0 +1,630 @@
+use std::fmt;
+
+use clap::Args;
+use serde::Deserialize;
+use tokenizers::Tokenizer;
+
+use crate::{
+    error::ApiError,
+    manifest::ConditioningSpec,
+    midi::Instrument,
+    music::DRUM_PROGRAM,
+    registry::Model
+};
+
+
+const MIN_BPM: f64 = 20.0;
+const MAX_BPM: f64 = 400.0;
+const MAX_BARS: u32 = 256;
+const MAX_GENRES: usize = 8;
+
+const PITCH_CLASSES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+
+// Krumhansl-Kessler key profiles, how well each pitch class above the tonic fits a major and a
+// minor key
+const MAJOR_PROFILE: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+// General MIDI level 1 instrument names, by program number
+pub const GM_PROGRAMS: [&str; 128] = [
+    "Acoustic Grand Piano", "Bright Acoustic Piano", "Electric Grand Piano", "Honky-tonk Piano",
+    "Electric Piano 1", "Electric Piano 2", "Harpsichord", "Clavinet",
+    "Celesta", "Glockenspiel", "Music Box", "Vibraphone",
+    "Marimba", "Xylophone", "Tubular Bells", "Dulcimer",
+    "Drawbar Organ", "Percussive Organ", "Rock Organ", "Church Organ",
+    "Reed Organ", "Accordion", "Harmonica", "Tango Accordion",
+    "Acoustic Guitar (nylon)", "Acoustic Guitar (steel)", "Electric Guitar (jazz)", "Electric Guitar (clean)",
+    "Electric Guitar (muted)", "Overdriven Guitar", "Distortion Guitar", "Guitar Harmonics",
+    "Acoustic Bass", "Electric Bass (finger)", "Electric Bass (pick)", "Fretless Bass",
+    "Slap Bass 1", "Slap Bass 2", "Synth Bass 1", "Synth Bass 2",
+    "Violin", "Viola", "Cello", "Contrabass",
+    "Tremolo Strings", "Pizzicato Strings", "Orchestral Harp", "Timpani",
+    "String Ensemble 1", "String Ensemble 2", "Synth Strings 1", "Synth Strings 2",
+    "Choir Aahs", "Voice Oohs", "Synth Voice", "Orchestra Hit",
+    "Trumpet", "Trombone", "Tuba", "Muted Trumpet",
+    "French Horn", "Brass Section", "Synth Brass 1", "Synth Brass 2",
+    "Soprano Sax", "Alto Sax", "Tenor Sax", "Baritone Sax",
+    "Oboe", "English Horn", "Bassoon", "Clarinet",
+    "Piccolo", "Flute", "Recorder", "Pan Flute",
+    "Blown Bottle", "Shakuhachi", "Whistle", "Ocarina",
+    "Lead 1 (square)", "Lead 2 (sawtooth)", "Lead 3 (calliope)", "Lead 4 (chiff)",
+    "Lead 5 (charang)", "Lead 6 (voice)", "Lead 7 (fifths)", "Lead 8 (bass + lead)",
+    "Pad 1 (new age)", "Pad 2 (warm)", "Pad 3 (polysynth)", "Pad 4 (choir)",
+    "Pad 5 (bowed)", "Pad 6 (metallic)", "Pad 7 (halo)", "Pad 8 (sweep)",
+    "FX 1 (rain)", "FX 2 (soundtrack)", "FX 3 (crystal)", "FX 4 (atmosphere)",
+    "FX 5 (brightness)", "FX 6 (goblins)", "FX 7 (echoes)", "FX 8 (sci-fi)",
+    "Sitar", "Banjo", "Shamisen", "Koto",
+    "Kalimba", "Bagpipe", "Fiddle", "Shanai",
+    "Tinkle Bell", "Agogo", "Steel Drums", "Woodblock",
+    "Taiko Drum", "Melodic Tom", "Synth Drum", "Reverse Cymbal",
+    "Guitar Fret Noise", "Breath Noise", "Seashore", "Bird Tweet",
+    "Telephone Ring", "Helicopter", "Applause", "Gunshot",
+];
+
+// Musical properties a request can ask for, as sent by the client
+#[derive(Deserialize, Args, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ConditioningFields {
+    /// Key, e.g. "D minor", "F#" or "Bb dorian"
+    #[arg(long)]
+    pub key: Option<String>,
+    /// Mode of the key when the key doesn't name one, e.g. "minor"
+    #[arg(long)]
+    pub mode: Option<String>,
+    /// Tempo in beats per minute
+    #[arg(long)]
+    pub bpm: Option<f64>,
+    /// Time signature, e.g. "3/4"
+    #[arg(long)]
+    pub time_signature: Option<String>,
+    /// Length in bars
+    #[arg(long)]
+    pub bars: Option<u32>,
+    /// General MIDI program number, 0 to 127
+    #[arg(long)]
+    pub program: Option<u8>,
+    /// General MIDI instrument name, or "drums"
+    #[arg(long)]
+    pub instrument: Option<String>,
+    /// Genre tag, can be given several times
+    #[arg(long = "genre")]
+    pub genres: Vec<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    Major,
+    Minor,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    Locrian,
+}
+
+impl Mode {
+    fn parse(name: &str) -> Option<Self> {
+        // "M" and "m" are the only case sensitive spellings
+        match name {
+            "M" => return Some(Self::Major),
+            "m" => return Some(Self::Minor),
+            _ => {}
+        }
+        let mode = match name.to_ascii_lowercase().as_str() {
+            "major" | "maj" | "ionian" => Self::Major,
+            "minor" | "min" | "aeolian" => Self::Minor,
+            "dorian" => Self::Dorian,
+            "phrygian" => Self::Phrygian,
+            "lydian" => Self::Lydian,
+            "mixolydian" => Self::Mixolydian,
+            "locrian" => Self::Locrian,
+            _ => return None,
+        };
+        Some(mode)
+    }
+
+    // semitones of the scale's degrees above the tonic
+    fn intervals(self) -> [u8; 7] {
+        match self {
+            Self::Major => [0, 2, 4, 5, 7, 9, 11],
+            Self::Minor => [0, 2, 3, 5, 7, 8, 10],
+            Self::Dorian => [0, 2, 3, 5, 7, 9, 10],
+            Self::Phrygian => [0, 1, 3, 5, 7, 8, 10],
+            Self::Lydian => [0, 2, 4, 6, 7, 9, 11],
+            Self::Mixolydian => [0, 2, 4, 5, 7, 9, 10],
+            Self::Locrian => [0, 1, 3, 5, 6, 8, 10],
+        }
+    }
+
+    // semitones from the tonic of the major scale with the same notes up to this mode's tonic
+    fn major_degree(self) -> u8 {
+        match self {
+            Self::Major => 0,
+            Self::Dorian => 2,
+            Self::Phrygian => 4,
+            Self::Lydian => 5,
+            Self::Mixolydian => 7,
+            Self::Minor => 9,
+            Self::Locrian => 11,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Self::Major => "major",
+            Self::Minor => "minor",
+            Self::Dorian => "dorian",
+            Self::Phrygian => "phrygian",
+            Self::Lydian => "lydian",
+            Self::Mixolydian => "mixolydian",
+            Self::Locrian => "locrian",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Key {
+    // pitch class, 0 is C
+    pub tonic: u8,
+    pub mode: Mode,
+}
+
+impl Key {
+    // "D minor", "Dm", "F#", "Bb dorian". The mode is `None` when the name has none.
+    fn parse(name: &str) -> Option<(u8, Option<Mode>)> {
+        let mut chars = name.trim().chars();
+        let mut tonic: i32 = match chars.next()?.to_ascii_uppercase() {
+            'C' => 0,
+            'D' => 2,
+            'E' => 4,
+            'F' => 5,
+            'G' => 7,
+            'A' => 9,
+            'B' => 11,
+            _ => return None,
+        };
+        let mut rest = chars.as_str();
+        loop {
+            if let Some(r) = rest.strip_prefix(['#', '♯']) {
+                tonic += 1;
+                rest = r;
+            } else if let Some(r) = rest.strip_prefix(['b', '♭']) {
+                tonic -= 1;
+                rest = r;
+            } else {
+                break;
+            }
+        }
+        let rest = rest.trim();
+        let mode = if rest.is_empty() { None } else { Some(Mode::parse(rest)?) };
+        Some((tonic.rem_euclid(12) as u8, mode))
+    }
+
+    // which of the 12 pitch classes, 0 is C, are in the key's scale
+    pub fn pitch_classes(self) -> [bool; 12] {
+        let mut scale = [false; 12];
+        for interval in self.mode.intervals() {
+            scale[((self.tonic + interval) % 12) as usize] = true;
+        }
+        scale
+    }
+
+    // Sharps of the key signature, negative for flats, between 5 flats and 6 sharps
+    pub fn fifths(self) -> i8 {
+        let major = (self.tonic + 12 - self.mode.major_degree()) % 12;
+        let fifths = (major * 7 % 12) as i8;
+        if fifths > 6 { fifths - 12 } else { fifths }
+    }
+
+    // The major or minor key whose profile best matches how long each pitch class sounds
+    pub fn estimate(weights: [f64; 12]) -> Key {
+        let correlation = |profile: &[f64; 12], tonic: usize| {
+            let mean_w = weights.iter().sum::<f64>() / 12.0;
+            let mean_p = profile.iter().sum::<f64>() / 12.0;
+            let (mut cov, mut var_w, mut var_p) = (0.0, 0.0, 0.0);
+            for (pc, &w) in weights.iter().enumerate() {
+                let p = profile[(pc + 12 - tonic) % 12];
+                cov += (w - mean_w) * (p - mean_p);
+                var_w += (w - mean_w).powi(2);
+                var_p += (p - mean_p).powi(2);
+            }
+            cov / (var_w * var_p).sqrt().max(f64::EPSILON)
+        };
+        let mut best = (Key { tonic: 0, mode: Mode::Major }, f64::MIN);
+        for tonic in 0..12 {
+            for (mode, profile) in [(Mode::Major, &MAJOR_PROFILE), (Mode::Minor, &MINOR_PROFILE)] {
+                let score = correlation(profile, tonic);
+                if score > best.1 {
+                    best = (Key { tonic: tonic as u8, mode }, score);
+                }
+            }
+        }
+        best.0
+    }
+
+    // value of the key's conditioning token, e.g. "D_minor"
+    fn token_value(self) -> String {
+        format!("{}_{}", PITCH_CLASSES[self.tonic as usize], self.mode.name())
+    }
+}
+
+impl fmt::Display for Key {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", PITCH_CLASSES[self.tonic as usize], self.mode.name())
+    }
+}
+
+// Validated conditioning fields, free of contradictions
+#[derive(Clone, Debug, Default)]
+pub struct Conditioning {
+    pub key: Option<Key>,
+    pub bpm: Option<f64>,
+    pub time_signature: Option<(u8, u8)>,
+    pub bars: Option<u32>,
+    pub instrument: Option<Instrument>,
+    // lowercase, sorted and deduplicated so the same tags always condition the same way
+    pub genres: Vec<String>,
+}
+
+impl Conditioning {
+    pub fn parse(fields: ConditioningFields) -> Result<Self, ApiError> {
+        let invalid = |message: String| Err(ApiError::InvalidRequest(message));
+
+        let mode = match fields.mode.as_deref() {
+            Some(name) => match Mode::parse(name.trim()) {
+                Some(mode) => Some(mode),
+                None => return invalid(format!("unknown mode {:?}", name)),
+            },
+            None => None,
+        };
+        let key = match fields.key.as_deref() {
+            Some(name) => {
+                let Some((tonic, named_mode)) = Key::parse(name) else {
+                    return invalid(format!("unknown key {:?}", name));
+                };
+                if let (Some(named), Some(mode)) = (named_mode, mode) && named != mode {
+                    return invalid(format!("key {:?} contradicts mode {:?}", name, mode.name()));
+                }
+                Some(Key { tonic, mode: named_mode.or(mode).unwrap_or(Mode::Major) })
+            }
+            None if mode.is_some() => return invalid(String::from("mode needs a key")),
+            None => None,
+        };
+
+        if let Some(bpm) = fields.bpm && !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+            return invalid(format!("bpm must be between {} and {}", MIN_BPM, MAX_BPM));
+        }
+
+        let time_signature = match fields.time_signature.as_deref() {
+            Some(text) => {
+                let parsed = text.trim().split_once('/')
+                    .and_then(|(n, d)| Some((n.trim().parse::<u8>().ok()?, d.trim().parse::<u8>().ok()?)))
+                    .filter(|&(n, d)| (1..=32).contains(&n) && d.is_power_of_two() && d <= 32);
+                match parsed {
+                    Some(meter) => Some(meter),
+                    None => return invalid(format!("invalid time signature {:?}", text)),
+                }
+            }
+            None => None,
+        };
+
+        if let Some(bars) = fields.bars && !(1..=MAX_BARS).contains(&bars) {
+            return invalid(format!("bars must be between 1 and {}", MAX_BARS));
+        }
+
+        let program = match fields.program {
+            Some(program) if program < 128 => Some(Instrument::Program(program)),
+            Some(_) => return invalid(String::from("program must be between 0 and 127")),
+            None => None,
+        };
+        let named = match fields.instrument.as_deref() {
+            Some(name) => match instrument_named(name) {
+                Some(instrument) => Some(instrument),
+                None => return invalid(format!("unknown General MIDI instrument {:?}", name)),
+            },
+            None => None,
+        };
+        if let (Some(program), Some(named)) = (program, named) && program != named {
+            return invalid(format!(
+                "instrument {:?} contradicts program {}",
+                fields.instrument.unwrap_or_default(), fields.program.unwrap_or_default()
+            ));
+        }
+
+        let mut genres: Vec<String> = fields.genres.iter().map(|g| g.trim().to_lowercase()).collect();
+        if genres.iter().any(String::is_empty) {
+            return invalid(String::from("genre tags must not be empty"));
+        }
+        genres.sort_unstable();
+        genres.dedup();
+        if genres.len() > MAX_GENRES {
+            return invalid(format!("at most {} genre tags are allowed", MAX_GENRES));
+        }
+
+        Ok(Self {
+            key,
+            bpm: fields.bpm,
+            time_signature,
+            bars: fields.bars,
+            instrument: program.or(named),
+            genres,
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.key.is_none()
+            && self.bpm.is_none()
+            && self.time_signature.is_none()
+            && self.bars.is_none()
+            && self.instrument.is_none()
+            && self.genres.is_empty()
+    }
+
+    // Conditioning token ids for the fields the model has tokens for, in a fixed order, and a
+    // description of the remaining fields to append to the prompt
+    pub fn apply(&self, model: &Model) -> Result<(Vec<i64>, String), ApiError> {
+        self.encode(&model.manifest.conditioning, &model.tokenizer, &model.name)
+    }
+
+    fn encode(&self, spec: &ConditioningSpec, tokenizer: &Tokenizer, model: &str) -> Result<(Vec<i64>, String), ApiError> {
+        let mut out = Conditioned { tokenizer, model, ids: Vec::new(), text: Vec::new() };
+
+        if let Some(key) = self.key {
+            out.exact(spec.key.as_deref(), &key.token_value(), || format!("key: {}", key))?;
+        }
+        if let Some(bpm) = self.bpm {
+            out.nearest(spec.tempo.as_deref(), bpm, || format!("tempo: {} bpm", bpm))?;
+        }
+        if let Some((numerator, denominator)) = self.time_signature {
+            let value = format!("{}/{}", numerator, denominator);
+            out.exact(spec.time_signature.as_deref(), &value, || format!("time signature: {}", value))?;
+        }
+        if let Some(bars) = self.bars {
+            out.nearest(spec.bars.as_deref(), bars as f64, || format!("length: {} bars", bars))?;
+        }
+        if let Some(instrument) = self.instrument {
+            let (program, name) = match instrument {
+                Instrument::Program(program) => (program as i32, GM_PROGRAMS[program as usize]),
+                Instrument::Drums => (DRUM_PROGRAM, "drums"),
+            };
+            out.exact(spec.program.as_deref(), &program.to_string(), || format!("instrument: {}", name))?;
+        }
+        if !self.genres.is_empty() {
+            match spec.genre.as_deref() {
+                Some(template) => for genre in &self.genres {
+                    out.exact(Some(template), genre, String::new)?;
+                },
+                None => out.text.push(format!("genre: {}", self.genres.join(", "))),
+            }
+        }
+
+        Ok((out.ids, out.text.join(", ")))
+    }
+}
+
+struct Conditioned<'a> {
+    tokenizer: &'a Tokenizer,
+    model: &'a str,
+    ids: Vec<i64>,
+    text: Vec<String>,
+}
+
+impl Conditioned<'_> {
+    // The token named by the template, or the description when the model has no template
+    fn exact(&mut self, template: Option<&str>, value: &str, describe: impl FnOnce() -> String) -> Result<(), ApiError> {
+        let Some(template) = template else {
+            self.text.push(describe());
+            return Ok(());
+        };
+        let name = template.replace("{}", value);
+        match self.tokenizer.token_to_id(&name) {
+            Some(id) => {
+                self.ids.push(id as i64);
+                Ok(())
+            }
+            None => Err(ApiError::InvalidRequest(format!(
+                "model {} has no conditioning token {}", self.model, name
+            ))),
+        }
+    }
+
+    // The template's token with the value closest to `value`, models only have tokens for a few
+    // tempos and lengths
+    fn nearest(&mut self, template: Option<&str>, value: f64, describe: impl FnOnce() -> String) -> Result<(), ApiError> {
+        let Some((before, after)) = template.and_then(|t| t.split_once("{}")) else {
+            self.text.push(describe());
+            return Ok(());
+        };
+        let nearest = self.tokenizer.get_vocab(true).into_iter()
+            .filter_map(|(name, id)| {
+                let v = name.strip_prefix(before)?.strip_suffix(after)?.parse::<f64>().ok()?;
+                Some(((v - value).abs(), id))
+            })
+            // ties go to the lower id, the vocabulary's order is random
+            .min_by(|a, b| a.0.total_cmp(&b.0).then(a.1.cmp(&b.1)));
+        match nearest {
+            Some((_, id)) => {
+                self.ids.push(id as i64);
+                Ok(())
+            }
+            None => Err(ApiError::InvalidRequest(format!(
+                "model {} has no {}{{}}{} tokens", self.model, before, after
+            ))),
+        }
+    }
+}
+
+fn instrument_named(name: &str) -> Option<Instrument> {
+    let name = name.trim();
+    if name.eq_ignore_ascii_case("drums") {
+        return Some(Instrument::Drums);
+    }
+    GM_PROGRAMS.iter()
+        .position(|program| program.eq_ignore_ascii_case(name))
+        .map(|program| Instrument::Program(program as u8))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::str::FromStr;
+
+    use super::*;
+
+    fn parse(fields: ConditioningFields) -> Conditioning {
+        Conditioning::parse(fields).unwrap()
+    }
+
+    fn rejection(fields: ConditioningFields) -> String {
+        match Conditioning::parse(fields) {
+            Err(ApiError::InvalidRequest(message)) => message,
+            other => panic!("expected an invalid request, got {:?}", other.map(|c| c.key)),
+        }
+    }
+
+    fn key(tonic: u8, mode: Mode) -> Option<Key> {
+        Some(Key { tonic, mode })
+    }
+
+    #[test]
+    fn parses_keys_and_modes() {
+        let keyed = |name: &str, mode: Option<&str>| parse(ConditioningFields {
+            key: Some(name.to_string()),
+            mode: mode.map(String::from),
+            ..ConditioningFields::default()
+        }).key;
+        assert_eq!(keyed("D minor", None), key(2, Mode::Minor));
+        assert_eq!(keyed("Dm", None), key(2, Mode::Minor));
+        assert_eq!(keyed("DM", None), key(2, Mode::Major));
+        assert_eq!(keyed("F#", None), key(6, Mode::Major));
+        assert_eq!(keyed("Bb dorian", None), key(10, Mode::Dorian));
+        assert_eq!(keyed("c♯ Aeolian", None), key(1, Mode::Minor));
+        assert_eq!(keyed("Cb", None), key(11, Mode::Major));
+        assert_eq!(keyed("E", Some("minor")), key(4, Mode::Minor));
+        assert_eq!(keyed("E minor", Some("aeolian")), key(4, Mode::Minor));
+        assert_eq!(Key { tonic: 2, mode: Mode::Major }.fifths(), 2);
+        assert_eq!(Key { tonic: 5, mode: Mode::Major }.fifths(), -1);
+        assert_eq!(Key { tonic: 2, mode: Mode::Dorian }.fifths(), 0);
+    }
+
+    #[test]
+    fn parses_tempo_meter_bars_and_instruments() {
+        let conditioning = parse(ConditioningFields {
+            bpm: Some(96.5),
+            time_signature: Some(String::from(" 6 / 8 ")),
+            bars: Some(MAX_BARS),
+            instrument: Some(String::from("acoustic guitar (NYLON)")),
+            genres: vec![String::from("Jazz "), String::from("jazz"), String::from("Lo-Fi")],
+            ..ConditioningFields::default()
+        });
+        assert_eq!(conditioning.bpm, Some(96.5));
+        assert_eq!(conditioning.time_signature, Some((6, 8)));
+        assert_eq!(conditioning.bars, Some(MAX_BARS));
+        assert_eq!(conditioning.instrument, Some(Instrument::Program(24)));
+        assert_eq!(conditioning.genres, vec!["jazz", "lo-fi"]);
+
+        let drums = parse(ConditioningFields { instrument: Some(String::from("Drums")), ..ConditioningFields::default() });
+        assert_eq!(drums.instrument, Some(Instrument::Drums));
+        let program = parse(ConditioningFields { program: Some(40), instrument: Some(String::from("violin")), ..ConditioningFields::default() });
+        assert_eq!(program.instrument, Some(Instrument::Program(40)));
+        assert!(parse(ConditioningFields::default()).is_empty());
+    }
+
+    #[test]
+    fn rejects_invalid_fields() {
+        let rejected = |fields: ConditioningFields| matches!(Conditioning::parse(fields), Err(ApiError::InvalidRequest(_)));
+        assert!(rejected(ConditioningFields { key: Some(String::from("H minor")), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { key: Some(String::from("C blues")), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { mode: Some(String::from("minor")), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { bpm: Some(MAX_BPM + 1.0), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { bpm: Some(f64::NAN), ..ConditioningFields::default() }));
+        for meter in ["3/5", "0/4", "4", "33/4", "4/64"] {
+            assert!(rejected(ConditioningFields { time_signature: Some(meter.to_string()), ..ConditioningFields::default() }), "{}", meter);
+        }
+        assert!(rejected(ConditioningFields { bars: Some(0), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { bars: Some(MAX_BARS + 1), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { program: Some(128), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { instrument: Some(String::from("kazoo")), ..ConditioningFields::default() }));
+        assert!(rejected(ConditioningFields { genres: vec![String::from(" ")], ..ConditioningFields::default() }));
+    }
+
+    #[test]
+    fn contradictions_name_both_fields() {
+        let key = rejection(ConditioningFields {
+            key: Some(String::from("D minor")),
+            mode: Some(String::from("major")),
+            ..ConditioningFields::default()
+        });
+        assert_eq!(key, r#"key "D minor" contradicts mode "major""#);
+        let instrument = rejection(ConditioningFields {
+            program: Some(0),
+            instrument: Some(String::from("Violin")),
+            ..ConditioningFields::default()
+        });
+        assert_eq!(instrument, r#"instrument "Violin" contradicts program 0"#);
+    }
+
+    fn tokenizer(tokens: &[&str]) -> Tokenizer {
+        let vocab: serde_json::Map<String, serde_json::Value> = tokens.iter().enumerate()
+            .map(|(id, &token)| (token.to_string(), id.into()))
+            .collect();
+        let json = serde_json::json!({
+            "version": "1.0",
+            "added_tokens": [],
+            "normalizer": null,
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": null,
+            "model": { "type": "WordLevel", "vocab": vocab, "unk_token": "[UNK]" },
+        });
+        Tokenizer::from_str(&json.to_string()).unwrap()
+    }
+
+    #[test]
+    fn encodes_the_fields_the_scheme_has_tokens_for() {
+        let tokenizer = tokenizer(&[
+            "[UNK]", "KEY_D_minor", "TEMPO_90", "TEMPO_120", "TS_3/4", "BARS_4", "BARS_8", "PROGRAM_40", "PROGRAM_-1",
+        ]);
+        let spec = ConditioningSpec {
+            key: Some(String::from("KEY_{}")),
+            tempo: Some(String::from("TEMPO_{}")),
+            time_signature: Some(String::from("TS_{}")),
+            bars: Some(String::from("BARS_{}")),
+            program: Some(String::from("PROGRAM_{}")),
+            genre: None,
+        };
+        let conditioning = parse(ConditioningFields {
+            key: Some(String::from("Dm")),
+            bpm: Some(110.0),
+            time_signature: Some(String::from("3/4")),
+            bars: Some(5),
+            program: Some(40),
+            genres: vec![String::from("jazz")],
+            ..ConditioningFields::default()
+        });
+        // tempo and bars go to the nearest token, genres without a template to the text
+        let (ids, text) = conditioning.encode(&spec, &tokenizer, "test").unwrap();
+        assert_eq!(ids, vec![1, 3, 4, 5, 7]);
+        assert_eq!(text, "genre: jazz");
+
+        let drums = parse(ConditioningFields { instrument: Some(String::from("drums")), ..ConditioningFields::default() });
+        assert_eq!(drums.encode(&spec, &tokenizer, "test").unwrap().0, vec![8]);
+
+        // a scheme without templates describes everything in the text
+        let (ids, text) = conditioning.encode(&ConditioningSpec::default(), &tokenizer, "test").unwrap();
+        assert!(ids.is_empty());
+        assert_eq!(text, "key: D minor, tempo: 110 bpm, time signature: 3/4, length: 5 bars, instrument: Violin, genre: jazz");
+    }
+
+    #[test]
+    fn missing_tokens_are_rejected() {
+        let tokenizer = tokenizer(&["[UNK]", "KEY_D_minor"]);
+        let spec = ConditioningSpec { key: Some(String::from("KEY_{}")), tempo: Some(String::from("TEMPO_{}")), ..ConditioningSpec::default() };
+        let major = parse(ConditioningFields { key: Some(String::from("D")), ..ConditioningFields::default() });
+        match major.encode(&spec, &tokenizer, "test") {
+            Err(ApiError::InvalidRequest(message)) => assert_eq!(message, "model test has no conditioning token KEY_D_major"),
+            other => panic!("expected an invalid request, got {:?}", other),
+        }
+        let tempo = parse(ConditioningFields { bpm: Some(120.0), ..ConditioningFields::default() });
+        assert!(tempo.encode(&spec, &tokenizer, "test").is_err());
+    }
+}
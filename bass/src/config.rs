@@ -0,0 +1,190 @@
+use std::{
+    fs,
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    path::{Path, PathBuf}
+};
+
+use anyhow::{Context, bail};
+use clap::{Args, ValueEnum};
+use ort::session::builder::GraphOptimizationLevel;
+use serde::Deserialize;
+use tracing_subscriber::EnvFilter;
+
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum OptimizationLevel {
+    Disable,
+    Level1,
+    Level2,
+    Level3,
+}
+
+impl From<OptimizationLevel> for GraphOptimizationLevel {
+    fn from(level: OptimizationLevel) -> Self {
+        match level {
+            OptimizationLevel::Disable => GraphOptimizationLevel::Disable,
+            OptimizationLevel::Level1 => GraphOptimizationLevel::Level1,
+            OptimizationLevel::Level2 => GraphOptimizationLevel::Level2,
+            OptimizationLevel::Level3 => GraphOptimizationLevel::Level3,
+        }
+    }
+}
+
+// Server configuration, read from a TOML file and then overridden by env vars and CLI flags
+#[derive(Deserialize, Clone, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub model_path: PathBuf,
+    pub tokenizer_path: PathBuf,
+    pub model_store: Option<PathBuf>,
+    pub default_model: Option<String>,
+    pub reload_interval_secs: u64,
+    pub host: IpAddr,
+    pub port: u16,
+    pub intra_threads: usize,
+    pub inter_threads: usize,
+    pub sessions_per_model: usize,
+    pub max_batch_size: usize,
+    pub optimization_level: OptimizationLevel,
+    pub log_filter: String,
+    pub soundfont: Option<PathBuf>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            model_path: PathBuf::from("models/model.onnx"),
+            tokenizer_path: PathBuf::from("models/tokenizer.json"),
+            model_store: None,
+            default_model: None,
+            reload_interval_secs: 5,
+            host: IpAddr::V4(Ipv4Addr::LOCALHOST),
+            port: 8000,
+            intra_threads: 4,
+            inter_threads: 1,
+            sessions_per_model: 1,
+            max_batch_size: 8,
+            optimization_level: OptimizationLevel::Level3,
+            log_filter: String::from("info,ort=debug"),
+            soundfont: None,
+        }
+    }
+}
+
+// Flags shared by every command that loads a model. Each one can also be set through its env var.
+#[derive(Args, Debug, Default)]
+pub struct ConfigArgs {
+    /// Path to a TOML config file
+    #[arg(long, short, global = true, env = "BASS_CONFIG")]
+    pub config: Option<PathBuf>,
+    /// Path to the ONNX model
+    #[arg(long, global = true, env = "BASS_MODEL_PATH")]
+    pub model_path: Option<PathBuf>,
+    /// Path to the tokenizer.json matching the model
+    #[arg(long, global = true, env = "BASS_TOKENIZER_PATH")]
+    pub tokenizer_path: Option<PathBuf>,
+    /// Directory holding one <name>/model.onnx per model, replaces model_path
+    #[arg(long, global = true, env = "BASS_MODEL_STORE")]
+    pub model_store: Option<PathBuf>,
+    /// Model used by requests that don't name one
+    #[arg(long, global = true, env = "BASS_DEFAULT_MODEL")]
+    pub default_model: Option<String>,
+    /// Seconds between checks for changed model files, 0 disables reloading
+    #[arg(long, global = true, env = "BASS_RELOAD_INTERVAL_SECS")]
+    pub reload_interval_secs: Option<u64>,
+    /// Address to bind the HTTP server to
+    #[arg(long, global = true, env = "BASS_HOST")]
+    pub host: Option<IpAddr>,
+    /// Port to bind the HTTP server to
+    #[arg(long, global = true, env = "BASS_PORT")]
+    pub port: Option<u16>,
+    /// Threads used to parallelize a single operator
+    #[arg(long, global = true, env = "BASS_INTRA_THREADS")]
+    pub intra_threads: Option<usize>,
+    /// Threads used to run independent operators in parallel
+    #[arg(long, global = true, env = "BASS_INTER_THREADS")]
+    pub inter_threads: Option<usize>,
+    /// Sessions loaded per model, each one runs a batch at a time
+    #[arg(long, global = true, env = "BASS_SESSIONS_PER_MODEL")]
+    pub sessions_per_model: Option<usize>,
+    /// Most decoding steps merged into one run
+    #[arg(long, global = true, env = "BASS_MAX_BATCH_SIZE")]
+    pub max_batch_size: Option<usize>,
+    /// ONNX Runtime graph optimization level
+    #[arg(long, value_enum, global = true, env = "BASS_OPTIMIZATION_LEVEL")]
+    pub optimization_level: Option<OptimizationLevel>,
+    /// tracing filter directives, e.g. "info,ort=debug"
+    #[arg(long, global = true, env = "BASS_LOG")]
+    pub log_filter: Option<String>,
+    /// SF2 file MIDI previews are played with, oscillator presets only when missing
+    #[arg(long, global = true, env = "BASS_SOUNDFONT")]
+    pub soundfont: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn load(args: &ConfigArgs) -> anyhow::Result<Self> {
+        let mut config = match &args.config {
+            Some(path) => Self::from_file(path)?,
+            None => Self::default(),
+        };
+
+        if let Some(v) = &args.model_path { config.model_path = v.clone(); }
+        if let Some(v) = &args.tokenizer_path { config.tokenizer_path = v.clone(); }
+        if let Some(v) = &args.model_store { config.model_store = Some(v.clone()); }
+        if let Some(v) = &args.default_model { config.default_model = Some(v.clone()); }
+        if let Some(v) = args.reload_interval_secs { config.reload_interval_secs = v; }
+        if let Some(v) = args.host { config.host = v; }
+        if let Some(v) = args.port { config.port = v; }
+        if let Some(v) = args.intra_threads { config.intra_threads = v; }
+        if let Some(v) = args.inter_threads { config.inter_threads = v; }
+        if let Some(v) = args.sessions_per_model { config.sessions_per_model = v; }
+        if let Some(v) = args.max_batch_size { config.max_batch_size = v; }
+        if let Some(v) = args.optimization_level { config.optimization_level = v; }
+        if let Some(v) = &args.log_filter { config.log_filter = v.clone(); }
+        if let Some(v) = &args.soundfont { config.soundfont = Some(v.clone()); }
+
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn from_file(path: &Path) -> anyhow::Result<Self> {
+        let contents = fs::read_to_string(path)
+            .with_context(|| format!("could not read config file {}", path.display()))?;
+        toml::from_str(&contents)
+            .with_context(|| format!("invalid config file {}", path.display()))
+    }
+
+    fn validate(&self) -> anyhow::Result<()> {
+        match &self.model_store {
+            Some(store) if !store.is_dir() => {
+                bail!("model_store {} does not point to a directory", store.display());
+            }
+            Some(_) => {}
+            None => {
+                if !self.model_path.is_file() {
+                    bail!("model_path {} does not point to a file", self.model_path.display());
+                }
+                if !self.tokenizer_path.is_file() {
+                    bail!("tokenizer_path {} does not point to a file", self.tokenizer_path.display());
+                }
+            }
+        }
+        if self.intra_threads == 0 || self.inter_threads == 0 {
+            bail!("intra_threads and inter_threads must be at least 1");
+        }
+        if self.sessions_per_model == 0 || self.max_batch_size == 0 {
+            bail!("sessions_per_model and max_batch_size must be at least 1");
+        }
+        if let Some(soundfont) = &self.soundfont && !soundfont.is_file() {
+            bail!("soundfont {} does not point to a file", soundfont.display());
+        }
+        EnvFilter::try_new(&self.log_filter)
+            .with_context(|| format!("invalid log_filter \"{}\"", self.log_filter))?;
+        Ok(())
+    }
+
+    pub fn bind_addr(&self) -> SocketAddr {
+        SocketAddr::new(self.host, self.port)
+    }
+}
@@ -0,0 +1,457 @@
+use std::sync::Arc;
+
+use anyhow::bail;
+use clap::Args;
+use serde::Deserialize;
+
+use crate::{
+    conditioning::Conditioning,
+    error::ApiError,
+    manifest::{Modality, MusicSpec, TokenScheme},
+    midi::{Instrument, TimeSignature},
+    music::MusicToken,
+    registry::Model
+};
+
+
+// Limits on what a MIDI model may generate, enforced by masking tokens before sampling
+#[derive(Deserialize, Args, Clone, Debug, Default)]
+#[serde(default)]
+pub struct ConstraintParams {
+    /// Pitch classes notes may use, 0 is C, e.g. 0,2,4,5,7,9,11
+    #[arg(long, value_delimiter = ',')]
+    pub scale: Option<Vec<u8>>,
+    /// Keeps notes in the scale of the requested key
+    #[arg(long)]
+    pub in_key: bool,
+    /// Lowest pitch notes may use
+    #[arg(long)]
+    pub min_pitch: Option<u8>,
+    /// Highest pitch notes may use
+    #[arg(long)]
+    pub max_pitch: Option<u8>,
+    /// Subdivisions of a beat that onsets and time shifts must land on, e.g. 4 for sixteenths
+    #[arg(long)]
+    pub grid: Option<u32>,
+    /// Most notes sounding at once
+    #[arg(long)]
+    pub max_polyphony: Option<usize>,
+    /// Forbids token orders that don't decode, like a note off before its note on
+    #[arg(long)]
+    pub grammar: bool,
+}
+
+impl ConstraintParams {
+    fn is_empty(&self) -> bool {
+        self.scale.is_none()
+            && !self.in_key
+            && self.min_pitch.is_none()
+            && self.max_pitch.is_none()
+            && self.grid.is_none()
+            && self.max_polyphony.is_none()
+            && !self.grammar
+    }
+}
+
+// Where a token sequence stands, followed token by token the way `music::decode` reads it
+#[derive(Clone)]
+pub struct Timeline {
+    scheme: TokenScheme,
+    ticks_per_beat: u32,
+    positions_per_beat: u32,
+    tick: u32,
+    bar_start: Option<u32>,
+    bar_ticks: u32,
+    instrument: Instrument,
+    // (pitch, instrument, end) of notes that may still sound. The end is unknown while a REMI
+    // note waits for its duration or a MIDI-like note for its note off.
+    notes: Vec<(u8, Instrument, Option<u32>)>,
+    previous: Option<MusicToken>,
+}
+
+impl Timeline {
+    fn new(spec: &MusicSpec) -> Self {
+        let ticks_per_beat = spec.ticks_per_beat as u32;
+        Self {
+            scheme: spec.scheme,
+            ticks_per_beat,
+            positions_per_beat: spec.positions_per_beat.max(1),
+            tick: 0,
+            bar_start: None,
+            bar_ticks: 4 * ticks_per_beat,
+            instrument: Instrument::Program(0),
+            notes: Vec::new(),
+            previous: None,
+        }
+    }
+
+    fn advance(&mut self, token: MusicToken) {
+        match token {
+            MusicToken::Bar => {
+                let start = self.bar_start.map_or(self.tick, |start| start + self.bar_ticks);
+                self.bar_start = Some(start);
+                self.tick = start;
+            }
+            MusicToken::Position(position) => self.tick = self.bar_start.unwrap_or(0) + self.position_ticks(position),
+            MusicToken::TimeShift(ticks) => self.tick += ticks,
+            MusicToken::Duration(ticks) => {
+                let tick = self.tick;
+                if let Some(note) = self.notes.iter_mut().rev().find(|n| n.2.is_none()) {
+                    note.2 = Some(tick + ticks.max(1));
+                }
+            }
+            MusicToken::Pitch(pitch) | MusicToken::PitchDrum(pitch) => {
+                // a REMI note that never got its duration is dropped, as when decoding
+                self.notes.retain(|n| n.2.is_some());
+                let instrument = if matches!(token, MusicToken::PitchDrum(_)) { Instrument::Drums } else { self.instrument };
+                self.notes.push((pitch, instrument, None));
+            }
+            MusicToken::NoteOn(pitch) => self.notes.push((pitch, self.instrument, None)),
+            MusicToken::NoteOff(pitch) => {
+                let (tick, instrument) = (self.tick, self.instrument);
+                if let Some(note) = self.notes.iter_mut().find(|n| n.0 == pitch && n.1 == instrument && n.2.is_none()) {
+                    note.2 = Some(tick);
+                }
+            }
+            MusicToken::TimeSig(numerator, denominator) => {
+                self.bar_ticks = TimeSignature { tick: self.tick, numerator, denominator }.bar_ticks(self.ticks_per_beat as u16);
+            }
+            MusicToken::Program(program) => self.instrument = program,
+            MusicToken::Velocity(_) | MusicToken::Tempo(_) => {}
+        }
+        let tick = self.tick;
+        self.notes.retain(|n| n.2.is_none_or(|end| end > tick));
+        self.previous = Some(token);
+    }
+
+    fn position_ticks(&self, position: u32) -> u32 {
+        position * self.ticks_per_beat / self.positions_per_beat
+    }
+
+    fn sounding(&self) -> usize {
+        self.notes.len()
+    }
+
+    // a MIDI-like note of the current instrument waiting for its note off
+    fn is_open(&self, pitch: u8) -> bool {
+        self.notes.iter().any(|n| n.0 == pitch && n.1 == self.instrument && n.2.is_none())
+    }
+
+    // a REMI note waiting for its velocity or duration
+    fn pending_note(&self) -> bool {
+        self.scheme == TokenScheme::Remi && self.notes.iter().any(|n| n.2.is_none())
+    }
+}
+
+// One stage of the constraint pipeline, deciding which tokens may come next
+pub trait LogitsProcessor: Send + Sync {
+    // the request field it enforces, for errors
+    fn name(&self) -> &'static str;
+    fn allows(&self, token: &MusicToken, timeline: &Timeline) -> bool;
+}
+
+// Notes outside the allowed pitch classes. Drums and note offs are left alone.
+pub struct ScaleMask(pub [bool; 12]);
+pub struct PitchRange(pub u8, pub u8);
+// Onsets and time shifts that would leave the grid, in ticks
+pub struct GridMask(pub u32);
+pub struct MaxPolyphony(pub usize);
+pub struct Grammar {
+    // whether notes carry a velocity token
+    pub velocities: bool,
+}
+
+// Pitch of a token starting a pitched note, drums excluded
+fn pitched_note(token: &MusicToken, timeline: &Timeline) -> Option<u8> {
+    match *token {
+        MusicToken::Pitch(pitch) | MusicToken::NoteOn(pitch) if timeline.instrument != Instrument::Drums => Some(pitch),
+        _ => None,
+    }
+}
+
+impl LogitsProcessor for ScaleMask {
+    fn name(&self) -> &'static str {
+        "scale"
+    }
+
+    fn allows(&self, token: &MusicToken, timeline: &Timeline) -> bool {
+        pitched_note(token, timeline).is_none_or(|pitch| self.0[pitch as usize % 12])
+    }
+}
+
+impl LogitsProcessor for PitchRange {
+    fn name(&self) -> &'static str {
+        "pitch range"
+    }
+
+    fn allows(&self, token: &MusicToken, timeline: &Timeline) -> bool {
+        pitched_note(token, timeline).is_none_or(|pitch| (self.0..=self.1).contains(&pitch))
+    }
+}
+
+impl LogitsProcessor for GridMask {
+    fn name(&self) -> &'static str {
+        "grid"
+    }
+
+    fn allows(&self, token: &MusicToken, timeline: &Timeline) -> bool {
+        match *token {
+            // positions count from the bar line
+            MusicToken::Position(position) => timeline.position_ticks(position).is_multiple_of(self.0),
+            MusicToken::TimeShift(ticks) => (timeline.tick + ticks).is_multiple_of(self.0),
+            _ => true,
+        }
+    }
+}
+
+impl LogitsProcessor for MaxPolyphony {
+    fn name(&self) -> &'static str {
+        "max_polyphony"
+    }
+
+    fn allows(&self, token: &MusicToken, timeline: &Timeline) -> bool {
+        match token {
+            MusicToken::Pitch(_) | MusicToken::PitchDrum(_) | MusicToken::NoteOn(_) => timeline.sounding() < self.0,
+            _ => true,
+        }
+    }
+}
+
+impl LogitsProcessor for Grammar {
+    fn name(&self) -> &'static str {
+        "grammar"
+    }
+
+    fn allows(&self, token: &MusicToken, timeline: &Timeline) -> bool {
+        let previous = timeline.previous;
+        match timeline.scheme {
+            // Pitch, Velocity, Duration, with positions moving forward inside their bar
+            TokenScheme::Remi => {
+                if timeline.pending_note() {
+                    let wants_velocity = self.velocities && matches!(previous, Some(MusicToken::Pitch(_) | MusicToken::PitchDrum(_)));
+                    return if wants_velocity {
+                        matches!(token, MusicToken::Velocity(_))
+                    } else {
+                        matches!(token, MusicToken::Duration(_))
+                    };
+                }
+                match *token {
+                    MusicToken::Velocity(_) | MusicToken::Duration(_) => false,
+                    MusicToken::Position(position) => timeline.bar_start.is_some_and(|start| {
+                        let ticks = timeline.position_ticks(position);
+                        start + ticks >= timeline.tick && ticks < timeline.bar_ticks
+                    }),
+                    _ => true,
+                }
+            }
+            // NoteOn then its Velocity, and note offs only for notes that are on
+            TokenScheme::MidiLike => {
+                if self.velocities && matches!(previous, Some(MusicToken::NoteOn(_))) {
+                    return matches!(token, MusicToken::Velocity(_));
+                }
+                match *token {
+                    MusicToken::Velocity(_) => false,
+                    MusicToken::NoteOn(pitch) => !timeline.is_open(pitch),
+                    MusicToken::NoteOff(pitch) => timeline.is_open(pitch),
+                    _ => true,
+                }
+            }
+        }
+    }
+}
+
+// The processors a request asked for, applied in order to the logits of every step. Clones
+// share the processors and follow their own sequence.
+#[derive(Clone)]
+pub struct Constraints {
+    // parsed music token of each id, `None` for special tokens, which are never masked
+    tokens: Arc<Vec<Option<MusicToken>>>,
+    timeline: Timeline,
+    processors: Arc<Vec<Box<dyn LogitsProcessor>>>,
+}
+
+impl Constraints {
+    // `None` when the request has no constraints
+    pub fn new(params: ConstraintParams, conditioning: &Conditioning, model: &Model) -> Result<Option<Self>, ApiError> {
+        if params.is_empty() {
+            return Ok(None);
+        }
+        let invalid = |message: String| Err(ApiError::InvalidRequest(message));
+        if model.manifest.modality != Modality::Midi {
+            return invalid(format!("model {} does not generate MIDI, constraints need a MIDI model", model.name));
+        }
+        let spec = &model.manifest.music;
+
+        let mut processors: Vec<Box<dyn LogitsProcessor>> = Vec::new();
+        let scale = match (params.scale, params.in_key, conditioning.key) {
+            (Some(_), true, _) => return invalid(String::from("scale contradicts in_key")),
+            (None, true, None) => return invalid(String::from("in_key needs a key")),
+            (None, true, Some(key)) => Some(key.pitch_classes()),
+            (Some(pitch_classes), false, _) => {
+                if pitch_classes.is_empty() || pitch_classes.iter().any(|&p| p >= 12) {
+                    return invalid(String::from("scale must list pitch classes between 0 and 11"));
+                }
+                let mut scale = [false; 12];
+                for p in pitch_classes {
+                    scale[p as usize] = true;
+                }
+                Some(scale)
+            }
+            (None, false, _) => None,
+        };
+        if let Some(scale) = scale {
+            processors.push(Box::new(ScaleMask(scale)));
+        }
+
+        if params.min_pitch.is_some() || params.max_pitch.is_some() {
+            let (low, high) = (params.min_pitch.unwrap_or(0), params.max_pitch.unwrap_or(127));
+            if high > 127 || low > high {
+                return invalid(String::from("pitch range must satisfy min_pitch <= max_pitch <= 127"));
+            }
+            processors.push(Box::new(PitchRange(low, high)));
+        }
+
+        if let Some(grid) = params.grid {
+            if grid == 0 || !spec.positions_per_beat.is_multiple_of(grid) {
+                return invalid(format!(
+                    "grid must divide the model's {} positions per beat", spec.positions_per_beat
+                ));
+            }
+            processors.push(Box::new(GridMask(spec.ticks_per_beat as u32 / grid)));
+        }
+
+        if let Some(max) = params.max_polyphony {
+            if max == 0 {
+                return invalid(String::from("max_polyphony must be at least 1"));
+            }
+            processors.push(Box::new(MaxPolyphony(max)));
+        }
+
+        let vocab = model.tokenizer.get_vocab(true);
+        let mut tokens = vec![None; vocab.values().max().map_or(0, |&id| id as usize + 1)];
+        for (name, &id) in &vocab {
+            tokens[id as usize] = MusicToken::parse(name, spec);
+        }
+
+        if params.grammar {
+            let velocities = tokens.iter().any(|t| matches!(t, Some(MusicToken::Velocity(_))));
+            processors.push(Box::new(Grammar { velocities }));
+        }
+
+        Ok(Some(Self {
+            tokens: Arc::new(tokens),
+            timeline: Timeline::new(spec),
+            processors: Arc::new(processors),
+        }))
+    }
+
+    // Follows a prompt token or a sampled one
+    pub fn advance(&mut self, token: i64) {
+        if let Some(Some(token)) = self.tokens.get(token as usize) {
+            self.timeline.advance(*token);
+        }
+    }
+
+    // Masks the tokens the processors forbid next. Fails when a processor leaves no token at all,
+    // generating anything else would break the constraint.
+    pub fn process(&self, logits: &mut [f32]) -> anyhow::Result<()> {
+        for processor in self.processors.iter() {
+            for (logit, token) in logits.iter_mut().zip(self.tokens.iter()) {
+                if let Some(token) = token && !processor.allows(token, &self.timeline) {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+            if !logits.iter().any(|l| *l > f32::NEG_INFINITY) {
+                bail!("the {} constraint leaves no token to generate", processor.name());
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // `None` stands for a special token
+    fn constraints(tokens: Vec<Option<MusicToken>>, processors: Vec<Box<dyn LogitsProcessor>>) -> Constraints {
+        Constraints {
+            tokens: Arc::new(tokens),
+            // 8 positions and 480 ticks per beat
+            timeline: Timeline::new(&MusicSpec::default()),
+            processors: Arc::new(processors),
+        }
+    }
+
+    fn masked(constraints: &Constraints) -> Vec<bool> {
+        let mut logits = vec![0.5; constraints.tokens.len()];
+        constraints.process(&mut logits).unwrap();
+        logits.iter().map(|&l| l == f32::NEG_INFINITY).collect()
+    }
+
+    const C_MAJOR: [bool; 12] = [true, false, true, false, true, true, false, true, false, true, false, true];
+
+    #[test]
+    fn scale_masks_off_scale_pitches() {
+        let tokens = vec![
+            None,
+            Some(MusicToken::Pitch(60)),
+            Some(MusicToken::Pitch(61)),
+            Some(MusicToken::Pitch(74)),
+            Some(MusicToken::Pitch(78)),
+            Some(MusicToken::Position(3)),
+            Some(MusicToken::Duration(240)),
+        ];
+        let constraints = constraints(tokens, vec![Box::new(ScaleMask(C_MAJOR))]);
+        assert_eq!(masked(&constraints), vec![false, false, true, false, true, false, false]);
+    }
+
+    #[test]
+    fn scale_leaves_drums_and_note_offs_alone() {
+        let tokens = vec![Some(MusicToken::NoteOn(61)), Some(MusicToken::NoteOff(61)), Some(MusicToken::NoteOn(62))];
+        let mut constraints = constraints(tokens, vec![Box::new(ScaleMask(C_MAJOR))]);
+        constraints.timeline.scheme = TokenScheme::MidiLike;
+        assert_eq!(masked(&constraints), vec![true, false, false]);
+        constraints.timeline.advance(MusicToken::Program(Instrument::Drums));
+        assert_eq!(masked(&constraints), vec![false, false, false]);
+    }
+
+    #[test]
+    fn grid_masks_off_grid_positions_and_shifts() {
+        let tokens = vec![
+            Some(MusicToken::Position(0)),
+            Some(MusicToken::Position(1)),
+            Some(MusicToken::Position(2)),
+            Some(MusicToken::Position(6)),
+            Some(MusicToken::TimeShift(60)),
+            Some(MusicToken::TimeShift(120)),
+            Some(MusicToken::Pitch(61)),
+        ];
+        // sixteenths, 120 ticks
+        let mut constraints = constraints(tokens, vec![Box::new(GridMask(120))]);
+        assert_eq!(masked(&constraints), vec![false, true, false, false, true, false, false]);
+        // off the grid, a shift has to bring it back
+        constraints.timeline.advance(MusicToken::TimeShift(60));
+        assert_eq!(masked(&constraints)[4..6], [false, true]);
+    }
+
+    #[test]
+    fn processors_run_in_order() {
+        let tokens = vec![Some(MusicToken::Pitch(48)), Some(MusicToken::Pitch(61)), Some(MusicToken::Pitch(72))];
+        let constraints = constraints(tokens, vec![Box::new(ScaleMask(C_MAJOR)), Box::new(PitchRange(60, 127))]);
+        assert_eq!(masked(&constraints), vec![true, true, false]);
+    }
+
+    #[test]
+    fn no_legal_token_is_an_error() {
+        let tokens = vec![Some(MusicToken::Pitch(61)), Some(MusicToken::Pitch(63))];
+        let off_scale = constraints(tokens, vec![Box::new(ScaleMask(C_MAJOR))]);
+        let error = off_scale.process(&mut [1.0, 2.0]).unwrap_err();
+        assert_eq!(error.to_string(), "the scale constraint leaves no token to generate");
+
+        // tokens masked before the constraints count too
+        let tokens = vec![Some(MusicToken::Pitch(60)), Some(MusicToken::Pitch(61))];
+        let in_scale = constraints(tokens, vec![Box::new(ScaleMask(C_MAJOR))]);
+        assert!(in_scale.process(&mut [f32::NEG_INFINITY, 0.0]).is_err());
+    }
+}
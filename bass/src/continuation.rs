@@ -0,0 +1,231 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{FromRequest, Json, Multipart, Request, State},
+    http::header,
+    response::Response
+};
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Deserialize;
+
+use crate::{
+    conditioning::Conditioning,
+    constraints::{ConstraintParams, Constraints},
+    error::ApiError,
+    manifest::Modality,
+    midi::Score,
+    format::{Accept, OutputFormat},
+    model::{self, Generation},
+    music::{self, Continuation, Vocabulary},
+    postprocess::{PostProcess, PostProcessParams},
+    registry::{Model, ModelRegistry},
+    sampler::SamplingParams,
+    synth::{Preview, PreviewParams}
+};
+
+
+// New bars generated when a continuation doesn't ask for a number
+const DEFAULT_BARS: u32 = 4;
+// Most bars a single continuation can ask for
+const MAX_BARS: u32 = 64;
+
+// Bars of the uploaded file, counted from 0, `end` excluded
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct BarRange {
+    pub start: u32,
+    pub end: u32,
+}
+
+#[derive(Deserialize, Default)]
+pub struct ContinueRequest {
+    // base64 encoded MIDI file, multipart uploads send it as a `midi` file part instead
+    pub midi: Option<String>,
+    pub model: Option<String>,
+    pub max_new_tokens: Option<usize>,
+    // number of candidates to generate, each with its own seed
+    pub num_variations: Option<usize>,
+    // number of bars to add after the end of the file
+    pub bars: Option<u32>,
+    // regenerates these bars, with the bars before and after them as context
+    pub infill: Option<BarRange>,
+    // negotiated from the Accept header when missing, then MIDI
+    pub output_format: Option<OutputFormat>,
+    pub stream: Option<bool>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+    #[serde(flatten)]
+    pub constraints: ConstraintParams,
+    #[serde(flatten)]
+    pub post_process: PostProcessParams,
+    #[serde(flatten)]
+    pub preview: PreviewParams,
+}
+
+// Continues an uploaded MIDI file or fills in bars of it, and returns only the new bars. Takes a
+// JSON body with the file base64 encoded, or a multipart form with a `midi` file part and an
+// optional `request` part holding the other fields as JSON.
+pub async fn continue_midi(
+    State(registry): State<Arc<ModelRegistry>>,
+    request: Request
+) -> Result<Response, ApiError> {
+    let accept = Accept::from_headers(request.headers());
+    let (body, midi) = read_upload(request).await?;
+    let stream = body.stream.unwrap_or(!accept.wants_file());
+    let generation = prepare(&registry, body, &midi, &accept)?;
+    model::respond(generation, stream).await
+}
+
+async fn read_upload(request: Request) -> Result<(ContinueRequest, Vec<u8>), ApiError> {
+    let multipart = request.headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.starts_with("multipart/form-data"));
+
+    if !multipart {
+        let Json(mut body) = Json::<ContinueRequest>::from_request(request, &()).await?;
+        let Some(data) = body.midi.take() else {
+            return Err(ApiError::InvalidRequest(String::from("missing field `midi`")));
+        };
+        let midi = STANDARD.decode(data.trim())
+            .map_err(|e| ApiError::MalformedUpload(format!("midi is not valid base64: {}", e)))?;
+        return Ok((body, midi));
+    }
+
+    let mut form = Multipart::from_request(request, &()).await
+        .map_err(|e| ApiError::MalformedUpload(e.body_text()))?;
+    let mut body = ContinueRequest::default();
+    let mut midi = None;
+    while let Some(field) = form.next_field().await? {
+        match field.name() {
+            Some("midi") => {
+                let bytes = field.bytes().await?;
+                midi = Some(bytes.to_vec());
+            }
+            Some("request") => {
+                let bytes = field.bytes().await?;
+                body = Json::<ContinueRequest>::from_bytes(&bytes)?.0;
+            }
+            _ => {}
+        }
+    }
+    let Some(midi) = midi else {
+        return Err(ApiError::InvalidRequest(String::from("missing part `midi`")));
+    };
+    Ok((body, midi))
+}
+
+// Tokenizes the upload with the model's scheme and frames it as a continuation prompt, or as a
+// fill-in-the-middle prompt when bars are to be infilled
+fn prepare(registry: &ModelRegistry, body: ContinueRequest, midi: &[u8], accept: &Accept) -> Result<Generation, ApiError> {
+    let model = model::resolve_model(registry, body.model.as_deref())?;
+    if model.manifest.modality != Modality::Midi {
+        return Err(ApiError::InvalidRequest(format!("model {} does not generate MIDI", model.name)));
+    }
+    let output_format = match body.output_format {
+        Some(format) => format,
+        None => accept.choose(model.manifest.modality)?.unwrap_or(OutputFormat::Midi),
+    };
+    model::check_format(&model, output_format)?;
+    let post_process = PostProcess::new(body.post_process, &model, output_format)?;
+    let preview = Preview::new(body.preview, &model, output_format, registry.soundfont())?;
+    let constraints = Constraints::new(body.constraints, &Conditioning::default(), &model)?;
+
+    let spec = &model.manifest.music;
+    let score = Score::from_smf(midi)
+        .map_err(|e| ApiError::InvalidMidi(format!("{:#}", e)))?
+        .resample(spec.ticks_per_beat);
+    if score.notes.is_empty() {
+        return Err(ApiError::InvalidMidi(String::from("the file has no notes")));
+    }
+    let bar_ticks = score.bar_ticks();
+    // e.g. 1/128 bars on a coarse tick grid
+    if bar_ticks == 0 {
+        return Err(ApiError::InvalidMidi(String::from("the file's bars are shorter than a tick")));
+    }
+    let total_bars = score.end().div_ceil(bar_ticks);
+    let file_end = bar_tick(total_bars, bar_ticks)?;
+    let vocab = Vocabulary::new(model.tokenizer.get_vocab(true).keys().map(String::as_str), spec);
+
+    let mut tokens: Vec<i64> = model.manifest.tokens.bos.map(|id| id as i64).into_iter().collect();
+    let (continuation, bars) = match body.infill {
+        None => {
+            let bars = body.bars.unwrap_or(DEFAULT_BARS);
+            if bars == 0 || bars > MAX_BARS {
+                return Err(ApiError::InvalidRequest(format!("bars must be between 1 and {}", MAX_BARS)));
+            }
+            let (prefix, ids) = known_tokens(&model, music::encode(&score, total_bars, spec, &vocab));
+            tokens.extend(ids);
+            let end = bar_tick(total_bars.saturating_add(bars), bar_ticks)?;
+            (Continuation { prefix, start: file_end, end }, bars)
+        }
+        Some(range) => {
+            let Some(infill) = &spec.infill else {
+                return Err(ApiError::InvalidRequest(format!("model {} was not trained for infilling", model.name)));
+            };
+            if range.start >= range.end || range.end > total_bars {
+                return Err(ApiError::InvalidRequest(format!(
+                    "infill bars {}..{} are not within the file's {} bars",
+                    range.start, range.end, total_bars
+                )));
+            }
+            let (start, end) = (bar_tick(range.start, bar_ticks)?, bar_tick(range.end, bar_ticks)?);
+            let before = score.window(0, start);
+            let after = score.window(end, file_end);
+            let (prefix, prefix_ids) = known_tokens(&model, music::encode(&before, range.start, spec, &vocab));
+            let (_, suffix_ids) = known_tokens(&model, music::encode(&after, total_bars - range.end, spec, &vocab));
+
+            tokens.push(infill.prefix as i64);
+            tokens.extend(prefix_ids);
+            tokens.push(infill.suffix as i64);
+            tokens.extend(suffix_ids);
+            tokens.push(infill.middle as i64);
+            let continuation = Continuation { prefix, start, end };
+            (continuation, range.end - range.start)
+        }
+    };
+
+    let gen_tokens = model::fit_context(&model, tokens.len(), body.max_new_tokens)?;
+    Ok(Generation {
+        model,
+        tokens,
+        gen_tokens,
+        samplers: model::samplers(body.sampling, body.num_variations)?,
+        constraints,
+        output_format,
+        continuation: Some(continuation),
+        // the prompt leaves the first new bar open
+        bar_limit: Some(bars),
+        key: None,
+        post_process,
+        preview,
+        audio: None,
+        mastering: None,
+    })
+}
+
+// Tick of a bar line, a file that ends near the last tick can't be continued past it
+fn bar_tick(bar: u32, bar_ticks: u32) -> Result<u32, ApiError> {
+    bar.checked_mul(bar_ticks).ok_or_else(|| {
+        ApiError::MalformedUpload(format!("bar {} lies past the last tick a MIDI file can address", bar))
+    })
+}
+
+// Keeps the tokens the model's vocabulary has, e.g. drops program tokens for single-instrument
+// models, and returns their names along with their ids
+fn known_tokens(model: &Model, names: Vec<String>) -> (Vec<String>, Vec<i64>) {
+    names.into_iter()
+        .filter_map(|name| model.tokenizer.token_to_id(&name).map(|id| (name, id as i64)))
+        .unzip()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bar_lines_past_the_tick_range_are_rejected() {
+        assert_eq!(bar_tick(4, 1920).unwrap(), 7680);
+        let error = bar_tick(u32::MAX / 1920 + 1, 1920).unwrap_err();
+        assert_eq!(error.status(), axum::http::StatusCode::BAD_REQUEST);
+    }
+}
@@ -0,0 +1,470 @@
+use std::{borrow::Cow, iter, mem};
+
+use ort::{
+    session::{RunOptions, Session, SessionInputValue},
+    tensor::{Shape, TensorElementType},
+    value::Tensor
+};
+
+use crate::manifest::{Axis, Manifest};
+
+
+// A past key/value input and the present output that feeds it on the next step
+struct CacheSlot {
+    past: String,
+    present: String,
+    // shape of an empty cache, with the past sequence axis set to 0
+    empty_shape: Vec<i64>,
+    // the past sequence axis, the first dynamic axis after the batch axis
+    seq_axis: Option<usize>,
+}
+
+// Names and layout of the tensors a decoder graph takes and returns, resolved from its manifest
+pub struct DecoderGraph {
+    input_ids: String,
+    input_layout: Vec<Axis>,
+    attention_mask: Option<String>,
+    position_ids: Option<String>,
+    logits: String,
+    logits_layout: Vec<Axis>,
+    cache: Vec<CacheSlot>,
+    // several sequences can share a run when every tensor leads with a dynamic batch axis
+    batchable: bool,
+    // sequences of different lengths can share a run when the graph takes an attention mask and
+    // position ids, each row is left-padded to the longest one and the padding masked out
+    pads: bool,
+}
+
+impl DecoderGraph {
+    pub fn new(manifest: &Manifest, session: &Session) -> anyhow::Result<Self> {
+        let dynamic_batch = |shape: Option<&Shape>| shape.and_then(|s| s.first().copied()) == Some(-1);
+        let Some((inputs, outputs)) = manifest.decoder() else {
+            anyhow::bail!("manifest describes no token decoder");
+        };
+
+        let mut cache = Vec::new();
+        let mut batchable = inputs.layout.first() == Some(&Axis::Batch)
+            && outputs.layout.first() == Some(&Axis::Batch);
+        let past_prefix = manifest.cache.as_ref().map(|c| c.past.as_str());
+        for input in &session.inputs {
+            if input.name == inputs.input_ids {
+                batchable &= dynamic_batch(input.input_type.tensor_shape());
+            }
+            if !past_prefix.is_some_and(|p| input.name.starts_with(p)) {
+                continue;
+            }
+            if input.input_type.tensor_type() != Some(TensorElementType::Float32) {
+                anyhow::bail!("cache input {} is {}, only f32 caches are supported", input.name, input.input_type);
+            }
+            let Some(present) = manifest.present_name(&input.name) else {
+                anyhow::bail!("cache input {} has no matching present output", input.name);
+            };
+            batchable &= dynamic_batch(input.input_type.tensor_shape());
+            let shape = input.input_type.tensor_shape().map(|shape| shape.to_vec()).unwrap_or_default();
+            let empty_shape = shape.iter().enumerate()
+                .map(|(axis, &d)| match (axis, d) {
+                    (0, -1) => 1,
+                    (_, -1) => 0,
+                    (_, d) => d,
+                })
+                .collect();
+            let seq_axis = shape.iter().skip(1).position(|&d| d == -1).map(|axis| axis + 1);
+            cache.push(CacheSlot { past: input.name.clone(), present, empty_shape, seq_axis });
+        }
+
+        let pads = batchable
+            && inputs.attention_mask.is_some()
+            && inputs.position_ids.is_some()
+            && cache.iter().all(|slot| slot.seq_axis.is_some());
+        Ok(Self {
+            input_ids: inputs.input_ids.clone(),
+            input_layout: inputs.layout.clone(),
+            attention_mask: inputs.attention_mask.clone(),
+            position_ids: inputs.position_ids.clone(),
+            logits: outputs.logits.clone(),
+            logits_layout: outputs.layout.clone(),
+            cache,
+            batchable,
+            pads,
+        })
+    }
+
+    pub fn has_cache(&self) -> bool {
+        !self.cache.is_empty()
+    }
+
+    pub fn batchable(&self) -> bool {
+        self.batchable
+    }
+
+    // Every axis is 1 except the batch and sequence axes, e.g. [1, 1, len] for our original exports
+    fn ids_shape(&self, batch: usize, len: usize) -> Vec<i64> {
+        self.input_layout.iter()
+            .map(|&axis| match axis {
+                Axis::Batch => batch as i64,
+                Axis::Sequence => len as i64,
+                Axis::Channel | Axis::Vocab => 1,
+            })
+            .collect()
+    }
+
+    // Gathers the vocab axis at the last sequence position, first index of every other axis
+    fn last_logits(&self, dims: &[i64], data: &[f32]) -> Vec<f32> {
+        let mut strides = vec![1usize; dims.len()];
+        for axis in (0..dims.len().saturating_sub(1)).rev() {
+            strides[axis] = strides[axis + 1] * dims[axis + 1] as usize;
+        }
+
+        let mut offset = 0;
+        let mut vocab = (0, 0);
+        for (axis, &kind) in self.logits_layout.iter().enumerate() {
+            match kind {
+                Axis::Sequence => offset += (dims[axis] as usize).saturating_sub(1) * strides[axis],
+                Axis::Vocab => vocab = (dims[axis] as usize, strides[axis]),
+                Axis::Batch | Axis::Channel => {}
+            }
+        }
+        (0..vocab.0).map(|i| data[offset + i * vocab.1]).collect()
+    }
+
+    // Lines the states up as the rows of one run. Each row is the state's cache followed by its
+    // unseen tokens, and both parts are left-padded to the longest of the batch, so the last
+    // position of every row is a real token. The caches move out of the states into the batch.
+    fn pack(&self, states: &mut [DecodeState]) -> Packed {
+        let lens: Vec<(usize, usize)> = states.iter().map(|s| s.lens(self)).collect();
+        let past_len = lens.iter().map(|&(past, _)| past).max().unwrap_or(0);
+        let fresh_len = lens.iter().map(|&(_, fresh)| fresh).max().unwrap_or(0);
+        let total_len = past_len + fresh_len;
+
+        let batch = states.len();
+        let mut packed = Packed {
+            batch,
+            fresh_len,
+            ids: Vec::with_capacity(batch * fresh_len),
+            mask: Vec::with_capacity(batch * total_len),
+            positions: Vec::with_capacity(batch * fresh_len),
+            past: Vec::with_capacity(self.cache.len()),
+            keep: Vec::with_capacity(batch),
+        };
+        for (state, &(past, fresh)) in states.iter().zip(&lens) {
+            let (past_pad, fresh_pad) = (past_len - past, fresh_len - fresh);
+            // padding is masked out, any id and position will do
+            packed.ids.extend(iter::repeat_n(0, fresh_pad).chain(state.tokens[past..].iter().copied()));
+            packed.positions.extend(iter::repeat_n(0, fresh_pad).chain(past as i64..(past + fresh) as i64));
+            let real = |j: usize| (past_pad..past_len).contains(&j) || j >= past_len + fresh_pad;
+            packed.mask.extend((0..total_len).map(|j| i64::from(real(j))));
+            packed.keep.push((0..total_len).filter(|&j| real(j)).collect());
+        }
+
+        for (i, slot) in self.cache.iter().enumerate() {
+            let rows = states.iter_mut().map(|state| {
+                let past = match state.past.get_mut(i) {
+                    Some(past) => mem::take(past),
+                    None => CacheTensor { dims: slot.empty_shape.clone(), data: Vec::new() },
+                };
+                match slot.seq_axis {
+                    Some(axis) => past.pad_front(axis, past_len),
+                    None => past,
+                }
+            });
+            packed.past.push(CacheTensor::concat(rows));
+        }
+        packed
+    }
+
+    // Hands each state its logits and its share of the present cache, without the padding
+    fn unpack(&self, states: &mut [DecodeState], keep: &[Vec<usize>], logits: (&[i64], &[f32]), present: Vec<CacheTensor>) -> Vec<Vec<f32>> {
+        let batch = states.len();
+        let (dims, data) = logits;
+        let mut row_dims = dims.to_vec();
+        row_dims[0] /= batch as i64;
+        let logits = data.chunks(data.len() / batch).map(|row| self.last_logits(&row_dims, row)).collect();
+
+        for state in states.iter_mut() {
+            state.past.resize_with(self.cache.len(), CacheTensor::default);
+        }
+        for (i, (slot, value)) in self.cache.iter().zip(present).enumerate() {
+            for ((state, row), keep) in states.iter_mut().zip(value.split(batch)).zip(keep) {
+                state.past[i] = match slot.seq_axis {
+                    Some(axis) => row.gather(axis, keep),
+                    None => row,
+                };
+            }
+        }
+        if self.has_cache() {
+            for state in states.iter_mut() {
+                state.past_len = state.tokens.len();
+            }
+        }
+        logits
+    }
+
+    // Runs one forward pass for every state and returns the logits of each one's last position.
+    // States must share a `batch_key`, and more than one state needs a batchable graph.
+    pub async fn step(&self, session: &mut Session, states: &mut [DecodeState]) -> ort::Result<Vec<Vec<f32>>> {
+        let packed = self.pack(states);
+        let (batch, fresh_len) = (packed.batch as i64, packed.fresh_len as i64);
+
+        let mut inputs: Vec<(Cow<'_, str>, SessionInputValue<'_>)> = Vec::new();
+        let ids = Tensor::from_array((self.ids_shape(packed.batch, packed.fresh_len), packed.ids))?;
+        inputs.push((self.input_ids.as_str().into(), ids.into()));
+        if let Some(name) = &self.attention_mask {
+            let total_len = packed.mask.len() as i64 / batch;
+            let mask = Tensor::from_array((vec![batch, total_len], packed.mask))?;
+            inputs.push((name.as_str().into(), mask.into()));
+        }
+        if let Some(name) = &self.position_ids {
+            let positions = Tensor::from_array((vec![batch, fresh_len], packed.positions))?;
+            inputs.push((name.as_str().into(), positions.into()));
+        }
+        for (slot, past) in self.cache.iter().zip(packed.past) {
+            let past = Tensor::from_array((past.dims, past.data))?;
+            inputs.push((slot.past.as_str().into(), past.into()));
+        }
+
+        let options = RunOptions::new()?;
+        let outputs = session.run_async(inputs, &options)?.await?;
+
+        let mut present = Vec::with_capacity(self.cache.len());
+        for slot in &self.cache {
+            let Some(value) = outputs.get(slot.present.as_str()) else {
+                return Err(ort::Error::new(format!("missing cache output {}", slot.present)));
+            };
+            let (dims, data) = value.try_extract_tensor::<f32>()?;
+            present.push(CacheTensor { dims: dims.to_vec(), data: data.to_vec() });
+        }
+        let (dims, data) = outputs[self.logits.as_str()].try_extract_tensor::<f32>()?;
+        Ok(self.unpack(states, &packed.keep, (dims, data), present))
+    }
+}
+
+// The inputs of one run, row after row
+struct Packed {
+    batch: usize,
+    fresh_len: usize,
+    ids: Vec<i64>,
+    mask: Vec<i64>,
+    positions: Vec<i64>,
+    // one batched tensor per cache slot
+    past: Vec<CacheTensor>,
+    // positions of each row's real entries along the present cache's sequence axis
+    keep: Vec<Vec<usize>>,
+}
+
+// A key/value cache tensor held between steps, row-major with a leading batch axis
+#[derive(Clone, Debug, Default, PartialEq)]
+struct CacheTensor {
+    dims: Vec<i64>,
+    data: Vec<f32>,
+}
+
+impl CacheTensor {
+    // (elements before, length of, elements after) `axis`
+    fn around(&self, axis: usize) -> (usize, usize, usize) {
+        let product = |dims: &[i64]| dims.iter().product::<i64>() as usize;
+        (product(&self.dims[..axis]), self.dims[axis] as usize, product(&self.dims[axis + 1..]))
+    }
+
+    // Prepends zeros along `axis` up to `len`
+    fn pad_front(self, axis: usize, len: usize) -> Self {
+        let (outer, n, inner) = self.around(axis);
+        if n >= len {
+            return self;
+        }
+        let mut data = Vec::with_capacity(outer * len * inner);
+        for o in 0..outer {
+            data.extend(iter::repeat_n(0.0, (len - n) * inner));
+            data.extend_from_slice(&self.data[o * n * inner..(o + 1) * n * inner]);
+        }
+        let mut dims = self.dims;
+        dims[axis] = len as i64;
+        Self { dims, data }
+    }
+
+    // Keeps the `keep` positions along `axis`
+    fn gather(&self, axis: usize, keep: &[usize]) -> Self {
+        let (outer, n, inner) = self.around(axis);
+        let mut data = Vec::with_capacity(outer * keep.len() * inner);
+        for o in 0..outer {
+            for &j in keep {
+                let at = (o * n + j) * inner;
+                data.extend_from_slice(&self.data[at..at + inner]);
+            }
+        }
+        let mut dims = self.dims.clone();
+        dims[axis] = keep.len() as i64;
+        Self { dims, data }
+    }
+
+    // Joins rows along the batch axis, which is a plain concatenation in row-major order
+    fn concat(rows: impl Iterator<Item = Self>) -> Self {
+        let mut joined = Self::default();
+        for row in rows {
+            if joined.dims.is_empty() {
+                joined.dims = row.dims;
+            } else {
+                joined.dims[0] += row.dims[0];
+            }
+            joined.data.extend(row.data);
+        }
+        joined
+    }
+
+    fn split(self, batch: usize) -> Vec<Self> {
+        let mut dims = self.dims;
+        dims[0] /= batch as i64;
+        let row_len = self.data.len() / batch;
+        (0..batch)
+            .map(|i| Self { dims: dims.clone(), data: self.data[i * row_len..(i + 1) * row_len].to_vec() })
+            .collect()
+    }
+}
+
+// Per-request decoding state: the sequence so far and, when the graph has one, its key/value cache
+pub struct DecodeState {
+    pub tokens: Vec<i64>,
+    past: Vec<CacheTensor>,
+    past_len: usize,
+}
+
+impl DecodeState {
+    pub fn new(tokens: Vec<i64>) -> Self {
+        Self { tokens, past: Vec::new(), past_len: 0 }
+    }
+
+    // (cached length, length fed to the graph)
+    fn lens(&self, graph: &DecoderGraph) -> (usize, usize) {
+        let past_len = if graph.has_cache() { self.past_len } else { 0 };
+        (past_len, self.tokens.len() - past_len)
+    }
+
+    // Only states with equal keys can share a run. A graph that pads batches prompts with prompts
+    // and single tokens with single tokens, so a long prompt never pads every row to its length.
+    pub fn batch_key(&self, graph: &DecoderGraph) -> (usize, usize) {
+        let (past_len, fresh_len) = self.lens(graph);
+        if graph.pads {
+            (0, fresh_len.min(2))
+        } else {
+            (past_len, fresh_len)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const VOCAB: usize = 4;
+
+    // A graph with one cache slot of shape [batch, 1, seq, 2] and [batch, seq, vocab] logits
+    fn graph() -> DecoderGraph {
+        DecoderGraph {
+            input_ids: String::from("input_ids"),
+            input_layout: vec![Axis::Batch, Axis::Sequence],
+            attention_mask: Some(String::from("attention_mask")),
+            position_ids: Some(String::from("position_ids")),
+            logits: String::from("logits"),
+            logits_layout: vec![Axis::Batch, Axis::Sequence, Axis::Vocab],
+            cache: vec![CacheSlot {
+                past: String::from("past.0"),
+                present: String::from("present.0"),
+                empty_shape: vec![1, 1, 0, 2],
+                seq_axis: Some(2),
+            }],
+            batchable: true,
+            pads: true,
+        }
+    }
+
+    // One attention head over a key/value pair made from each token and its position, so wrong
+    // positions, unmasked padding or a misaligned cache all change the logits
+    fn attend(packed: &Packed) -> (Vec<i64>, Vec<f32>, CacheTensor) {
+        let (batch, fresh_len) = (packed.batch, packed.fresh_len);
+        let past = &packed.past[0];
+        let past_len = past.dims[2] as usize;
+        let total_len = past_len + fresh_len;
+
+        let mut present = Vec::new();
+        let mut logits = Vec::new();
+        for b in 0..batch {
+            let mut kv: Vec<[f32; 2]> = past.data[b * past_len * 2..(b + 1) * past_len * 2]
+                .chunks(2)
+                .map(|pair| [pair[0], pair[1]])
+                .collect();
+            for t in 0..fresh_len {
+                let (id, position) = (packed.ids[b * fresh_len + t] as f32, packed.positions[b * fresh_len + t] as f32);
+                kv.push([(id + 0.3 * position).sin(), (0.7 * id - 0.2 * position).cos()]);
+            }
+            for t in 0..fresh_len {
+                let q = kv[past_len + t];
+                let (mut sum, mut out) = (0.0, [0.0; 2]);
+                for (j, k) in kv.iter().enumerate().take(past_len + t + 1) {
+                    if packed.mask[b * total_len + j] == 0 {
+                        continue;
+                    }
+                    let w = (q[0] * k[0] + q[1] * k[1]).exp();
+                    sum += w;
+                    out = [out[0] + w * k[0], out[1] + w * k[1]];
+                }
+                let out = [out[0] / sum, out[1] / sum];
+                logits.extend([out[0], out[1], out[0] * out[1], out[0] - out[1]]);
+            }
+            present.extend(kv.into_iter().flatten());
+        }
+        let present = CacheTensor { dims: vec![batch as i64, 1, total_len as i64, 2], data: present };
+        (vec![batch as i64, fresh_len as i64, VOCAB as i64], logits, present)
+    }
+
+    fn step(graph: &DecoderGraph, states: &mut [DecodeState]) -> Vec<Vec<f32>> {
+        let packed = graph.pack(states);
+        let (dims, logits, present) = attend(&packed);
+        graph.unpack(states, &packed.keep, (&dims, &logits), vec![present])
+    }
+
+    fn assert_close(batched: &[f32], alone: &[f32]) {
+        assert_eq!(batched.len(), alone.len());
+        for (a, b) in batched.iter().zip(alone) {
+            assert!((a - b).abs() < 1e-5, "{:?} != {:?}", batched, alone);
+        }
+    }
+
+    #[test]
+    fn padded_batches_match_unbatched_runs() {
+        let graph = graph();
+        let prompts = [vec![1, 2, 3], vec![2], vec![3, 1]];
+        let mut alone: Vec<DecodeState> = prompts.iter().map(|p| DecodeState::new(p.clone())).collect();
+        let mut batched: Vec<DecodeState> = prompts.iter().map(|p| DecodeState::new(p.clone())).collect();
+
+        // prompts of different lengths, then single tokens on caches of different lengths
+        for next in [0, 1] {
+            let expected: Vec<Vec<f32>> = alone.chunks_mut(1).flat_map(|state| step(&graph, state)).collect();
+            let logits = step(&graph, &mut batched[..2]);
+            for (logits, expected) in logits.iter().zip(&expected) {
+                assert_close(logits, expected);
+            }
+            for state in alone.iter_mut().chain(&mut batched[..2]) {
+                state.tokens.push(next);
+            }
+        }
+
+        // a whole prompt next to a state that is several tokens into its cache
+        let prompt = DecodeState::new(alone[2].tokens.clone());
+        let logits = step(&graph, &mut [mem::replace(&mut batched[0], DecodeState::new(Vec::new())), prompt]);
+        let expected = step(&graph, &mut alone[..1]);
+        assert_close(&logits[0], &expected[0]);
+        let expected = step(&graph, &mut alone[2..]);
+        assert_close(&logits[1], &expected[0]);
+    }
+
+    #[test]
+    fn padded_caches_come_back_without_the_padding() {
+        let graph = graph();
+        let mut alone = [DecodeState::new(vec![1, 2, 3])];
+        step(&graph, &mut alone);
+        let mut batched = [DecodeState::new(vec![1, 2, 3]), DecodeState::new(vec![2])];
+        step(&graph, &mut batched);
+        assert_eq!(batched[0].past, alone[0].past);
+        assert_eq!(batched[1].past[0].dims, vec![1, 1, 1, 2]);
+        assert_eq!(batched[0].batch_key(&graph), batched[1].batch_key(&graph));
+    }
+}
@@ -0,0 +1,307 @@
+use clap::ValueEnum;
+use rand::{Rng, rngs::StdRng};
+use serde::Deserialize;
+
+use crate::manifest::{BetaSchedule, DenoiserSpec, Prediction};
+
+
+// How latents move from one noise level to the next
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum DiffusionSampler {
+    // deterministic, on the training timesteps
+    #[default]
+    Ddim,
+    // first order, on noise levels
+    Euler,
+    // Euler, adding fresh noise back after each step
+    EulerAncestral,
+    // second order multistep, reuses the previous step's prediction
+    #[serde(rename = "dpmpp_2m")]
+    #[value(name = "dpmpp_2m")]
+    DpmPp2m,
+}
+
+// Cumulative signal level of every training timestep, and what the denoiser predicts at them
+pub struct NoiseSchedule {
+    alphas_cumprod: Vec<f64>,
+    prediction: Prediction,
+}
+
+impl NoiseSchedule {
+    pub fn new(spec: &DenoiserSpec) -> Self {
+        let n = spec.train_timesteps;
+        let at = |i: usize, start: f64, end: f64| {
+            if n > 1 { start + (end - start) * i as f64 / (n - 1) as f64 } else { start }
+        };
+        let betas = (0..n).map(|i| match spec.beta_schedule {
+            BetaSchedule::Linear => at(i, spec.beta_start, spec.beta_end),
+            BetaSchedule::ScaledLinear => at(i, spec.beta_start.sqrt(), spec.beta_end.sqrt()).powi(2),
+        });
+
+        let mut alphas_cumprod = Vec::with_capacity(n);
+        let mut product = 1.0;
+        for beta in betas {
+            product *= 1.0 - beta;
+            alphas_cumprod.push(product);
+        }
+        Self { alphas_cumprod, prediction: spec.prediction }
+    }
+
+    // `steps` evenly spaced training timesteps, noisiest first
+    pub fn timesteps(&self, steps: usize) -> Vec<usize> {
+        let ratio = self.alphas_cumprod.len() / steps.max(1);
+        (0..steps).rev().map(|i| i * ratio).collect()
+    }
+
+    // The clean sample and the noise a denoiser output implies at timestep `t`
+    fn split(&self, sample: &[f32], output: &[f32], t: usize) -> (Vec<f32>, Vec<f32>) {
+        let alpha = self.alphas_cumprod[t];
+        let (signal, noise) = (alpha.sqrt() as f32, (1.0 - alpha).sqrt() as f32);
+        sample.iter().zip(output)
+            .map(|(&x, &out)| match self.prediction {
+                Prediction::Epsilon => ((x - noise * out) / signal, out),
+                Prediction::VPrediction => (signal * x - noise * out, signal * out + noise * x),
+            })
+            .unzip()
+    }
+
+    // Noise level of timestep `t`, as the standard deviation of noise added to a unit signal
+    fn sigma(&self, t: usize) -> f64 {
+        let alpha = self.alphas_cumprod[t];
+        ((1.0 - alpha) / alpha).sqrt()
+    }
+}
+
+// Latents on their way from noise to a clean sample, one denoiser call per step. DDIM works on
+// the latents as the denoiser sees them, the other samplers on latents scaled by their noise
+// level, which are scaled back before each call.
+pub struct Denoising {
+    schedule: NoiseSchedule,
+    sampler: DiffusionSampler,
+    timesteps: Vec<usize>,
+    // noise level of each timestep and 0 after the last
+    sigmas: Vec<f64>,
+    step: usize,
+    latents: Vec<f32>,
+    // clean sample predicted by the previous step, for DPM++ 2M
+    previous: Option<Vec<f32>>,
+    rng: StdRng,
+}
+
+impl Denoising {
+    // Starts from seeded noise of `len` values
+    pub fn new(schedule: NoiseSchedule, sampler: DiffusionSampler, steps: usize, len: usize, mut rng: StdRng) -> Self {
+        let timesteps = schedule.timesteps(steps);
+        let sigmas: Vec<f64> = timesteps.iter().map(|&t| schedule.sigma(t)).chain([0.0]).collect();
+        let mut latents = gaussian(&mut rng, len);
+        if sampler != DiffusionSampler::Ddim {
+            let scale = sigmas[0] as f32;
+            latents.iter_mut().for_each(|x| *x *= scale);
+        }
+        Self { schedule, sampler, timesteps, sigmas, step: 0, latents, previous: None, rng }
+    }
+
+    // Timestep the denoiser runs at next, `None` once every step is done
+    pub fn timestep(&self) -> Option<usize> {
+        self.timesteps.get(self.step).copied()
+    }
+
+    // The latents as the denoiser expects them at the current timestep
+    pub fn model_input(&self) -> Vec<f32> {
+        match self.sampler {
+            DiffusionSampler::Ddim => self.latents.clone(),
+            _ => {
+                let scale = (1.0 / (self.sigmas[self.step].powi(2) + 1.0).sqrt()) as f32;
+                self.latents.iter().map(|x| x * scale).collect()
+            }
+        }
+    }
+
+    // Moves to the next noise level given the denoiser's output for `model_input`
+    pub fn step(&mut self, output: &[f32]) {
+        let Some(t) = self.timestep() else { return };
+        let (sigma, sigma_next) = (self.sigmas[self.step], self.sigmas[self.step + 1]);
+        let (denoised, noise) = self.schedule.split(&self.model_input(), output, t);
+        let latents = &mut self.latents;
+        match self.sampler {
+            DiffusionSampler::Ddim => {
+                // re-noises the predicted clean sample to the next timestep's level
+                let alpha = self.timesteps.get(self.step + 1).map(|&p| self.schedule.alphas_cumprod[p]).unwrap_or(1.0);
+                let (signal, spread) = (alpha.sqrt() as f32, (1.0 - alpha).sqrt() as f32);
+                for ((x, &x0), &eps) in latents.iter_mut().zip(&denoised).zip(&noise) {
+                    *x = signal * x0 + spread * eps;
+                }
+            }
+            DiffusionSampler::Euler => {
+                let dt = (sigma_next - sigma) as f32;
+                let sigma = sigma as f32;
+                for (x, &x0) in latents.iter_mut().zip(&denoised) {
+                    *x += (*x - x0) / sigma * dt;
+                }
+            }
+            DiffusionSampler::EulerAncestral => {
+                // part of the way down deterministically, the rest as fresh noise
+                let up = (sigma_next.powi(2) * (sigma.powi(2) - sigma_next.powi(2)) / sigma.powi(2)).sqrt().min(sigma_next);
+                let down = (sigma_next.powi(2) - up.powi(2)).sqrt();
+                let dt = (down - sigma) as f32;
+                let sigma = sigma as f32;
+                let fresh = gaussian(&mut self.rng, latents.len());
+                for ((x, &x0), &n) in latents.iter_mut().zip(&denoised).zip(&fresh) {
+                    *x += (*x - x0) / sigma * dt + n * up as f32;
+                }
+            }
+            DiffusionSampler::DpmPp2m => {
+                let lambda = |s: f64| -s.ln();
+                if sigma_next == 0.0 {
+                    latents.copy_from_slice(&denoised);
+                } else {
+                    let h = lambda(sigma_next) - lambda(sigma);
+                    let ratio = (sigma_next / sigma) as f32;
+                    let weight = -(-h).exp_m1() as f32;
+                    // extrapolates the prediction from the previous step's, the first step has none
+                    let estimate: Vec<f32> = match (&self.previous, self.step.checked_sub(1)) {
+                        (Some(previous), Some(last)) => {
+                            let r = (lambda(sigma) - lambda(self.sigmas[last])) / h;
+                            let (a, b) = ((1.0 + 1.0 / (2.0 * r)) as f32, (1.0 / (2.0 * r)) as f32);
+                            denoised.iter().zip(previous).map(|(&d, &p)| a * d - b * p).collect()
+                        }
+                        _ => denoised.clone(),
+                    };
+                    for (x, &e) in latents.iter_mut().zip(&estimate) {
+                        *x = ratio * *x + weight * e;
+                    }
+                }
+                self.previous = Some(denoised);
+            }
+        }
+        self.step += 1;
+    }
+
+    // The clean latents once every step is done
+    pub fn into_latents(self) -> Vec<f32> {
+        self.latents
+    }
+}
+
+// Standard normal samples, by the Box-Muller transform
+pub fn gaussian(rng: &mut StdRng, len: usize) -> Vec<f32> {
+    let mut out = Vec::with_capacity(len + 1);
+    while out.len() < len {
+        // 1 - u keeps the logarithm finite
+        let u: f64 = 1.0 - rng.random::<f64>();
+        let v: f64 = rng.random();
+        let radius = (-2.0 * u.ln()).sqrt();
+        let angle = std::f64::consts::TAU * v;
+        out.push((radius * angle.cos()) as f32);
+        out.push((radius * angle.sin()) as f32);
+    }
+    out.truncate(len);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    // betas of 0.1, 0.2, 0.3 and 0.4
+    fn spec(schedule: &str, prediction: &str) -> DenoiserSpec {
+        toml::from_str(&format!(r#"
+            sample = "sample"
+            timestep = "timestep"
+            encoder_hidden_states = "encoder_hidden_states"
+            output = "output"
+            train_timesteps = 4
+            beta_start = 0.1
+            beta_end = 0.4
+            beta_schedule = "{}"
+            prediction = "{}"
+        "#, schedule, prediction)).unwrap()
+    }
+
+    // Two latents at 1 and -0.5, on the timesteps 2 and 0 of `spec`
+    fn denoising(sampler: DiffusionSampler, steps: usize) -> Denoising {
+        let mut denoising = Denoising::new(NoiseSchedule::new(&spec("linear", "epsilon")), sampler, steps, 2, StdRng::seed_from_u64(1));
+        denoising.latents = vec![1.0, -0.5];
+        denoising
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f64]) {
+        for (a, e) in actual.iter().zip(expected) {
+            assert!((*a as f64 - e).abs() < 1e-5, "{:?} != {:?}", actual, expected);
+        }
+    }
+
+    #[test]
+    fn schedules_accumulate_betas() {
+        let linear = NoiseSchedule::new(&spec("linear", "epsilon"));
+        assert_close(&linear.alphas_cumprod.iter().map(|&a| a as f32).collect::<Vec<_>>(), &[0.9, 0.72, 0.504, 0.3024]);
+        // betas from 0.1 to 0.4 evenly spaced in square root
+        let scaled = NoiseSchedule::new(&spec("scaled_linear", "epsilon"));
+        let root = |i: f64| 0.1f64.sqrt() + (0.4f64.sqrt() - 0.1f64.sqrt()) * i / 3.0;
+        assert!((scaled.alphas_cumprod[1] - (1.0 - root(0.0).powi(2)) * (1.0 - root(1.0).powi(2))).abs() < 1e-12);
+        assert!((linear.sigma(0) - 1.0 / 3.0).abs() < 1e-12);
+    }
+
+    #[test]
+    fn timesteps_are_evenly_spaced_noisiest_first() {
+        let mut spec = spec("linear", "epsilon");
+        spec.train_timesteps = 1000;
+        let schedule = NoiseSchedule::new(&spec);
+        assert_eq!(schedule.timesteps(10), vec![900, 800, 700, 600, 500, 400, 300, 200, 100, 0]);
+        assert_eq!(schedule.timesteps(3), vec![666, 333, 0]);
+        assert_eq!(schedule.timesteps(1), vec![0]);
+    }
+
+    #[test]
+    fn v_prediction_splits_into_sample_and_noise() {
+        let schedule = NoiseSchedule::new(&spec("linear", "v_prediction"));
+        let (denoised, noise) = schedule.split(&[1.0], &[0.5], 0);
+        // sqrt(0.9) - sqrt(0.1) / 2 and sqrt(0.9) / 2 + sqrt(0.1)
+        assert_close(&denoised, &[0.790569]);
+        assert_close(&noise, &[0.790569]);
+    }
+
+    #[test]
+    fn ddim_renoises_the_predicted_sample() {
+        let mut denoising = denoising(DiffusionSampler::Ddim, 2);
+        assert_eq!(denoising.timestep(), Some(2));
+        denoising.step(&[0.5, 0.25]);
+        // x0 = (x - sqrt(0.496) eps) / sqrt(0.504), then sqrt(0.9) x0 + sqrt(0.1) eps
+        assert_close(&denoising.latents, &[1.0238581, -0.8243772]);
+        assert_eq!(denoising.timestep(), Some(0));
+    }
+
+    #[test]
+    fn euler_steps_along_the_derivative() {
+        let mut denoising = denoising(DiffusionSampler::Euler, 2);
+        denoising.step(&[0.5, 0.25]);
+        assert_close(&denoising.latents, &[0.6706508, -0.6646746]);
+    }
+
+    #[test]
+    fn euler_ancestral_lands_on_the_prediction_at_the_last_step() {
+        let mut denoising = denoising(DiffusionSampler::EulerAncestral, 2);
+        denoising.step = 1;
+        denoising.step(&[0.5, 0.25]);
+        // no noise is added on the way to sigma 0
+        let scale = (1.0f64 / 9.0 + 1.0).sqrt();
+        let x0 = |x: f64, eps: f64| (x / scale - 0.1f64.sqrt() * eps) / 0.9f64.sqrt();
+        assert_close(&denoising.latents, &[x0(1.0, 0.5), x0(-0.5, 0.25)]);
+        assert_eq!(denoising.timestep(), None);
+    }
+
+    #[test]
+    fn dpmpp_2m_extrapolates_from_the_previous_step() {
+        let mut denoising = denoising(DiffusionSampler::DpmPp2m, 3);
+        assert_eq!(denoising.timesteps, vec![2, 1, 0]);
+        // the first step has no previous prediction and matches Euler
+        denoising.step(&[0.5, 0.25]);
+        assert_close(&denoising.latents, &[0.8157889, -0.5921055]);
+        denoising.step(&[-0.2, 0.4]);
+        assert_close(&denoising.latents, &[1.0109275, -0.7375910]);
+    }
+}
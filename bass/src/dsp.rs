@@ -0,0 +1,309 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+
+// Zero crossings of the resampling kernel on each side of its center
+const SINC_ZEROS: usize = 16;
+
+// Interleaved samples at another sample rate, by windowed-sinc interpolation. Going down the
+// kernel widens so it also filters out what the new rate can't hold.
+pub fn resample(samples: &[f32], channels: u16, from: u32, to: u32) -> Vec<f32> {
+    if from == to || samples.is_empty() {
+        return samples.to_vec();
+    }
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let ratio = to as f64 / from as f64;
+    let cutoff = ratio.min(1.0);
+    let radius = SINC_ZEROS as f64 / cutoff;
+    let out_frames = (frames as f64 * ratio).round() as usize;
+
+    let mut out = vec![0.0; out_frames * channels];
+    for (frame, chunk) in out.chunks_mut(channels).enumerate() {
+        let center = frame as f64 / ratio;
+        let first = (center - radius).ceil().max(0.0) as usize;
+        let last = ((center + radius).floor() as usize).min(frames - 1);
+        for source in first..=last {
+            let weight = (cutoff * sinc(cutoff * (center - source as f64)) * hann((center - source as f64) / radius)) as f32;
+            for (channel, value) in chunk.iter_mut().enumerate() {
+                *value += weight * samples[source * channels + channel];
+            }
+        }
+    }
+    out
+}
+
+fn sinc(x: f64) -> f64 {
+    if x.abs() < 1e-9 { 1.0 } else { (PI * x).sin() / (PI * x) }
+}
+
+// Hann window over [-1, 1]
+fn hann(x: f64) -> f64 {
+    if x.abs() >= 1.0 { 0.0 } else { 0.5 + 0.5 * (PI * x).cos() }
+}
+
+// A biquad filter in transposed direct form II, `a0` normalized to 1
+struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    z: [f64; 2],
+}
+
+impl Biquad {
+    fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.z[0];
+        self.z[0] = self.b[1] * x - self.a[0] * y + self.z[1];
+        self.z[1] = self.b[2] * x - self.a[1] * y;
+        y
+    }
+}
+
+// The K-weighting of BS.1770, a high shelf then a high pass, designed for any sample rate the
+// way libebur128 does it
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = sample_rate as f64;
+    let (f0, gain, q) = (1681.974450955533, 3.999843853973347, 0.7071752369554196);
+    let k = (PI * f0 / rate).tan();
+    let vh = 10f64.powf(gain / 20.0);
+    let vb = vh.powf(0.4996667741545416);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad {
+        b: [(vh + vb * k / q + k * k) / a0, 2.0 * (k * k - vh) / a0, (vh - vb * k / q + k * k) / a0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+    let (f0, q) = (38.13547087602444, 0.5003270373238773);
+    let k = (PI * f0 / rate).tan();
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad {
+        b: [1.0, -2.0, 1.0],
+        a: [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+        z: [0.0; 2],
+    };
+    [shelf, high_pass]
+}
+
+// Integrated loudness in LUFS by ITU-R BS.1770-4: K-weighted power in 400 ms blocks overlapping
+// by 75%, gated at -70 LUFS and then 10 LU below the ungated mean. Clips shorter than a block are
+// one block. `None` when the gates leave nothing, e.g. for silence.
+pub fn loudness(samples: &[f32], channels: u16, sample_rate: u32) -> Option<f64> {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    if frames == 0 {
+        return None;
+    }
+    let mut power = vec![0.0f64; frames];
+    for channel in 0..channels {
+        let [mut shelf, mut high_pass] = k_weighting(sample_rate);
+        for (frame, power) in power.iter_mut().enumerate() {
+            let y = high_pass.process(shelf.process(samples[frame * channels + channel] as f64));
+            *power += y * y;
+        }
+    }
+    let mut sums = vec![0.0f64; frames + 1];
+    for (frame, p) in power.iter().enumerate() {
+        sums[frame + 1] = sums[frame] + p;
+    }
+
+    let block = ((sample_rate as f64 * 0.4) as usize).clamp(1, frames);
+    let step = ((sample_rate as f64 * 0.1) as usize).max(1);
+    let blocks: Vec<f64> = (0..=frames - block).step_by(step)
+        .map(|start| (sums[start + block] - sums[start]) / block as f64)
+        .collect();
+    let lufs = |power: f64| -0.691 + 10.0 * power.log10();
+    let mean = |blocks: &[f64]| blocks.iter().sum::<f64>() / blocks.len() as f64;
+
+    let audible: Vec<f64> = blocks.into_iter().filter(|&p| lufs(p) > -70.0).collect();
+    if audible.is_empty() {
+        return None;
+    }
+    let threshold = lufs(mean(&audible)) - 10.0;
+    let gated: Vec<f64> = audible.into_iter().filter(|&p| lufs(p) > threshold).collect();
+    Some(lufs(mean(&gated)))
+}
+
+// Taps per phase of the 4x oversampling true peaks are measured with, as BS.1770 suggests
+const PEAK_TAPS: usize = 12;
+const OVERSAMPLING: usize = 4;
+
+// Per frame, the highest absolute value of any channel at the frame and between it and the next
+// frame, 4x oversampled so peaks between samples count too
+fn frame_peaks(samples: &[f32], channels: usize) -> Vec<f32> {
+    let frames = samples.len() / channels;
+    let half = PEAK_TAPS as isize / 2;
+    // weights of the frames around `frame` for the points 1/4, 2/4 and 3/4 of the way to the next
+    let weights: Vec<Vec<f32>> = (1..OVERSAMPLING)
+        .map(|phase| {
+            let offset = phase as f64 / OVERSAMPLING as f64;
+            (1 - half..=half)
+                .map(|tap| {
+                    let x = offset - tap as f64;
+                    (sinc(x) * hann(x / half as f64)) as f32
+                })
+                .collect()
+        })
+        .collect();
+
+    (0..frames)
+        .map(|frame| {
+            let mut peak = 0.0f32;
+            for channel in 0..channels {
+                let at = |f: isize| {
+                    if f < 0 || f >= frames as isize { 0.0 } else { samples[f as usize * channels + channel] }
+                };
+                peak = peak.max(samples[frame * channels + channel].abs());
+                for weights in &weights {
+                    let value: f32 = (1 - half..=half).zip(weights).map(|(tap, w)| w * at(frame as isize + tap)).sum();
+                    peak = peak.max(value.abs());
+                }
+            }
+            peak
+        })
+        .collect()
+}
+
+// Lookahead of the limiter, its gain is down before a peak arrives
+const LIMITER_LOOKAHEAD_SECS: f32 = 0.005;
+// Time constant the limiter's gain recovers with after a peak
+const LIMITER_RELEASE_SECS: f32 = 0.05;
+
+// Keeps the true peak at or below `ceiling` with a lookahead limiter. The gain needed at each
+// frame is the minimum over the lookahead, averaged over the lookahead so it ramps down smoothly
+// and still reaches that minimum by the peak, then released exponentially.
+pub fn limit(samples: &mut [f32], channels: u16, sample_rate: u32, ceiling: f32) {
+    let channels = channels.max(1) as usize;
+    let required: Vec<f32> = frame_peaks(samples, channels).into_iter()
+        .map(|peak| if peak > ceiling { ceiling / peak } else { 1.0 })
+        .collect();
+    if required.iter().all(|&gain| gain >= 1.0) {
+        return;
+    }
+    let lookahead = ((sample_rate as f32 * LIMITER_LOOKAHEAD_SECS) as usize).max(1);
+
+    // minimum over [frame, frame + lookahead) with a queue of increasing gains
+    let mut minimum = vec![1.0f32; required.len()];
+    let mut queue: VecDeque<usize> = VecDeque::new();
+    for frame in (0..required.len()).rev() {
+        while queue.back().is_some_and(|&f| required[f] >= required[frame]) {
+            queue.pop_back();
+        }
+        queue.push_back(frame);
+        while queue.front().is_some_and(|&f| f >= frame + lookahead) {
+            queue.pop_front();
+        }
+        minimum[frame] = required[queue[0]];
+    }
+
+    let release = 1.0 - (-1.0 / (LIMITER_RELEASE_SECS * sample_rate as f32)).exp();
+    // frames before the start count as the first window, which holds every early peak
+    let mut sum = minimum[0] as f64 * lookahead as f64;
+    let mut gain = 1.0f32;
+    for (frame, chunk) in samples.chunks_exact_mut(channels).enumerate() {
+        sum += minimum[frame] as f64 - minimum.get(frame.wrapping_sub(lookahead)).copied().unwrap_or(minimum[0]) as f64;
+        let target = (sum / lookahead as f64) as f32;
+        gain = if target < gain { target } else { gain + (target - gain) * release };
+        chunk.iter_mut().for_each(|s| *s *= gain);
+    }
+}
+
+// The frames between the first and the last one with a channel above `threshold`
+pub fn trim(samples: &[f32], channels: u16, threshold: f32) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    let loud = |frame: &[f32]| frame.iter().any(|s| s.abs() > threshold);
+    let first = samples.chunks_exact(channels).position(loud);
+    let last = samples.chunks_exact(channels).rposition(loud);
+    match (first, last) {
+        (Some(first), Some(last)) => samples[first * channels..(last + 1) * channels].to_vec(),
+        _ => Vec::new(),
+    }
+}
+
+// Raised cosine fades over the first `fade_in` and the last `fade_out` frames
+pub fn fade(samples: &mut [f32], channels: u16, fade_in: usize, fade_out: usize) {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let curve = |i: usize, len: usize| (0.5 - 0.5 * (PI * (i as f64 + 0.5) / len as f64).cos()) as f32;
+    let fade_in = fade_in.min(frames);
+    for (i, chunk) in samples.chunks_exact_mut(channels).take(fade_in).enumerate() {
+        chunk.iter_mut().for_each(|s| *s *= curve(i, fade_in));
+    }
+    let fade_out = fade_out.min(frames);
+    for (i, chunk) in samples.chunks_exact_mut(channels).skip(frames - fade_out).enumerate() {
+        chunk.iter_mut().for_each(|s| *s *= curve(fade_out - 1 - i, fade_out));
+    }
+}
+
+// Cuts the audio from the first rising zero crossing of its channel sum within `search` frames
+// of the start to the last one within `search` frames of the end, so the wrap from the last
+// frame back to the first continues the waveform. Ends without a crossing stay where they are.
+pub fn loop_at_zero_crossings(samples: &[f32], channels: u16, search: usize) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let mix = |frame: usize| samples[frame * channels..(frame + 1) * channels].iter().sum::<f32>();
+    let rising = |frame: &usize| mix(frame - 1) < 0.0 && mix(*frame) >= 0.0;
+    let start = (1..search.min(frames)).find(rising).unwrap_or(0);
+    let end = (frames.saturating_sub(search).max(start + 1)..frames).rev().find(rising).unwrap_or(frames);
+    samples[start * channels..end * channels].to_vec()
+}
+
+// Folds the last `overlap` frames into the first ones with an equal power crossfade, the loop
+// ends where the overlap started so its wrap lands on the tail's continuation
+pub fn loop_crossfade(samples: &[f32], channels: u16, overlap: usize) -> Vec<f32> {
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let overlap = overlap.min(frames / 2);
+    let body = frames - overlap;
+    let mut out = samples[..body * channels].to_vec();
+    for i in 0..overlap {
+        let t = (i as f64 + 0.5) / overlap as f64;
+        let (head, tail) = ((0.5 * PI * t).sin() as f32, (0.5 * PI * t).cos() as f32);
+        for channel in 0..channels {
+            out[i * channels + channel] = samples[i * channels + channel] * head + samples[(body + i) * channels + channel] * tail;
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, amplitude: f64, sample_rate: u32, secs: f64) -> Vec<f32> {
+        (0..(sample_rate as f64 * secs) as usize)
+            .map(|i| (amplitude * (2.0 * PI * frequency * i as f64 / sample_rate as f64).sin()) as f32)
+            .collect()
+    }
+
+    fn true_peak(samples: &[f32]) -> f32 {
+        frame_peaks(samples, 1).into_iter().fold(0.0, f32::max)
+    }
+
+    #[test]
+    fn measures_loudness_like_bs1770() {
+        // a 997 Hz sine at -20 dBFS reads -23 LUFS on one channel
+        let samples = sine(997.0, 0.1, 48_000, 3.0);
+        let lufs = loudness(&samples, 1, 48_000).unwrap();
+        assert!((lufs + 23.01).abs() < 0.05, "{} LUFS", lufs);
+        assert_eq!(loudness(&vec![0.0; 48_000], 1, 48_000), None);
+    }
+
+    #[test]
+    fn limiting_keeps_true_peaks_under_the_ceiling() {
+        // a quarter of the sample rate, phased so every sample misses the crest by 3 dB
+        let mut samples: Vec<f32> = (0..4_800)
+            .map(|i| (1.2 * (PI / 2.0 * i as f64 + PI / 4.0).sin()) as f32)
+            .collect();
+        assert!(true_peak(&samples) > 1.15);
+        limit(&mut samples, 1, 48_000, 0.5);
+        assert!(true_peak(&samples) <= 0.5 + 1e-3);
+    }
+
+    #[test]
+    fn crossfaded_loops_wrap_into_their_tail() {
+        let samples: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+        let looped = loop_crossfade(&samples, 1, 100);
+        assert_eq!(looped.len(), 900);
+        // the wrap from the last frame continues where the tail went on
+        assert!((looped[0] - 900.0).abs() < 10.0);
+        assert!((looped[99] - 99.0).abs() < 10.0);
+    }
+}
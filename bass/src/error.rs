@@ -0,0 +1,161 @@
+use std::fmt;
+
+use axum::{
+    Json,
+    extract::{multipart::MultipartError, rejection::JsonRejection},
+    http::StatusCode,
+    response::{IntoResponse, Response}
+};
+use serde::Serialize;
+
+
+// Everything a request can fail with before its stream starts. Errors after that point are
+// reported as `error` events instead.
+#[derive(Debug)]
+pub enum ApiError {
+    MalformedJson(String),
+    MalformedUpload(String),
+    BodyTooLarge(String),
+    InvalidRequest(String),
+    InvalidMidi(String),
+    EmptyPrompt,
+    PromptTooLong { len: usize, max: usize },
+    ContextOverflow { tokens: usize, context_length: usize },
+    UnknownModel(String),
+    UnknownJob(String),
+    JobNotFinished(String),
+    NoPreview(String),
+    NotAcceptable(String),
+    ModelUnavailable,
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct ErrorBody<'a> {
+    error: ErrorDetail<'a>,
+}
+
+#[derive(Serialize)]
+struct ErrorDetail<'a> {
+    code: &'static str,
+    message: &'a str,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Self::MalformedJson(_) | Self::MalformedUpload(_) => StatusCode::BAD_REQUEST,
+            Self::BodyTooLarge(_) | Self::PromptTooLong { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+            Self::InvalidRequest(_)
+            | Self::InvalidMidi(_)
+            | Self::EmptyPrompt
+            | Self::ContextOverflow { .. }
+            | Self::UnknownModel(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            Self::UnknownJob(_) | Self::NoPreview(_) => StatusCode::NOT_FOUND,
+            Self::JobNotFinished(_) => StatusCode::CONFLICT,
+            Self::NotAcceptable(_) => StatusCode::NOT_ACCEPTABLE,
+            Self::ModelUnavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    // Stable identifier clients can match on, shared with the `error` event
+    pub fn code(&self) -> &'static str {
+        match self {
+            Self::MalformedJson(_) => "malformed_json",
+            Self::MalformedUpload(_) => "malformed_upload",
+            Self::BodyTooLarge(_) => "body_too_large",
+            Self::InvalidRequest(_) => "invalid_request",
+            Self::InvalidMidi(_) => "invalid_midi",
+            Self::EmptyPrompt => "empty_prompt",
+            Self::PromptTooLong { .. } => "prompt_too_long",
+            Self::ContextOverflow { .. } => "context_overflow",
+            Self::UnknownModel(_) => "unknown_model",
+            Self::UnknownJob(_) => "unknown_job",
+            Self::JobNotFinished(_) => "job_not_finished",
+            Self::NoPreview(_) => "no_preview",
+            Self::NotAcceptable(_) => "not_acceptable",
+            Self::ModelUnavailable => "model_unavailable",
+            Self::Internal(_) => "internal_error",
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::MalformedJson(e) => write!(f, "malformed JSON body: {}", e),
+            Self::MalformedUpload(e) => write!(f, "malformed upload: {}", e),
+            Self::BodyTooLarge(e) => write!(f, "body too large: {}", e),
+            Self::InvalidRequest(e) => write!(f, "invalid request: {}", e),
+            Self::InvalidMidi(e) => write!(f, "invalid MIDI file: {}", e),
+            Self::EmptyPrompt => write!(f, "prompt is empty"),
+            Self::PromptTooLong { len, max } => write!(f, "prompt is {} bytes, the limit is {}", len, max),
+            Self::ContextOverflow { tokens, context_length } => write!(
+                f,
+                "prompt is {} tokens, which leaves no room in the model's context window of {}",
+                tokens, context_length
+            ),
+            Self::UnknownModel(name) => write!(f, "unknown model {}", name),
+            Self::UnknownJob(id) => write!(f, "unknown job {}", id),
+            Self::JobNotFinished(id) => write!(f, "job {} has no result yet", id),
+            Self::NoPreview(id) => write!(f, "job {} did not ask for a preview", id),
+            Self::NotAcceptable(e) => write!(f, "not acceptable: {}", e),
+            Self::ModelUnavailable => write!(f, "no model is loaded"),
+            Self::Internal(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            tracing::error!("{}", self);
+        }
+        let message = self.to_string();
+        let body = ErrorBody { error: ErrorDetail { code: self.code(), message: &message } };
+        (status, Json(body)).into_response()
+    }
+}
+
+impl From<JsonRejection> for ApiError {
+    fn from(rejection: JsonRejection) -> Self {
+        match rejection {
+            // well-formed JSON that doesn't fit the request type, e.g. a missing prompt
+            JsonRejection::JsonDataError(e) => Self::InvalidRequest(e.body_text()),
+            // the body went over the server's body limit while it was read
+            JsonRejection::BytesRejection(e) if e.status() == StatusCode::PAYLOAD_TOO_LARGE => Self::BodyTooLarge(e.body_text()),
+            e => Self::MalformedJson(e.body_text()),
+        }
+    }
+}
+
+impl From<MultipartError> for ApiError {
+    fn from(e: MultipartError) -> Self {
+        match e.status() {
+            StatusCode::PAYLOAD_TOO_LARGE => Self::BodyTooLarge(e.body_text()),
+            _ => Self::MalformedUpload(e.body_text()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{body::Body, extract::{FromRequest, Request}, http::header};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn bodies_over_the_limit_are_too_large() {
+        // axum's default limit is 2 MB
+        let body = format!("{{\"prompt\": \"{}\"}}", "a".repeat(3 * 1024 * 1024));
+        let request = Request::post("/").header(header::CONTENT_TYPE, "application/json").body(Body::from(body)).unwrap();
+        let rejection = Json::<serde_json::Value>::from_request(request, &()).await.unwrap_err();
+        let error = ApiError::from(rejection);
+        assert_eq!(error.status(), StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(error.code(), "body_too_large");
+    }
+}
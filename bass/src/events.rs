@@ -0,0 +1,169 @@
+use axum::response::sse::Event;
+use base64::{Engine, engine::general_purpose::STANDARD};
+use serde::Serialize;
+
+
+// Version of the event schema below, bumped whenever a field changes meaning or goes away
+pub const PROTOCOL_VERSION: u32 = 1;
+
+// Formats a `result` artifact can come in
+pub const OUTPUT_FORMATS: &[&str] = &["text", "midi", "midi0", "musicxml", "abc", "notes", "wav", "flac"];
+
+// Where the final output of a generation can be found. Streams carry it inline, base64 encoded
+// when it is binary, jobs link to a download instead.
+#[derive(Serialize, Clone, Debug)]
+pub struct Artifact {
+    pub content_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub data: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoding: Option<&'static str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub url: Option<String>,
+}
+
+impl Artifact {
+    pub fn text(text: String) -> Self {
+        Self {
+            content_type: String::from("text/plain; charset=utf-8"),
+            data: Some(text),
+            encoding: None,
+            url: None,
+        }
+    }
+
+    pub fn binary(content_type: &str, bytes: &[u8]) -> Self {
+        Self {
+            content_type: content_type.to_string(),
+            data: Some(STANDARD.encode(bytes)),
+            encoding: Some("base64"),
+            url: None,
+        }
+    }
+
+    pub fn link(content_type: String, url: String) -> Self {
+        Self { content_type, data: None, encoding: None, url: Some(url) }
+    }
+
+    // The inline payload as raw bytes
+    pub fn bytes(&self) -> Option<Vec<u8>> {
+        let data = self.data.as_ref()?;
+        match self.encoding {
+            Some(_) => STANDARD.decode(data).ok(),
+            None => Some(data.clone().into_bytes()),
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Debug, Default)]
+pub struct Timings {
+    pub prompt_tokens: usize,
+    pub generated_tokens: usize,
+    pub elapsed_ms: u64,
+    // `None` when the generation stopped before its first token
+    pub time_to_first_token_ms: Option<u64>,
+    pub tokens_per_second: f32,
+}
+
+// How one of a request's variations turned out, for clients to rank them
+#[derive(Serialize, Clone, Debug)]
+pub struct CandidateSummary {
+    pub candidate: usize,
+    pub seed: u64,
+    pub generated_tokens: usize,
+    // mean log-probability of the generated tokens, `None` when there are none
+    pub mean_logprob: Option<f32>,
+    // whether the candidate produced a result
+    pub ok: bool,
+}
+
+// Everything a generation reports to its client. A stream always opens with `started` and closes
+// with `summary` and `done`. Per-candidate events carry the index of the variation they belong
+// to, an `error` for a candidate means no `result` follows for it.
+#[derive(Serialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum GenerationEvent {
+    Started {
+        request_id: String,
+        model: String,
+        // seed of the first candidate
+        seed: u64,
+    },
+    Token {
+        candidate: usize,
+        id: i64,
+        text: String,
+        step: usize,
+        logprob: f32,
+    },
+    Progress {
+        fraction: f32,
+    },
+    // a denoising step of a diffusion model finished
+    Denoise {
+        candidate: usize,
+        // 1-based, the last one is `steps`
+        step: usize,
+        steps: usize,
+        // training timestep the step started from
+        timestep: usize,
+    },
+    Result {
+        candidate: usize,
+        artifact: Artifact,
+        // the notes rendered to WAV, when the request asked for a preview
+        #[serde(skip_serializing_if = "Option::is_none")]
+        preview: Option<Artifact>,
+    },
+    Error {
+        // `None` when the whole request failed
+        #[serde(skip_serializing_if = "Option::is_none")]
+        candidate: Option<usize>,
+        code: &'static str,
+        message: String,
+    },
+    Summary {
+        candidates: Vec<CandidateSummary>,
+    },
+    Done {
+        timings: Timings,
+    },
+}
+
+#[derive(Serialize)]
+struct Envelope<'a> {
+    version: u32,
+    #[serde(flatten)]
+    event: &'a GenerationEvent,
+}
+
+impl GenerationEvent {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Started { .. } => "started",
+            Self::Token { .. } => "token",
+            Self::Progress { .. } => "progress",
+            Self::Denoise { .. } => "denoise",
+            Self::Result { .. } => "result",
+            Self::Error { .. } => "error",
+            Self::Summary { .. } => "summary",
+            Self::Done { .. } => "done",
+        }
+    }
+
+    // SSE frame named after the event type, with the versioned JSON body as data and the
+    // position in the stream as id
+    pub fn to_sse(&self, id: usize) -> Event {
+        let envelope = Envelope { version: PROTOCOL_VERSION, event: self };
+        Event::default()
+            .event(self.kind())
+            .id(id.to_string())
+            .json_data(envelope)
+            .unwrap_or_else(|e| Event::default().event("error").data(e.to_string()))
+    }
+}
+
+// Short random id that ties log lines and events of one request together
+pub fn request_id() -> String {
+    format!("{:016x}", rand::random::<u64>())
+}
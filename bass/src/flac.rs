@@ -0,0 +1,397 @@
+use crate::wav::{self, SampleFormat};
+
+
+pub const FLAC_CONTENT_TYPE: &str = "audio/flac";
+
+// Samples per channel in every frame but the last
+const BLOCK_SIZE: usize = 4096;
+// What the 3-bit channel count and the 20-bit sample rate of STREAMINFO hold
+const MAX_CHANNELS: u16 = 8;
+const MAX_SAMPLE_RATE: u32 = (1 << 20) - 1;
+// Largest Rice parameter tried, 15 is the escape code
+const MAX_RICE_PARAMETER: u32 = 14;
+
+// Writes interleaved samples in [-1, 1] as a FLAC stream of 16 or 24-bit integers, clipping what
+// is out of range. Each channel of each frame is stored with the fixed predictor that codes it
+// smallest, or verbatim. The STREAMINFO MD5 is left zero, which means it wasn't computed.
+pub fn encode(samples: &[f32], channels: u16, sample_rate: u32, format: SampleFormat) -> anyhow::Result<Vec<u8>> {
+    if format == SampleFormat::Float32 {
+        anyhow::bail!("FLAC stores only integer samples");
+    }
+    if channels > MAX_CHANNELS {
+        anyhow::bail!("FLAC stores at most {} channels, not {}", MAX_CHANNELS, channels);
+    }
+    if !(1..=MAX_SAMPLE_RATE).contains(&sample_rate) {
+        anyhow::bail!("FLAC stores sample rates from 1 to {} Hz, not {}", MAX_SAMPLE_RATE, sample_rate);
+    }
+    let bits = format.bits() as u32;
+    let channels = channels.max(1) as usize;
+    let frames = samples.len() / channels;
+    let samples: Vec<i32> = samples[..frames * channels].iter().map(|&s| wav::quantize(s, format)).collect();
+
+    let mut out = BitWriter::default();
+    out.bytes(b"fLaC");
+    // the only metadata block, last and of type STREAMINFO
+    out.bits(1, 1);
+    out.bits(0, 7);
+    out.bits(34, 24);
+    // the last block may be shorter than the minimum
+    out.bits(BLOCK_SIZE as u64, 16);
+    out.bits(BLOCK_SIZE as u64, 16);
+    out.bits(0, 24);
+    out.bits(0, 24);
+    out.bits(sample_rate as u64, 20);
+    out.bits(channels as u64 - 1, 3);
+    out.bits(bits as u64 - 1, 5);
+    out.bits(frames as u64, 36);
+    out.bytes(&[0; 16]);
+
+    let mut out = out.finish();
+    for (number, block) in samples.chunks(BLOCK_SIZE * channels).enumerate() {
+        write_frame(&mut out, number as u32, block, channels, bits);
+    }
+    Ok(out)
+}
+
+fn write_frame(out: &mut Vec<u8>, number: u32, block: &[i32], channels: usize, bits: u32) {
+    let len = block.len() / channels;
+    let mut frame = BitWriter::default();
+    // sync code and fixed blocking, the block size as 16 bits at the end of the header, the
+    // sample rate from STREAMINFO and independent channels
+    frame.bits(0xFFF8, 16);
+    frame.bits(0b0111, 4);
+    frame.bits(0b0000, 4);
+    frame.bits(channels as u64 - 1, 4);
+    frame.bits(if bits == 16 { 0b100 } else { 0b110 }, 3);
+    frame.bits(0, 1);
+    frame.bytes(&utf8_number(number));
+    frame.bits(len as u64 - 1, 16);
+    let crc = crc8(&frame.out);
+    frame.bits(crc as u64, 8);
+
+    for channel in 0..channels {
+        let samples: Vec<i64> = block.iter().skip(channel).step_by(channels).map(|&s| s as i64).collect();
+        write_subframe(&mut frame, &samples, bits);
+    }
+
+    let mut frame = frame.finish();
+    let crc = crc16(&frame);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    out.extend_from_slice(&frame);
+}
+
+// The cheapest of verbatim and the fixed predictors of order 0 to 4
+fn write_subframe(out: &mut BitWriter, samples: &[i64], bits: u32) {
+    let verbatim = samples.len() as u64 * bits as u64;
+    let best = (0..=4usize)
+        .filter(|&order| order < samples.len())
+        .map(|order| {
+            let residual = fixed_residual(samples, order);
+            let (parameter, cost) = rice_parameter(&residual);
+            (order, residual, parameter, order as u64 * bits as u64 + 10 + cost)
+        })
+        .min_by_key(|(_, _, _, cost)| *cost)
+        .filter(|(_, _, _, cost)| *cost < verbatim);
+
+    // a zero padding bit, then the subframe type and no wasted bits
+    match best {
+        Some((order, residual, parameter, _)) => {
+            out.bits(0, 1);
+            out.bits(0b001000 | order as u64, 6);
+            out.bits(0, 1);
+            for &warmup in &samples[..order] {
+                out.signed(warmup, bits);
+            }
+            // Rice coding with 4-bit parameters in a single partition
+            out.bits(0b00, 2);
+            out.bits(0, 4);
+            out.bits(parameter as u64, 4);
+            for &value in &residual {
+                out.rice(value, parameter);
+            }
+        }
+        None => {
+            out.bits(0, 1);
+            out.bits(0b000001, 6);
+            out.bits(0, 1);
+            for &sample in samples {
+                out.signed(sample, bits);
+            }
+        }
+    }
+}
+
+// What the fixed polynomial predictor of `order` misses, from the first sample it predicts
+fn fixed_residual(samples: &[i64], order: usize) -> Vec<i64> {
+    let mut residual = samples.to_vec();
+    for _ in 0..order {
+        residual = residual.windows(2).map(|pair| pair[1] - pair[0]).collect();
+    }
+    residual
+}
+
+// The Rice parameter that codes a residual smallest, and the bits it takes
+fn rice_parameter(residual: &[i64]) -> (u32, u64) {
+    let folded: Vec<u64> = residual.iter().map(|&v| zigzag(v)).collect();
+    (0..=MAX_RICE_PARAMETER)
+        .map(|k| (k, folded.iter().map(|&u| (u >> k) + 1 + k as u64).sum()))
+        .min_by_key(|&(_, cost)| cost)
+        .unwrap_or((0, 0))
+}
+
+fn zigzag(value: i64) -> u64 {
+    ((value << 1) ^ (value >> 63)) as u64
+}
+
+// Frame numbers are coded like UTF-8, extended to 31 bits
+fn utf8_number(number: u32) -> Vec<u8> {
+    if number < 0x80 {
+        return vec![number as u8];
+    }
+    let len = match number {
+        ..0x800 => 2,
+        0x800..0x1_0000 => 3,
+        0x1_0000..0x20_0000 => 4,
+        0x20_0000..0x400_0000 => 5,
+        _ => 6,
+    };
+    let lead = (0xFF00u16 >> len) as u8 | (number >> (6 * (len - 1))) as u8;
+    let mut out = vec![lead];
+    out.extend((0..len - 1).rev().map(|i| 0x80 | ((number >> (6 * i)) & 0x3F) as u8));
+    out
+}
+
+fn crc8(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |mut crc, &byte| {
+        crc ^= byte;
+        for _ in 0..8 {
+            crc = if crc & 0x80 != 0 { (crc << 1) ^ 0x07 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+fn crc16(bytes: &[u8]) -> u16 {
+    bytes.iter().fold(0u16, |mut crc, &byte| {
+        crc ^= (byte as u16) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x8005 } else { crc << 1 };
+        }
+        crc
+    })
+}
+
+// Packs values most significant bit first
+#[derive(Default)]
+struct BitWriter {
+    out: Vec<u8>,
+    acc: u64,
+    len: u32,
+}
+
+impl BitWriter {
+    fn bits(&mut self, value: u64, count: u32) {
+        for i in (0..count).rev() {
+            self.acc = (self.acc << 1) | ((value >> i) & 1);
+            self.len += 1;
+            if self.len == 8 {
+                self.out.push(self.acc as u8);
+                (self.acc, self.len) = (0, 0);
+            }
+        }
+    }
+
+    fn bytes(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.bits(byte as u64, 8);
+        }
+    }
+
+    // two's complement in `count` bits
+    fn signed(&mut self, value: i64, count: u32) {
+        self.bits(value as u64 & ((1 << count) - 1), count);
+    }
+
+    // the quotient in unary, ended by a one, then the low `parameter` bits
+    fn rice(&mut self, value: i64, parameter: u32) {
+        let folded = zigzag(value);
+        for _ in 0..folded >> parameter {
+            self.bits(0, 1);
+        }
+        self.bits(1, 1);
+        self.bits(folded, parameter);
+    }
+
+    // pads the last byte with zeros
+    fn finish(mut self) -> Vec<u8> {
+        if self.len > 0 {
+            self.bits(0, 8 - self.len);
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{Rng, SeedableRng, rngs::StdRng};
+
+    use super::*;
+
+    // Reads values most significant bit first
+    struct BitReader<'a> {
+        bytes: &'a [u8],
+        // in bits
+        pos: usize,
+    }
+
+    impl BitReader<'_> {
+        fn bits(&mut self, count: u32) -> u64 {
+            (0..count).fold(0, |value, _| {
+                let bit = (self.bytes[self.pos / 8] >> (7 - self.pos % 8)) & 1;
+                self.pos += 1;
+                (value << 1) | bit as u64
+            })
+        }
+
+        fn signed(&mut self, count: u32) -> i64 {
+            let value = self.bits(count) as i64;
+            if value >> (count - 1) & 1 == 1 { value - (1 << count) } else { value }
+        }
+
+        fn rice(&mut self, parameter: u32) -> i64 {
+            let mut quotient = 0;
+            while self.bits(1) == 0 {
+                quotient += 1;
+            }
+            let folded = (quotient << parameter) | self.bits(parameter);
+            (folded >> 1) as i64 ^ -((folded & 1) as i64)
+        }
+
+        fn byte_pos(&self) -> usize {
+            self.pos.div_ceil(8)
+        }
+    }
+
+    struct Decoded {
+        sample_rate: u32,
+        channels: usize,
+        bits: u32,
+        frames: u64,
+        samples: Vec<i32>,
+    }
+
+    // Just enough of a decoder for what `encode` writes, checking both CRCs of every frame
+    fn decode(bytes: &[u8]) -> Decoded {
+        assert_eq!(&bytes[..4], b"fLaC");
+        let mut reader = BitReader { bytes, pos: 32 };
+        assert_eq!((reader.bits(1), reader.bits(7), reader.bits(24)), (1, 0, 34));
+        assert_eq!((reader.bits(16), reader.bits(16)), (BLOCK_SIZE as u64, BLOCK_SIZE as u64));
+        reader.bits(48);
+        let sample_rate = reader.bits(20) as u32;
+        let channels = reader.bits(3) as usize + 1;
+        let bits = reader.bits(5) as u32 + 1;
+        let frames = reader.bits(36);
+        assert!((0..16).all(|_| reader.bits(8) == 0));
+
+        let mut samples = Vec::new();
+        let mut number = 0;
+        while reader.byte_pos() < bytes.len() {
+            let start = reader.byte_pos();
+            assert_eq!((reader.bits(16), reader.bits(4), reader.bits(4)), (0xFFF8, 0b0111, 0));
+            assert_eq!(reader.bits(4) as usize, channels - 1);
+            assert_eq!(reader.bits(3), if bits == 16 { 0b100 } else { 0b110 });
+            assert_eq!(reader.bits(1), 0);
+            let lead = reader.bits(8) as u8;
+            let ones = lead.leading_ones();
+            let mut coded = (lead & (0x7F >> ones)) as u32;
+            for _ in 1..ones {
+                coded = (coded << 6) | (reader.bits(8) as u32 & 0x3F);
+            }
+            assert_eq!(coded, number);
+            let len = reader.bits(16) as usize + 1;
+            let header_crc = crc8(&bytes[start..reader.byte_pos()]);
+            assert_eq!(reader.bits(8) as u8, header_crc);
+
+            let mut block = vec![Vec::with_capacity(len); channels];
+            for channel in &mut block {
+                assert_eq!(reader.bits(1), 0);
+                let kind = reader.bits(6);
+                assert_eq!(reader.bits(1), 0);
+                if kind == 0b000001 {
+                    channel.extend((0..len).map(|_| reader.signed(bits)));
+                    continue;
+                }
+                assert_eq!(kind & 0b111000, 0b001000);
+                let order = (kind & 0b111) as usize;
+                channel.extend((0..order).map(|_| reader.signed(bits)));
+                assert_eq!((reader.bits(2), reader.bits(4)), (0, 0));
+                let parameter = reader.bits(4) as u32;
+                for _ in order..len {
+                    let residual = reader.rice(parameter);
+                    let s = |back: usize| channel[channel.len() - back];
+                    let prediction = match order {
+                        0 => 0,
+                        1 => s(1),
+                        2 => 2 * s(1) - s(2),
+                        3 => 3 * s(1) - 3 * s(2) + s(3),
+                        _ => 4 * s(1) - 6 * s(2) + 4 * s(3) - s(4),
+                    };
+                    channel.push(prediction + residual);
+                }
+            }
+            reader.pos = reader.byte_pos() * 8;
+            let frame_crc = crc16(&bytes[start..reader.byte_pos()]);
+            assert_eq!(reader.bits(16) as u16, frame_crc);
+
+            samples.extend((0..len).flat_map(|i| block.iter().map(move |channel| channel[i] as i32)));
+            number += 1;
+        }
+        Decoded { sample_rate, channels, bits, frames, samples }
+    }
+
+    // A sine, noise, silence and clipped samples, so every predictor and the verbatim subframe
+    // get a turn
+    fn signal(frames: usize, channels: usize) -> Vec<f32> {
+        let mut rng = StdRng::seed_from_u64(3);
+        (0..frames * channels)
+            .map(|i| match (i / channels) * 4 / frames {
+                0 => (i as f32 * 0.01).sin() * 0.8,
+                1 => rng.random_range(-1.0..1.0),
+                2 => 0.0,
+                _ => if i % 7 < 3 { 1.5 } else { -1.5 },
+            })
+            .collect()
+    }
+
+    #[test]
+    fn round_trips_through_a_decoder() {
+        for (channels, format) in [(2, SampleFormat::Pcm16), (1, SampleFormat::Pcm24), (6, SampleFormat::Pcm16)] {
+            // two full blocks and a short one
+            let frames = 2 * BLOCK_SIZE + 1000;
+            let samples = signal(frames, channels);
+            let decoded = decode(&encode(&samples, channels as u16, 44_100, format).unwrap());
+            assert_eq!(decoded.sample_rate, 44_100);
+            assert_eq!(decoded.channels, channels);
+            assert_eq!(decoded.bits, format.bits() as u32);
+            assert_eq!(decoded.frames, frames as u64);
+            let expected: Vec<i32> = samples.iter().map(|&s| wav::quantize(s, format)).collect();
+            assert_eq!(decoded.samples, expected);
+        }
+    }
+
+    #[test]
+    fn frame_numbers_are_coded_like_utf8() {
+        assert_eq!(utf8_number(0x7F), vec![0x7F]);
+        assert_eq!(utf8_number(0x80), vec![0xC2, 0x80]);
+        assert_eq!(utf8_number(0x800), vec![0xE0, 0xA0, 0x80]);
+        assert_eq!(utf8_number(0x1_0000), vec![0xF0, 0x90, 0x80, 0x80]);
+    }
+
+    #[test]
+    fn rejects_what_streaminfo_cannot_hold() {
+        assert!(encode(&[0.0; 9], 9, 44_100, SampleFormat::Pcm16).is_err());
+        assert!(encode(&[0.0; 2], 2, 1 << 20, SampleFormat::Pcm16).is_err());
+        assert!(encode(&[0.0; 2], 2, 0, SampleFormat::Pcm16).is_err());
+        assert!(encode(&[0.0; 8], 8, MAX_SAMPLE_RATE, SampleFormat::Pcm24).is_ok());
+    }
+}
@@ -0,0 +1,170 @@
+use axum::http::{HeaderMap, header};
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::{
+    abc::ABC_CONTENT_TYPE,
+    error::ApiError,
+    flac::FLAC_CONTENT_TYPE,
+    manifest::Modality,
+    midi::MIDI_CONTENT_TYPE,
+    musicxml::MUSICXML_CONTENT_TYPE,
+    wav::WAV_CONTENT_TYPE
+};
+
+
+pub const NOTES_CONTENT_TYPE: &str = "application/json";
+const TEXT_CONTENT_TYPE: &str = "text/plain; charset=utf-8";
+
+// Media types that leave the format to the server, the event stream is the default response
+const OPEN_MEDIA_TYPES: [&str; 2] = ["*/*", "text/event-stream"];
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputFormat {
+    // detokenized text
+    Text,
+    // format 1 Standard MIDI File, one track per instrument
+    #[serde(alias = "smf1")]
+    Midi,
+    // format 0 Standard MIDI File, a single track
+    #[serde(alias = "smf0")]
+    Midi0,
+    // uncompressed MusicXML, one part per instrument
+    #[value(name = "musicxml")]
+    MusicXml,
+    // ABC notation, one voice per instrument voice
+    Abc,
+    // the notes, tempos and time signatures as JSON
+    #[serde(alias = "json")]
+    Notes,
+    // RIFF WAVE, only from audio models
+    Wav,
+    // FLAC, only from audio models
+    Flac,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Self::Text => "txt",
+            Self::Midi | Self::Midi0 => "mid",
+            Self::MusicXml => "musicxml",
+            Self::Abc => "abc",
+            Self::Notes => "json",
+            Self::Wav => "wav",
+            Self::Flac => "flac",
+        }
+    }
+
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::Text => TEXT_CONTENT_TYPE,
+            Self::Midi | Self::Midi0 => MIDI_CONTENT_TYPE,
+            Self::MusicXml => MUSICXML_CONTENT_TYPE,
+            Self::Abc => ABC_CONTENT_TYPE,
+            Self::Notes => NOTES_CONTENT_TYPE,
+            Self::Wav => WAV_CONTENT_TYPE,
+            Self::Flac => FLAC_CONTENT_TYPE,
+        }
+    }
+
+    // Formats with the media type of an Accept header entry, without its parameters
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        match media_type.to_ascii_lowercase().as_str() {
+            "text/plain" => Some(Self::Text),
+            "audio/midi" | "audio/x-midi" | "audio/mid" => Some(Self::Midi),
+            "application/vnd.recordare.musicxml+xml" | "application/vnd.recordare.musicxml" => Some(Self::MusicXml),
+            "text/vnd.abc" => Some(Self::Abc),
+            "application/json" => Some(Self::Notes),
+            "audio/wav" | "audio/x-wav" | "audio/wave" | "audio/vnd.wave" => Some(Self::Wav),
+            "audio/flac" | "audio/x-flac" => Some(Self::Flac),
+            _ => None,
+        }
+    }
+
+    // Whether a model of this modality can produce the format
+    pub fn produced_by(self, modality: Modality) -> bool {
+        match self {
+            Self::Wav | Self::Flac => modality == Modality::Audio,
+            Self::Text | Self::Midi | Self::Midi0 | Self::MusicXml | Self::Abc | Self::Notes => modality == Modality::Midi,
+        }
+    }
+
+    // What a model of this modality answers with when the request doesn't say
+    pub fn default_for(modality: Modality) -> Self {
+        match modality {
+            Modality::Midi => Self::Text,
+            Modality::Audio => Self::Wav,
+        }
+    }
+}
+
+// The formats an Accept header asks for, most preferred first
+#[derive(Debug)]
+pub struct Accept {
+    formats: Vec<OutputFormat>,
+    // the header is missing or also accepts the event stream or anything
+    open: bool,
+}
+
+// No Accept header, anything goes
+impl Default for Accept {
+    fn default() -> Self {
+        Self { formats: Vec::new(), open: true }
+    }
+}
+
+impl Accept {
+    pub fn from_headers(headers: &HeaderMap) -> Self {
+        let Some(value) = headers.get(header::ACCEPT).and_then(|v| v.to_str().ok()) else {
+            return Self::default();
+        };
+
+        let mut open = false;
+        let mut ranked: Vec<(OutputFormat, f32)> = Vec::new();
+        for entry in value.split(',') {
+            let mut params = entry.split(';');
+            let media_type = params.next().unwrap_or_default().trim();
+            let quality = params
+                .filter_map(|p| p.trim().strip_prefix("q="))
+                .find_map(|q| q.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            if quality <= 0.0 {
+                continue;
+            }
+            if OPEN_MEDIA_TYPES.contains(&media_type) {
+                open = true;
+            } else if let Some(format) = OutputFormat::from_media_type(media_type) {
+                ranked.push((format, quality));
+            }
+        }
+        // stable, so equal qualities keep the header's order
+        ranked.sort_by(|a, b| b.1.total_cmp(&a.1));
+        Self { formats: ranked.into_iter().map(|(format, _)| format).collect(), open }
+    }
+
+    // The most preferred format a model of this modality produces. `None` leaves the choice to
+    // the server, an error means nothing the client accepts can be produced.
+    pub fn choose(&self, modality: Modality) -> Result<Option<OutputFormat>, ApiError> {
+        match self.formats.iter().find(|f| f.produced_by(modality)) {
+            Some(&format) => Ok(Some(format)),
+            None if self.open => Ok(None),
+            None => Err(ApiError::NotAcceptable(format!(
+                "a {} model can't produce any of the accepted formats",
+                if modality == Modality::Midi { "midi" } else { "audio" }
+            ))),
+        }
+    }
+
+    // Whether the client asked for a file rather than events, streaming stays the default
+    // otherwise
+    pub fn wants_file(&self) -> bool {
+        !self.open && !self.formats.is_empty()
+    }
+
+    // Whether a stored artifact of this content type is acceptable
+    pub fn allows(&self, content_type: &str) -> bool {
+        self.open || self.formats.iter().any(|f| f.content_type() == content_type)
+    }
+}
@@ -0,0 +1,363 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    convert::Infallible,
+    pin::pin,
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, Instant}
+};
+
+use axum::{
+    extract::{Json, Path, Query, State, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode, header},
+    response::{
+        IntoResponse, Response, Sse,
+        sse::{Event, KeepAlive}
+    }
+};
+use futures::{Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::{sync::watch, task::AbortHandle};
+
+use crate::{
+    error::ApiError,
+    events::{self, Artifact, GenerationEvent, Timings},
+    format::Accept,
+    model::{PromptRequest, generation_events, prepare},
+    registry::ModelRegistry,
+    wav::WAV_CONTENT_TYPE
+};
+
+
+// How long finished jobs and their results are kept around for clients to pick up
+const JOB_TTL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum JobStatus {
+    Queued,
+    Running,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+struct JobState {
+    status: JobStatus,
+    progress: f32,
+    // every event so far, the index is the SSE event id
+    events: Vec<GenerationEvent>,
+    // (content type, bytes) of each finished candidate's artifact
+    results: BTreeMap<usize, (String, Vec<u8>)>,
+    // WAV bytes of each preview, for requests that asked for one
+    previews: BTreeMap<usize, Vec<u8>>,
+    // set once the last event, `done`, was recorded
+    finished: Option<Instant>,
+}
+
+// A generation running in the background, independent of any client connection
+pub struct Job {
+    id: String,
+    model: String,
+    created: Instant,
+    state: Mutex<JobState>,
+    // holds the number of events, subscribers wake up whenever it changes
+    updates: watch::Sender<usize>,
+    task: Mutex<Option<AbortHandle>>,
+}
+
+impl Job {
+    fn new(id: String, model: String) -> Self {
+        Self {
+            id,
+            model,
+            created: Instant::now(),
+            state: Mutex::new(JobState {
+                status: JobStatus::Queued,
+                progress: 0.0,
+                events: Vec::new(),
+                results: BTreeMap::new(),
+                previews: BTreeMap::new(),
+                finished: None,
+            }),
+            updates: watch::Sender::new(0),
+            task: Mutex::new(None),
+        }
+    }
+
+    fn result_url(&self, candidate: usize) -> String {
+        match candidate {
+            0 => format!("/jobs/{}/result", self.id),
+            candidate => format!("/jobs/{}/result?candidate={}", self.id, candidate),
+        }
+    }
+
+    fn preview_url(&self, candidate: usize) -> String {
+        match candidate {
+            0 => format!("/jobs/{}/preview", self.id),
+            candidate => format!("/jobs/{}/preview?candidate={}", self.id, candidate),
+        }
+    }
+
+    // Records an event and updates the job's status from it. The inline artifact and preview of
+    // a `result` are kept for download and replaced with links to them.
+    fn push(&self, event: GenerationEvent) {
+        let mut state = self.state.lock().unwrap();
+        if state.finished.is_some() {
+            return;
+        }
+        let event = match event {
+            GenerationEvent::Started { .. } => {
+                state.status = JobStatus::Running;
+                event
+            }
+            GenerationEvent::Progress { fraction } => {
+                state.progress = fraction;
+                event
+            }
+            GenerationEvent::Result { candidate, artifact, preview } => {
+                state.results.insert(candidate, (artifact.content_type.clone(), artifact.bytes().unwrap_or_default()));
+                let artifact = Artifact::link(artifact.content_type, self.result_url(candidate));
+                let preview = preview.map(|preview| {
+                    state.previews.insert(candidate, preview.bytes().unwrap_or_default());
+                    Artifact::link(preview.content_type, self.preview_url(candidate))
+                });
+                GenerationEvent::Result { candidate, artifact, preview }
+            }
+            // a failed candidate only fails the job when no other candidate succeeds
+            GenerationEvent::Error { candidate: None, .. } => {
+                state.status = JobStatus::Failed;
+                event
+            }
+            GenerationEvent::Done { .. } => {
+                if state.status == JobStatus::Running {
+                    state.status = if state.results.is_empty() { JobStatus::Failed } else { JobStatus::Completed };
+                }
+                state.finished = Some(Instant::now());
+                event
+            }
+            GenerationEvent::Token { .. }
+            | GenerationEvent::Denoise { .. }
+            | GenerationEvent::Error { .. }
+            | GenerationEvent::Summary { .. } => event,
+        };
+        state.events.push(event);
+        self.updates.send_replace(state.events.len());
+    }
+
+    // Stops the generation, a no-op for jobs that already finished. The event log still ends
+    // with `error` and `done` like any other failed generation.
+    fn cancel(&self) {
+        if let Some(task) = self.task.lock().unwrap().take() {
+            task.abort();
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.finished.is_some() {
+            return;
+        }
+        tracing::info!("job {} cancelled", self.id);
+        let generated_tokens = state.events.iter()
+            .filter(|e| matches!(e, GenerationEvent::Token { .. }))
+            .count();
+        state.events.push(GenerationEvent::Error {
+            candidate: None,
+            code: "cancelled",
+            message: String::from("the job was cancelled"),
+        });
+        state.events.push(GenerationEvent::Done {
+            timings: Timings {
+                generated_tokens,
+                elapsed_ms: self.created.elapsed().as_millis() as u64,
+                ..Timings::default()
+            },
+        });
+        state.status = JobStatus::Cancelled;
+        state.finished = Some(Instant::now());
+        self.updates.send_replace(state.events.len());
+    }
+
+    fn info(&self) -> JobInfo {
+        let state = self.state.lock().unwrap();
+        JobInfo {
+            id: self.id.clone(),
+            model: self.model.clone(),
+            status: state.status,
+            progress: state.progress,
+            events: state.events.len(),
+            result: state.results.keys().next().map(|_| format!("/jobs/{}/result", self.id)),
+            results: state.results.keys().map(|&candidate| self.result_url(candidate)).collect(),
+            previews: state.previews.keys().map(|&candidate| self.preview_url(candidate)).collect(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct JobInfo {
+    id: String,
+    model: String,
+    status: JobStatus,
+    progress: f32,
+    events: usize,
+    // the first candidate's result
+    #[serde(skip_serializing_if = "Option::is_none")]
+    result: Option<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    results: Vec<String>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    previews: Vec<String>,
+}
+
+#[derive(Deserialize)]
+pub struct ResultQuery {
+    candidate: Option<usize>,
+}
+
+#[derive(Default)]
+pub struct JobStore {
+    jobs: RwLock<HashMap<String, Arc<Job>>>,
+}
+
+impl JobStore {
+    fn get(&self, id: &str) -> Result<Arc<Job>, ApiError> {
+        self.jobs.read().unwrap()
+            .get(id)
+            .cloned()
+            .ok_or_else(|| ApiError::UnknownJob(id.to_string()))
+    }
+
+    fn insert(&self, job: Arc<Job>) {
+        let mut jobs = self.jobs.write().unwrap();
+        // drop jobs nobody picked up in time
+        jobs.retain(|_, job| {
+            job.state.lock().unwrap().finished.is_none_or(|finished| finished.elapsed() < JOB_TTL)
+        });
+        jobs.insert(job.id.clone(), job);
+    }
+}
+
+// Starts a generation in the background and answers with its id right away. The Accept header
+// is about that answer, so only `output_format` picks the result's format.
+pub async fn create(
+    State(registry): State<Arc<ModelRegistry>>,
+    State(store): State<Arc<JobStore>>,
+    body: Result<Json<PromptRequest>, JsonRejection>
+) -> Result<Response, ApiError> {
+    let Json(body) = body?;
+    let generation = prepare(&registry, body, &Accept::default())?;
+
+    let id = events::request_id();
+    let job = Arc::new(Job::new(id.clone(), generation.model.name.clone()));
+    store.insert(Arc::clone(&job));
+
+    let events = generation_events(id.clone(), generation);
+    let task = tokio::spawn({
+        let job = Arc::clone(&job);
+        async move {
+            let mut events = pin!(events);
+            while let Some(event) = events.next().await {
+                job.push(event);
+            }
+        }
+    });
+    *job.task.lock().unwrap() = Some(task.abort_handle());
+
+    let location = format!("/jobs/{}", id);
+    Ok((StatusCode::ACCEPTED, [(header::LOCATION, location)], Json(job.info())).into_response())
+}
+
+pub async fn status(
+    State(store): State<Arc<JobStore>>,
+    Path(id): Path<String>
+) -> Result<Json<JobInfo>, ApiError> {
+    Ok(Json(store.get(&id)?.info()))
+}
+
+pub async fn cancel(
+    State(store): State<Arc<JobStore>>,
+    Path(id): Path<String>
+) -> Result<Json<JobInfo>, ApiError> {
+    let job = store.get(&id)?;
+    job.cancel();
+    Ok(Json(job.info()))
+}
+
+// Replays the job's events and follows it until it finishes. A `Last-Event-ID` header resumes
+// after the event with that id.
+pub async fn events(
+    State(store): State<Arc<JobStore>>,
+    Path(id): Path<String>,
+    headers: HeaderMap
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, ApiError> {
+    let job = store.get(&id)?;
+    let from = headers.get("last-event-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.trim().parse::<usize>().ok())
+        .map_or(0, |last| last + 1);
+
+    let stream = async_stream_lite::async_stream(|yielder| async move {
+        let mut updates = job.updates.subscribe();
+        let mut next = from;
+        loop {
+            updates.borrow_and_update();
+            let (pending, finished) = {
+                let state = job.state.lock().unwrap();
+                (state.events.get(next..).unwrap_or_default().to_vec(), state.finished.is_some())
+            };
+            for event in pending {
+                yielder.r#yield(Ok(event.to_sse(next))).await;
+                next += 1;
+            }
+            if finished || updates.changed().await.is_err() {
+                break;
+            }
+        }
+    });
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new()))
+}
+
+// Downloads a candidate's artifact, the first finished candidate's when none is given. The
+// format was fixed when the job was created, an Accept header that excludes it is refused.
+pub async fn result(
+    State(store): State<Arc<JobStore>>,
+    Path(id): Path<String>,
+    Query(query): Query<ResultQuery>,
+    headers: HeaderMap
+) -> Result<Response, ApiError> {
+    let job = store.get(&id)?;
+    let state = job.state.lock().unwrap();
+    let result = match query.candidate {
+        Some(candidate) => state.results.get(&candidate),
+        None => state.results.values().next(),
+    };
+    let Some((content_type, bytes)) = result else {
+        return Err(ApiError::JobNotFinished(id));
+    };
+    if !Accept::from_headers(&headers).allows(content_type) {
+        return Err(ApiError::NotAcceptable(format!("the result of job {} is {}", id, content_type)));
+    }
+    Ok(([(header::CONTENT_TYPE, content_type.clone())], bytes.clone()).into_response())
+}
+
+// Downloads a candidate's WAV preview, the first finished candidate's when none is given
+pub async fn preview(
+    State(store): State<Arc<JobStore>>,
+    Path(id): Path<String>,
+    Query(query): Query<ResultQuery>,
+    headers: HeaderMap
+) -> Result<Response, ApiError> {
+    let job = store.get(&id)?;
+    let state = job.state.lock().unwrap();
+    let preview = match query.candidate {
+        Some(candidate) => state.previews.get(&candidate),
+        None => state.previews.values().next(),
+    };
+    let Some(bytes) = preview else {
+        return match state.finished {
+            Some(_) => Err(ApiError::NoPreview(id)),
+            None => Err(ApiError::JobNotFinished(id)),
+        };
+    };
+    if !Accept::from_headers(&headers).allows(WAV_CONTENT_TYPE) {
+        return Err(ApiError::NotAcceptable(format!("the preview of job {} is {}", id, WAV_CONTENT_TYPE)));
+    }
+    Ok(([(header::CONTENT_TYPE, WAV_CONTENT_TYPE)], bytes.clone()).into_response())
+}
@@ -1,5 +1,192 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use clap::{Parser, Subcommand};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+
+use crate::{
+    audio::AudioParams,
+    conditioning::ConditioningFields,
+    config::{Config, ConfigArgs},
+    constraints::ConstraintParams,
+    format::OutputFormat,
+    mastering::MasteringParams,
+    model::PromptRequest,
+    postprocess::PostProcessParams,
+    sampler::SamplingParams,
+    synth::PreviewParams
+};
+
+mod abc;
+mod audio;
+mod conditioning;
+mod config;
+mod constraints;
+mod continuation;
+mod decoder;
+mod diffusion;
+mod dsp;
+mod error;
+mod events;
+mod flac;
+mod format;
+mod jobs;
+mod manifest;
+mod mastering;
+mod midi;
 mod model;
+mod music;
+mod musicxml;
+mod notation;
+mod postprocess;
+mod registry;
+mod sampler;
+mod scheduler;
+mod soundfont;
+mod synth;
+mod wav;
+
+#[derive(Parser)]
+#[command(version, about = "Model server for the ahmad plugin")]
+struct Cli {
+    #[command(flatten)]
+    config: ConfigArgs,
+    #[command(subcommand)]
+    command: Command,
+}
+
+// parsed once at startup, the size difference between variants doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand)]
+enum Command {
+    /// Serve the model over HTTP
+    Serve,
+    /// Run a single prompt offline and write the result to a file
+    Generate {
+        prompt: String,
+        /// Model to generate with, the default model when missing
+        #[arg(long)]
+        model: Option<String>,
+        /// File the generated output is written to
+        #[arg(long, short)]
+        output: PathBuf,
+        /// Number of tokens to generate
+        #[arg(long)]
+        max_new_tokens: Option<usize>,
+        /// Number of candidates to generate, written next to the output as <name>-<n>.<ext>
+        #[arg(long)]
+        num_variations: Option<usize>,
+        /// Format of the output file, picked from its extension when missing
+        #[arg(long, value_enum)]
+        output_format: Option<OutputFormat>,
+        #[command(flatten)]
+        sampling: SamplingParams,
+        #[command(flatten)]
+        conditioning: ConditioningFields,
+        #[command(flatten)]
+        constraints: ConstraintParams,
+        #[command(flatten)]
+        post_process: PostProcessParams,
+        #[command(flatten)]
+        preview: PreviewParams,
+        #[command(flatten)]
+        audio: AudioParams,
+        #[command(flatten)]
+        mastering: MasteringParams,
+    },
+    /// Print the model's inputs and outputs and the tokenizer's vocabulary
+    Inspect {
+        /// Model to inspect, the default model when missing
+        #[arg(long)]
+        model: Option<String>,
+    },
+}
+
+#[tokio::main]
+async fn main() -> anyhow::Result<()> {
+    let cli = Cli::parse();
+    let config = Config::load(&cli.config)?;
+
+    // Initialize tracing to recieve debug messages from "ort"
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::EnvFilter::try_new(&config.log_filter)?)
+        .with(tracing_subscriber::fmt::layer())
+        .init();
+
+    let app_state = model::load(&config)?;
+    match cli.command {
+        Command::Serve => model::serve(&config, app_state).await,
+        Command::Generate {
+            prompt,
+            model,
+            output,
+            max_new_tokens,
+            num_variations,
+            output_format,
+            sampling,
+            conditioning,
+            constraints,
+            post_process,
+            preview,
+            audio,
+            mastering,
+        } => {
+            let output_format = output_format.or_else(|| {
+                match output.extension().and_then(|e| e.to_str()) {
+                    Some("mid" | "midi") => Some(OutputFormat::Midi),
+                    Some("musicxml" | "xml") => Some(OutputFormat::MusicXml),
+                    Some("abc") => Some(OutputFormat::Abc),
+                    Some("json") => Some(OutputFormat::Notes),
+                    Some("wav") => Some(OutputFormat::Wav),
+                    Some("flac") => Some(OutputFormat::Flac),
+                    Some("txt") => Some(OutputFormat::Text),
+                    // the model's own default
+                    _ => None,
+                }
+            });
+            let request = PromptRequest {
+                prompt,
+                model,
+                max_new_tokens,
+                num_variations,
+                output_format,
+                stream: None,
+                sampling,
+                conditioning,
+                constraints,
+                post_process,
+                preview,
+                audio,
+                mastering,
+            };
+            let results = model::generate_offline(&app_state, request).await?;
+            let numbered = num_variations.is_some_and(|n| n > 1);
+            // numbered by candidate, so a failed candidate leaves a gap instead of shifting the rest
+            for (candidate, result, preview) in results {
+                let path = if numbered { numbered_path(&output, candidate) } else { output.clone() };
+                fs::write(&path, result)?;
+                tracing::info!("wrote {}", path.display());
+                // "take.mid" -> "take.preview.wav"
+                if let Some(preview) = preview {
+                    let path = path.with_extension("preview.wav");
+                    fs::write(&path, preview)?;
+                    tracing::info!("wrote {}", path.display());
+                }
+            }
+            Ok(())
+        }
+        Command::Inspect { model } => {
+            print!("{}", model::inspect(&app_state, model.as_deref())?);
+            Ok(())
+        }
+    }
+}
 
-fn main() {
-    println!("Hello, world!");
+// "take.mid" -> "take-2.mid"
+fn numbered_path(path: &Path, i: usize) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let name = match path.extension() {
+        Some(extension) => format!("{}-{}.{}", stem, i, extension.to_string_lossy()),
+        None => format!("{}-{}", stem, i),
+    };
+    path.with_file_name(name)
 }
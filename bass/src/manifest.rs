@@ -0,0 +1,520 @@
+use std::{fs, path::{Path, PathBuf}};
+
+use anyhow::{Context, bail};
+use ort::{session::Session, tensor::TensorElementType};
+use serde::{Deserialize, Serialize};
+use tokenizers::Tokenizer;
+
+use crate::diffusion::DiffusionSampler;
+
+
+// Special tokens used by the tokenizers we ship, looked up when a model comes without a manifest
+const BOS_TOKENS: [&str; 4] = ["<s>", "<bos>", "<|startoftext|>", "BOS_None"];
+const EOS_TOKENS: [&str; 5] = ["</s>", "<eos>", "<|endoftext|>", "[EOS]", "EOS_None"];
+const PAD_TOKENS: [&str; 3] = ["<pad>", "[PAD]", "PAD_None"];
+
+// What the token stream of a model represents
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Modality {
+    Midi,
+    Audio,
+}
+
+// Meaning of each axis of a tensor, in order
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Axis {
+    Batch,
+    Channel,
+    Sequence,
+    Vocab,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InputSpec {
+    pub input_ids: String,
+    pub layout: Vec<Axis>,
+    pub attention_mask: Option<String>,
+    pub position_ids: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct OutputSpec {
+    pub logits: String,
+    pub layout: Vec<Axis>,
+}
+
+// Name prefixes pairing past key/value inputs with the present outputs that feed them
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct CacheSpec {
+    pub past: String,
+    pub present: String,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct TokenSpec {
+    pub bos: Option<u32>,
+    pub eos: Vec<u32>,
+    pub pad: Option<u32>,
+}
+
+// Token vocabulary families of miditok, the names of our music tokens follow its conventions
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TokenScheme {
+    // bars and positions within them, notes as pitch, velocity and duration
+    Remi,
+    // time shifts between events, notes as note-on and note-off
+    MidiLike,
+}
+
+// Token ids framing a fill-in-the-middle prompt: prefix, then suffix, then the middle to generate
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct InfillSpec {
+    pub prefix: u32,
+    pub suffix: u32,
+    pub middle: u32,
+}
+
+// How the tokens of a midi model map to time
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub struct MusicSpec {
+    pub scheme: TokenScheme,
+    // resolution of position, duration and time-shift tokens
+    pub positions_per_beat: u32,
+    // resolution of the MIDI files written from the tokens
+    pub ticks_per_beat: u16,
+    // only models trained for infilling have these
+    pub infill: Option<InfillSpec>,
+}
+
+impl Default for MusicSpec {
+    fn default() -> Self {
+        Self {
+            scheme: TokenScheme::Remi,
+            positions_per_beat: 8,
+            ticks_per_beat: 480,
+            infill: None,
+        }
+    }
+}
+
+// Token name templates of the conditioning fields a model was trained with, `{}` stands for the
+// value. Fields without one are described in the prompt text instead.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct ConditioningSpec {
+    pub key: Option<String>,
+    // the nearest tempo and length the vocabulary has are used
+    pub tempo: Option<String>,
+    pub time_signature: Option<String>,
+    pub bars: Option<String>,
+    pub program: Option<String>,
+    pub genre: Option<String>,
+}
+
+// What turns the text conditioning into latents an audio model's vocoder can render
+#[derive(Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum GeneratorKind {
+    // the model file is a denoiser, run for a number of steps from noise
+    Diffusion,
+    // the model file is a token decoder described by `inputs` and `outputs`, its tokens are
+    // the vocoder's codes
+    Autoregressive,
+}
+
+// What a denoiser predicts from noisy latents
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Prediction {
+    // the noise
+    #[default]
+    Epsilon,
+    // the velocity, sqrt(alpha) * noise - sqrt(1 - alpha) * sample
+    #[serde(rename = "v_prediction")]
+    VPrediction,
+}
+
+// How the noise level grows over the training timesteps
+#[derive(Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum BetaSchedule {
+    Linear,
+    #[default]
+    ScaledLinear,
+}
+
+// Turns the prompt into the hidden states a denoiser attends to. The prompt is padded or cut to
+// `max_length` tokens so prompt and negative prompt line up.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TextEncoderSpec {
+    // relative to the model file
+    pub model: PathBuf,
+    pub input_ids: String,
+    pub attention_mask: Option<String>,
+    // [batch, sequence, hidden]
+    pub output: String,
+    pub max_length: usize,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct DenoiserSpec {
+    // [batch, latent_channels, frames]
+    pub sample: String,
+    // [batch], int64 or float32
+    pub timestep: String,
+    pub encoder_hidden_states: String,
+    // the text encoder's attention mask, for denoisers that take it
+    pub encoder_attention_mask: Option<String>,
+    pub output: String,
+    #[serde(default)]
+    pub prediction: Prediction,
+    #[serde(default = "default_train_timesteps")]
+    pub train_timesteps: usize,
+    #[serde(default = "default_beta_start")]
+    pub beta_start: f64,
+    #[serde(default = "default_beta_end")]
+    pub beta_end: f64,
+    #[serde(default)]
+    pub beta_schedule: BetaSchedule,
+    // latents are divided by this before the vocoder
+    #[serde(default = "default_scaling_factor")]
+    pub scaling_factor: f32,
+}
+
+// Renders latents, or codes of an autoregressive generator, to a waveform
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct VocoderSpec {
+    // relative to the model file
+    pub model: PathBuf,
+    // float32 [1, latent_channels, frames] after diffusion, int64 [1, frames] after an
+    // autoregressive generator
+    pub input: String,
+    // float32 [1, samples] or [1, channels, samples]
+    pub output: String,
+    // id of the first code in the decoder's vocabulary, subtracted from generated tokens
+    #[serde(default)]
+    pub code_offset: i64,
+}
+
+// The stages of an audio model, each one an ONNX graph
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct AudioSpec {
+    pub generator: GeneratorKind,
+    pub sample_rate: u32,
+    #[serde(default = "default_channels")]
+    pub channels: u16,
+    // latent frames, or generated tokens, per second of audio
+    pub frames_per_second: f32,
+    // only for diffusion
+    #[serde(default)]
+    pub latent_channels: usize,
+    #[serde(default = "default_duration")]
+    pub default_duration_secs: f32,
+    pub max_duration_secs: f32,
+    // denoising steps and sampler when the request doesn't pick them
+    #[serde(default = "default_steps")]
+    pub steps: usize,
+    #[serde(default)]
+    pub sampler: DiffusionSampler,
+    // classifier-free guidance scale, 1.0 skips the unconditional pass
+    #[serde(default = "default_guidance_scale")]
+    pub guidance_scale: f32,
+    // required for diffusion, autoregressive generators read the prompt as tokens
+    pub text_encoder: Option<TextEncoderSpec>,
+    pub denoiser: Option<DenoiserSpec>,
+    pub vocoder: VocoderSpec,
+}
+
+fn default_train_timesteps() -> usize { 1000 }
+fn default_beta_start() -> f64 { 0.00085 }
+fn default_beta_end() -> f64 { 0.012 }
+fn default_scaling_factor() -> f32 { 1.0 }
+fn default_channels() -> u16 { 1 }
+fn default_duration() -> f32 { 10.0 }
+fn default_steps() -> usize { 50 }
+fn default_guidance_scale() -> f32 { 3.0 }
+
+// Describes how to drive a model's graph, read from the TOML file next to the ONNX file
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    pub modality: Modality,
+    // the token decoder, every model has one except diffusion audio models
+    pub inputs: Option<InputSpec>,
+    pub outputs: Option<OutputSpec>,
+    pub cache: Option<CacheSpec>,
+    #[serde(default)]
+    pub tokens: TokenSpec,
+    // Longest sequence the model was trained on, prompt and generated tokens included
+    pub context_length: Option<usize>,
+    #[serde(default)]
+    pub music: MusicSpec,
+    #[serde(default)]
+    pub conditioning: ConditioningSpec,
+    // only for audio models
+    pub audio: Option<AudioSpec>,
+}
+
+impl Manifest {
+    // Reads `<model>.toml` next to the model file, or guesses a manifest from the graph when there is none
+    pub fn load(model_path: &Path, session: &Session, tokenizer: &Tokenizer) -> anyhow::Result<Self> {
+        let path = model_path.with_extension("toml");
+        let manifest = if path.is_file() {
+            let contents = fs::read_to_string(&path)
+                .with_context(|| format!("could not read manifest {}", path.display()))?;
+            toml::from_str(&contents)
+                .with_context(|| format!("invalid manifest {}", path.display()))?
+        } else {
+            tracing::warn!("no manifest at {}, inferring one from the graph", path.display());
+            Self::infer(session, tokenizer)?
+        };
+
+        manifest.validate(session)
+            .with_context(|| format!("manifest does not match model {}", model_path.display()))?;
+        Ok(manifest)
+    }
+
+    fn infer(session: &Session, tokenizer: &Tokenizer) -> anyhow::Result<Self> {
+        let has_input = |name: &str| session.inputs.iter().any(|i| i.name == name);
+
+        let Some(ids) = session.inputs.iter().find(|i| {
+            i.name != "attention_mask" && i.name != "position_ids" && !i.name.starts_with("past")
+        }) else {
+            bail!("model has no token input");
+        };
+        // our original exports take [1, 1, len], HF-style decoders take [1, len]
+        let input_layout = match ids.input_type.tensor_shape().map(|s| s.len()) {
+            Some(3) => vec![Axis::Batch, Axis::Channel, Axis::Sequence],
+            Some(2) => vec![Axis::Batch, Axis::Sequence],
+            _ => vec![Axis::Sequence],
+        };
+
+        let Some(logits) = ["output1", "logits"].into_iter()
+            .filter_map(|name| session.outputs.iter().find(|o| o.name == name))
+            .chain(session.outputs.iter().filter(|o| !o.name.starts_with("present")))
+            .next() else {
+            bail!("model has no logits output");
+        };
+        let output_layout = match logits.output_type.tensor_shape().map(|s| s.len()) {
+            Some(4) => vec![Axis::Batch, Axis::Channel, Axis::Sequence, Axis::Vocab],
+            Some(3) => vec![Axis::Batch, Axis::Sequence, Axis::Vocab],
+            Some(2) => vec![Axis::Batch, Axis::Vocab],
+            _ => vec![Axis::Vocab],
+        };
+
+        let cache = session.inputs.iter().any(|i| i.name.starts_with("past_key_values"))
+            .then(|| CacheSpec { past: String::from("past_key_values"), present: String::from("present") })
+            .or_else(|| session.inputs.iter().any(|i| i.name.starts_with("past"))
+                .then(|| CacheSpec { past: String::from("past"), present: String::from("present") }));
+
+        // a fixed sequence axis is the context window, dynamic ones leave it unknown
+        let context_length = ids.input_type.tensor_shape()
+            .and_then(|s| input_layout.iter().position(|&a| a == Axis::Sequence).and_then(|axis| s.get(axis).copied()))
+            .filter(|&d| d > 0)
+            .map(|d| d as usize);
+
+        let find = |candidates: &[&str]| candidates.iter().find_map(|t| tokenizer.token_to_id(t));
+        Ok(Self {
+            modality: Modality::Midi,
+            inputs: Some(InputSpec {
+                input_ids: ids.name.clone(),
+                layout: input_layout,
+                attention_mask: has_input("attention_mask").then(|| String::from("attention_mask")),
+                position_ids: has_input("position_ids").then(|| String::from("position_ids")),
+            }),
+            outputs: Some(OutputSpec {
+                logits: logits.name.clone(),
+                layout: output_layout,
+            }),
+            cache,
+            tokens: TokenSpec {
+                bos: find(&BOS_TOKENS),
+                eos: EOS_TOKENS.iter().filter_map(|t| tokenizer.token_to_id(t)).collect(),
+                pad: find(&PAD_TOKENS),
+            },
+            context_length,
+            music: MusicSpec::default(),
+            conditioning: ConditioningSpec::default(),
+            audio: None,
+        })
+    }
+
+    // The token decoder's input and output specs, `None` for diffusion audio models
+    pub fn decoder(&self) -> Option<(&InputSpec, &OutputSpec)> {
+        self.inputs.as_ref().zip(self.outputs.as_ref())
+    }
+
+    // Checks every name, rank and token id in the manifest against the loaded graph
+    pub fn validate(&self, session: &Session) -> anyhow::Result<()> {
+        let generator = self.audio.as_ref().map(|a| a.generator);
+        match (self.modality, generator) {
+            (Modality::Midi, Some(_)) => bail!("[audio] is only for audio models"),
+            (Modality::Audio, None) => bail!("audio models need an [audio] section"),
+            _ => {}
+        }
+        if let Some(audio) = &self.audio {
+            self.validate_audio(audio, session)?;
+        }
+
+        let decoder = self.decoder();
+        match (decoder, generator) {
+            (Some(_), Some(GeneratorKind::Diffusion)) => {
+                bail!("diffusion models are driven by [audio.denoiser], not [inputs] and [outputs]")
+            }
+            (None, Some(GeneratorKind::Diffusion)) => Ok(()),
+            (Some((inputs, outputs)), _) => self.validate_decoder(inputs, outputs, session),
+            (None, _) => bail!("[inputs] and [outputs] describe the token decoder and are required"),
+        }
+    }
+
+    fn validate_audio(&self, audio: &AudioSpec, session: &Session) -> anyhow::Result<()> {
+        if audio.sample_rate == 0 || audio.channels == 0 || audio.frames_per_second <= 0.0 {
+            bail!("audio.sample_rate, audio.channels and audio.frames_per_second must be positive");
+        }
+        if !(audio.default_duration_secs > 0.0 && audio.default_duration_secs <= audio.max_duration_secs) {
+            bail!("audio.default_duration_secs must be positive and at most audio.max_duration_secs");
+        }
+        if audio.guidance_scale < 1.0 {
+            bail!("audio.guidance_scale must be at least 1.0");
+        }
+        match audio.generator {
+            GeneratorKind::Diffusion => {
+                let (Some(_), Some(denoiser)) = (&audio.text_encoder, &audio.denoiser) else {
+                    bail!("diffusion models need [audio.text_encoder] and [audio.denoiser]");
+                };
+                if audio.latent_channels == 0 || audio.steps == 0 {
+                    bail!("audio.latent_channels and audio.steps must be positive");
+                }
+                if audio.steps > denoiser.train_timesteps {
+                    bail!("audio.steps can't exceed the denoiser's {} training timesteps", denoiser.train_timesteps);
+                }
+                let names = [&denoiser.sample, &denoiser.timestep, &denoiser.encoder_hidden_states];
+                for name in names.into_iter().chain(&denoiser.encoder_attention_mask) {
+                    if !session.inputs.iter().any(|i| &i.name == name) {
+                        bail!("graph has no input named {}", name);
+                    }
+                }
+                if !session.outputs.iter().any(|o| o.name == denoiser.output) {
+                    bail!("graph has no output named {}", denoiser.output);
+                }
+            }
+            GeneratorKind::Autoregressive => {
+                if audio.text_encoder.is_some() || audio.denoiser.is_some() {
+                    bail!("autoregressive models read the prompt as tokens, they take no text encoder or denoiser");
+                }
+                // the unconditional sequence of classifier-free guidance starts from it
+                if self.tokens.bos.is_none() {
+                    bail!("autoregressive audio models need a tokens.bos");
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn validate_decoder(&self, inputs: &InputSpec, outputs: &OutputSpec, session: &Session) -> anyhow::Result<()> {
+        let layout = &inputs.layout;
+        if layout.iter().filter(|&&a| a == Axis::Sequence).count() != 1 || layout.contains(&Axis::Vocab) {
+            bail!("inputs.layout must have exactly one \"sequence\" axis and no \"vocab\" axis");
+        }
+        let Some(ids) = session.inputs.iter().find(|i| i.name == inputs.input_ids) else {
+            bail!("graph has no input named {}", inputs.input_ids);
+        };
+        if ids.input_type.tensor_type() != Some(TensorElementType::Int64) {
+            bail!("input {} must be an int64 tensor, graph declares {}", ids.name, ids.input_type);
+        }
+        check_rank(&ids.name, ids.input_type.tensor_shape().map(|s| s.len()), layout.len())?;
+
+        for name in [&inputs.attention_mask, &inputs.position_ids].into_iter().flatten() {
+            if !session.inputs.iter().any(|i| &i.name == name) {
+                bail!("graph has no input named {}", name);
+            }
+        }
+
+        let layout = &outputs.layout;
+        if layout.iter().filter(|&&a| a == Axis::Vocab).count() != 1 || layout.iter().filter(|&&a| a == Axis::Sequence).count() > 1 {
+            bail!("outputs.layout must have exactly one \"vocab\" axis and at most one \"sequence\" axis");
+        }
+        let Some(logits) = session.outputs.iter().find(|o| o.name == outputs.logits) else {
+            bail!("graph has no output named {}", outputs.logits);
+        };
+        if logits.output_type.tensor_type() != Some(TensorElementType::Float32) {
+            bail!("output {} must be a float32 tensor, graph declares {}", logits.name, logits.output_type);
+        }
+        let shape = logits.output_type.tensor_shape();
+        check_rank(&logits.name, shape.map(|s| s.len()), layout.len())?;
+
+        // every graph input must be something we know how to feed
+        for input in &session.inputs {
+            let known = input.name == inputs.input_ids
+                || Some(&input.name) == inputs.attention_mask.as_ref()
+                || Some(&input.name) == inputs.position_ids.as_ref()
+                || self.cache.as_ref().is_some_and(|c| input.name.starts_with(&c.past));
+            if !known {
+                bail!("graph input {} is not described by the manifest", input.name);
+            }
+        }
+
+        if let Some(cache) = &self.cache {
+            for input in session.inputs.iter().filter(|i| i.name.starts_with(&cache.past)) {
+                let present = self.present_name(&input.name).unwrap_or_default();
+                if !session.outputs.iter().any(|o| o.name == present) {
+                    bail!("cache input {} has no matching output {}", input.name, present);
+                }
+            }
+        }
+
+        let conditioning = &self.conditioning;
+        let templates = [
+            &conditioning.key, &conditioning.tempo, &conditioning.time_signature,
+            &conditioning.bars, &conditioning.program, &conditioning.genre,
+        ];
+        for template in templates.into_iter().flatten() {
+            if !template.contains("{}") {
+                bail!("conditioning template {:?} has no {{}} for the value", template);
+            }
+        }
+
+        // vocab size is only known when the export fixed it
+        let logits_axis = layout.iter().position(|&a| a == Axis::Vocab).unwrap_or_default();
+        let vocab_size = shape
+            .and_then(|s| s.get(logits_axis).copied())
+            .filter(|&d| d > 0);
+        if let Some(vocab_size) = vocab_size {
+            let infill = self.music.infill.iter().flat_map(|i| [i.prefix, i.suffix, i.middle]);
+            let tokens = self.tokens.bos.iter().chain(&self.tokens.eos).chain(&self.tokens.pad).copied().chain(infill);
+            for id in tokens {
+                if id as i64 >= vocab_size {
+                    bail!("special token id {} is outside the model's vocab of {}", id, vocab_size);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // "past_key_values.0.key" -> "present.0.key"
+    pub fn present_name(&self, past: &str) -> Option<String> {
+        let cache = self.cache.as_ref()?;
+        past.strip_prefix(cache.past.as_str())
+            .map(|rest| format!("{}{}", cache.present, rest))
+    }
+}
+
+fn check_rank(name: &str, rank: Option<usize>, expected: usize) -> anyhow::Result<()> {
+    match rank {
+        Some(rank) if rank != expected => bail!("{} has rank {} but the manifest layout has {} axes", name, rank, expected),
+        _ => Ok(()),
+    }
+}
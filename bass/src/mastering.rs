@@ -0,0 +1,161 @@
+use clap::{Args, ValueEnum};
+use serde::Deserialize;
+
+use crate::{
+    dsp,
+    error::ApiError,
+    registry::Model
+};
+
+
+// Integrated loudness a request can normalize to, in LUFS
+const LOUDNESS_RANGE: std::ops::RangeInclusive<f32> = -60.0..=0.0;
+// Ceiling loudness normalization limits to when the request sets none, what EBU R128 asks for
+const DEFAULT_TRUE_PEAK_DB: f32 = -1.0;
+const MAX_FADE_MS: f32 = 10_000.0;
+const DEFAULT_LOOP_CROSSFADE_MS: f32 = 100.0;
+const MAX_LOOP_CROSSFADE_MS: f32 = 2_000.0;
+// How far from each end a zero-crossing loop looks for its cut
+const LOOP_SEARCH_MS: f32 = 50.0;
+
+// Clean-up of generated audio before it is encoded. It runs in a fixed order: trim, loop, fades,
+// loudness normalization, then limiting.
+#[derive(Deserialize, Args, Clone, Debug, Default)]
+#[serde(default)]
+pub struct MasteringParams {
+    /// Integrated loudness to normalize to in LUFS, e.g. -14, limited to -1 dBTP unless
+    /// true_peak_db is set
+    #[arg(long)]
+    pub loudness_lufs: Option<f32>,
+    /// Ceiling of the true-peak limiter in dBTP
+    #[arg(long)]
+    pub true_peak_db: Option<f32>,
+    /// Trims leading and trailing audio quieter than this level in dBFS, e.g. -60
+    #[arg(long)]
+    pub trim_silence_db: Option<f32>,
+    /// Length of a fade in from silence
+    #[arg(long)]
+    pub fade_in_ms: Option<f32>,
+    /// Length of a fade out to silence
+    #[arg(long)]
+    pub fade_out_ms: Option<f32>,
+    /// Makes the audio loop seamlessly
+    #[arg(long, value_enum)]
+    pub loop_mode: Option<LoopMode>,
+    /// Length of the tail crossfaded into the head by the crossfade loop mode
+    #[arg(long)]
+    pub loop_crossfade_ms: Option<f32>,
+}
+
+impl MasteringParams {
+    fn is_empty(&self) -> bool {
+        self.loudness_lufs.is_none()
+            && self.true_peak_db.is_none()
+            && self.trim_silence_db.is_none()
+            && self.fade_in_ms.is_none()
+            && self.fade_out_ms.is_none()
+            && self.loop_mode.is_none()
+            && self.loop_crossfade_ms.is_none()
+    }
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+#[value(rename_all = "snake_case")]
+pub enum LoopMode {
+    // cuts both ends at rising zero crossings
+    ZeroCrossing,
+    // crossfades the tail into the head
+    Crossfade,
+}
+
+// The options a request set, checked and with their defaults filled in
+#[derive(Clone, Debug)]
+pub struct Mastering {
+    loudness_lufs: Option<f32>,
+    true_peak_db: Option<f32>,
+    trim_silence_db: Option<f32>,
+    fade_in_ms: f32,
+    fade_out_ms: f32,
+    loop_mode: Option<LoopMode>,
+    loop_crossfade_ms: f32,
+}
+
+impl Mastering {
+    // `None` when the request set none of the options
+    pub fn new(params: MasteringParams, model: &Model) -> Result<Option<Self>, ApiError> {
+        if params.is_empty() {
+            return Ok(None);
+        }
+        if model.manifest.audio.is_none() {
+            return Err(ApiError::InvalidRequest(format!(
+                "model {} does not generate audio, loudness_lufs, true_peak_db, trim_silence_db, fade_in_ms, fade_out_ms and loop_mode need an audio model",
+                model.name
+            )));
+        }
+        let invalid = |message: String| Err(ApiError::InvalidRequest(message));
+        if let Some(lufs) = params.loudness_lufs && !LOUDNESS_RANGE.contains(&lufs) {
+            return invalid(format!("loudness_lufs must be between {} and {}", LOUDNESS_RANGE.start(), LOUDNESS_RANGE.end()));
+        }
+        if let Some(db) = params.true_peak_db && !(-20.0..=0.0).contains(&db) {
+            return invalid(String::from("true_peak_db must be between -20 and 0"));
+        }
+        if let Some(db) = params.trim_silence_db && !(-120.0..=-20.0).contains(&db) {
+            return invalid(String::from("trim_silence_db must be between -120 and -20"));
+        }
+        for (name, ms) in [("fade_in_ms", params.fade_in_ms), ("fade_out_ms", params.fade_out_ms)] {
+            if let Some(ms) = ms && !(0.0..=MAX_FADE_MS).contains(&ms) {
+                return invalid(format!("{} must be between 0 and {}", name, MAX_FADE_MS));
+            }
+        }
+        // fading to silence would put a gap in every repetition
+        if params.loop_mode.is_some() && (params.fade_in_ms.is_some() || params.fade_out_ms.is_some()) {
+            return invalid(String::from("loop_mode can't be combined with fades"));
+        }
+        match (params.loop_mode, params.loop_crossfade_ms) {
+            (Some(LoopMode::Crossfade), Some(ms)) if !(ms > 0.0 && ms <= MAX_LOOP_CROSSFADE_MS) => {
+                return invalid(format!("loop_crossfade_ms must be above 0 and at most {}", MAX_LOOP_CROSSFADE_MS));
+            }
+            (Some(LoopMode::Crossfade), _) | (_, None) => {}
+            (_, Some(_)) => return invalid(String::from("loop_crossfade_ms needs loop_mode crossfade")),
+        }
+
+        Ok(Some(Self {
+            loudness_lufs: params.loudness_lufs,
+            true_peak_db: params.true_peak_db.or(params.loudness_lufs.map(|_| DEFAULT_TRUE_PEAK_DB)),
+            trim_silence_db: params.trim_silence_db,
+            fade_in_ms: params.fade_in_ms.unwrap_or(0.0),
+            fade_out_ms: params.fade_out_ms.unwrap_or(0.0),
+            loop_mode: params.loop_mode,
+            loop_crossfade_ms: params.loop_crossfade_ms.unwrap_or(DEFAULT_LOOP_CROSSFADE_MS),
+        }))
+    }
+
+    // Runs the options that are set on interleaved samples. Silent audio is left at its level.
+    pub fn apply(&self, samples: Vec<f32>, channels: u16, sample_rate: u32) -> Vec<f32> {
+        let frames = |ms: f32| (ms / 1000.0 * sample_rate as f32).round() as usize;
+        let mut samples = samples;
+        if let Some(db) = self.trim_silence_db {
+            samples = dsp::trim(&samples, channels, from_db(db));
+        }
+        samples = match self.loop_mode {
+            Some(LoopMode::ZeroCrossing) => dsp::loop_at_zero_crossings(&samples, channels, frames(LOOP_SEARCH_MS)),
+            Some(LoopMode::Crossfade) => dsp::loop_crossfade(&samples, channels, frames(self.loop_crossfade_ms)),
+            None => samples,
+        };
+        dsp::fade(&mut samples, channels, frames(self.fade_in_ms), frames(self.fade_out_ms));
+        if let Some(target) = self.loudness_lufs
+            && let Some(lufs) = dsp::loudness(&samples, channels, sample_rate) {
+            let gain = from_db(target - lufs as f32);
+            samples.iter_mut().for_each(|s| *s *= gain);
+        }
+        if let Some(db) = self.true_peak_db {
+            dsp::limit(&mut samples, channels, sample_rate, from_db(db));
+        }
+        samples
+    }
+}
+
+fn from_db(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
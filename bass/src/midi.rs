@@ -0,0 +1,635 @@
+use std::collections::{HashMap, VecDeque};
+
+use anyhow::bail;
+use serde::Serialize;
+
+
+// General MIDI reserves channel 10 for percussion
+const DRUM_CHANNEL: u8 = 9;
+// SMF files without a tempo event play at 120 BPM
+const DEFAULT_TEMPO_US: u32 = 500_000;
+
+pub const MIDI_CONTENT_TYPE: &str = "audio/midi";
+
+// Which sound a note is played with
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Instrument {
+    Program(u8),
+    Drums,
+}
+
+// Times are in ticks, `Score::ticks_per_beat` to a quarter note
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Note {
+    pub start: u32,
+    pub duration: u32,
+    pub pitch: u8,
+    pub velocity: u8,
+    pub instrument: Instrument,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Tempo {
+    pub tick: u32,
+    pub bpm: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeSignature {
+    pub tick: u32,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+impl TimeSignature {
+    pub fn bar_ticks(&self, ticks_per_beat: u16) -> u32 {
+        self.numerator as u32 * ticks_per_beat as u32 * 4 / self.denominator.max(1) as u32
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SmfFormat {
+    // everything in one track
+    Single,
+    // a conductor track for tempo and meter, then one track per instrument
+    MultiTrack,
+}
+
+// A piece of music as notes on a tick grid, what token sequences decode to and MIDI files are read into
+#[derive(Clone, Debug, PartialEq)]
+pub struct Score {
+    pub ticks_per_beat: u16,
+    pub tempos: Vec<Tempo>,
+    pub time_signatures: Vec<TimeSignature>,
+    pub notes: Vec<Note>,
+}
+
+impl Score {
+    pub fn new(ticks_per_beat: u16) -> Self {
+        Self { ticks_per_beat, tempos: Vec::new(), time_signatures: Vec::new(), notes: Vec::new() }
+    }
+
+    // Orders notes by start, then instrument and pitch, so equal scores compare equal
+    pub fn sort(&mut self) {
+        self.notes.sort_by_key(|n| (n.start, n.instrument, n.pitch, n.duration, n.velocity));
+        self.tempos.sort_by_key(|t| t.tick);
+        self.time_signatures.sort_by_key(|t| t.tick);
+    }
+
+    pub fn instruments(&self) -> Vec<Instrument> {
+        let mut instruments: Vec<Instrument> = self.notes.iter().map(|n| n.instrument).collect();
+        instruments.sort();
+        instruments.dedup();
+        instruments
+    }
+
+    pub fn to_smf(&self, format: SmfFormat) -> Vec<u8> {
+        let instruments = self.instruments();
+        let channels = assign_channels(&instruments);
+
+        let mut conductor = TrackWriter::default();
+        for tempo in &self.tempos {
+            let us = (60_000_000.0 / tempo.bpm.max(1.0)).round().min(0xFF_FFFF as f64) as u32;
+            conductor.push(tempo.tick, 0, vec![0xFF, 0x51, 0x03, (us >> 16) as u8, (us >> 8) as u8, us as u8]);
+        }
+        for sig in &self.time_signatures {
+            let denominator = sig.denominator.max(1).trailing_zeros() as u8;
+            conductor.push(sig.tick, 0, vec![0xFF, 0x58, 0x04, sig.numerator, denominator, 24, 8]);
+        }
+
+        let mut tracks = Vec::new();
+        match format {
+            SmfFormat::Single => {
+                let mut track = conductor;
+                for (instrument, &channel) in instruments.iter().zip(&channels) {
+                    track.instrument(&self.notes, *instrument, channel);
+                }
+                tracks.push(track);
+            }
+            SmfFormat::MultiTrack => {
+                tracks.push(conductor);
+                for (instrument, &channel) in instruments.iter().zip(&channels) {
+                    let mut track = TrackWriter::default();
+                    track.instrument(&self.notes, *instrument, channel);
+                    tracks.push(track);
+                }
+            }
+        }
+
+        let mut out = Vec::new();
+        out.extend_from_slice(b"MThd");
+        out.extend_from_slice(&6u32.to_be_bytes());
+        out.extend_from_slice(&(if format == SmfFormat::Single { 0u16 } else { 1u16 }).to_be_bytes());
+        out.extend_from_slice(&(tracks.len() as u16).to_be_bytes());
+        out.extend_from_slice(&self.ticks_per_beat.to_be_bytes());
+        for track in tracks {
+            track.write(&mut out);
+        }
+        out
+    }
+
+    pub fn end(&self) -> u32 {
+        self.notes.iter().map(|n| n.start.saturating_add(n.duration)).max().unwrap_or(0)
+    }
+
+    // Length of a bar in the first time signature, 4/4 when there is none
+    pub fn bar_ticks(&self) -> u32 {
+        let signature = self.time_signatures.first()
+            .copied()
+            .unwrap_or(TimeSignature { tick: 0, numerator: 4, denominator: 4 });
+        signature.bar_ticks(self.ticks_per_beat)
+    }
+
+    // The same music at another resolution. Ticks a finer resolution pushes past the u32 range
+    // stop at its end.
+    pub fn resample(&self, ticks_per_beat: u16) -> Score {
+        let scale = |tick: u32| {
+            let scaled = (tick as u64 * ticks_per_beat as u64 + self.ticks_per_beat as u64 / 2) / self.ticks_per_beat.max(1) as u64;
+            scaled.min(u32::MAX as u64) as u32
+        };
+        Score {
+            ticks_per_beat,
+            tempos: self.tempos.iter().map(|t| Tempo { tick: scale(t.tick), ..*t }).collect(),
+            time_signatures: self.time_signatures.iter().map(|t| TimeSignature { tick: scale(t.tick), ..*t }).collect(),
+            notes: self.notes.iter()
+                .map(|n| Note { start: scale(n.start), duration: scale(n.duration).max(1), ..*n })
+                .collect(),
+        }
+    }
+
+    // Moves note starts and ends `strength` of the way to the nearest multiple of `grid` ticks, 1.0
+    // snaps them onto it. A note keeps at least that part of a grid step.
+    pub fn quantize(&mut self, grid: u32, strength: f32) {
+        let grid = grid.max(1);
+        let strength = strength.clamp(0.0, 1.0);
+        let moved = |tick: u32| {
+            let snapped = (tick + grid / 2) / grid * grid;
+            (tick as f32 + (snapped as f32 - tick as f32) * strength).round() as u32
+        };
+        let shortest = ((grid as f32 * strength).round() as u32).max(1);
+        for note in &mut self.notes {
+            let start = moved(note.start);
+            note.duration = moved(note.start + note.duration).saturating_sub(start).max(shortest);
+            note.start = start;
+        }
+        self.sort();
+    }
+
+    // Tempo in effect at a tick, 120 BPM before the first tempo change
+    pub fn bpm_at(&self, tick: u32) -> f64 {
+        self.tempos.iter().rev().find(|t| t.tick <= tick).map_or(120.0, |t| t.bpm.max(1.0))
+    }
+
+    // The notes starting in [start, end), moved so `start` becomes tick 0 and cut off at `end`.
+    // The tempo and meter in effect at `start` carry over.
+    pub fn window(&self, start: u32, end: u32) -> Score {
+        let mut out = Score::new(self.ticks_per_beat);
+        let carried = self.tempos.iter().rev().find(|t| t.tick <= start);
+        out.tempos = carried.into_iter()
+            .map(|t| Tempo { tick: 0, ..*t })
+            .chain(self.tempos.iter().filter(|t| t.tick > start && t.tick < end).map(|t| Tempo { tick: t.tick - start, ..*t }))
+            .collect();
+        let carried = self.time_signatures.iter().rev().find(|t| t.tick <= start);
+        out.time_signatures = carried.into_iter()
+            .map(|t| TimeSignature { tick: 0, ..*t })
+            .chain(self.time_signatures.iter().filter(|t| t.tick > start && t.tick < end).map(|t| TimeSignature { tick: t.tick - start, ..*t }))
+            .collect();
+        out.notes = self.notes.iter()
+            .filter(|n| n.start >= start && n.start < end)
+            .map(|n| Note { start: n.start - start, duration: n.duration.min(end - n.start), ..*n })
+            .collect();
+        out
+    }
+
+    // Time of a tick in seconds, following the tempo changes before it
+    pub fn seconds(&self, tick: u32) -> f64 {
+        let mut seconds = 0.0;
+        let (mut last, mut bpm) = (0, 120.0);
+        for tempo in self.tempos.iter().take_while(|t| t.tick <= tick) {
+            seconds += (tempo.tick - last) as f64 * 60.0 / (bpm * self.ticks_per_beat.max(1) as f64);
+            (last, bpm) = (tempo.tick, tempo.bpm.max(1.0));
+        }
+        seconds + (tick - last) as f64 * 60.0 / (bpm * self.ticks_per_beat.max(1) as f64)
+    }
+
+    // The score as a JSON note list, with times in both ticks and seconds
+    pub fn to_json(&self) -> Vec<u8> {
+        let notes = self.notes.iter()
+            .map(|n| JsonNote {
+                start: n.start,
+                duration: n.duration,
+                start_secs: self.seconds(n.start),
+                duration_secs: self.seconds(n.start + n.duration) - self.seconds(n.start),
+                pitch: n.pitch,
+                velocity: n.velocity,
+                program: match n.instrument {
+                    Instrument::Program(program) => Some(program),
+                    Instrument::Drums => None,
+                },
+                drums: n.instrument == Instrument::Drums,
+            })
+            .collect();
+        let json = JsonScore {
+            ticks_per_beat: self.ticks_per_beat,
+            tempos: self.tempos.iter().map(|t| JsonTempo { tick: t.tick, bpm: t.bpm }).collect(),
+            time_signatures: self.time_signatures.iter()
+                .map(|t| JsonTimeSignature { tick: t.tick, numerator: t.numerator, denominator: t.denominator })
+                .collect(),
+            notes,
+        };
+        serde_json::to_vec(&json).unwrap_or_default()
+    }
+
+    // Reads a format 0 or 1 file. Controllers, pitch bends and sysex are skipped.
+    pub fn from_smf(bytes: &[u8]) -> anyhow::Result<Self> {
+        let mut reader = Reader { bytes, pos: 0 };
+        if reader.take(4)? != b"MThd" {
+            bail!("not a MIDI file");
+        }
+        let header_len = reader.u32()? as usize;
+        let header = reader.take(header_len)?;
+        if header.len() < 6 {
+            bail!("MIDI header is too short");
+        }
+        let format = u16::from_be_bytes([header[0], header[1]]);
+        let division = u16::from_be_bytes([header[4], header[5]]);
+        if format > 1 {
+            bail!("MIDI format {} is not supported", format);
+        }
+        if division & 0x8000 != 0 || division == 0 {
+            bail!("SMPTE time division is not supported");
+        }
+
+        let mut score = Score::new(division);
+        while !reader.done() {
+            let kind = reader.take(4)?;
+            let len = reader.u32()? as usize;
+            let chunk = reader.take(len)?;
+            if kind == b"MTrk" {
+                read_track(chunk, &mut score)?;
+            }
+        }
+        score.sort();
+        Ok(score)
+    }
+}
+
+#[derive(Serialize)]
+struct JsonScore {
+    ticks_per_beat: u16,
+    tempos: Vec<JsonTempo>,
+    time_signatures: Vec<JsonTimeSignature>,
+    notes: Vec<JsonNote>,
+}
+
+#[derive(Serialize)]
+struct JsonTempo {
+    tick: u32,
+    bpm: f64,
+}
+
+#[derive(Serialize)]
+struct JsonTimeSignature {
+    tick: u32,
+    numerator: u8,
+    denominator: u8,
+}
+
+#[derive(Serialize)]
+struct JsonNote {
+    start: u32,
+    duration: u32,
+    start_secs: f64,
+    duration_secs: f64,
+    pitch: u8,
+    velocity: u8,
+    // General MIDI program, `None` for drums
+    program: Option<u8>,
+    drums: bool,
+}
+
+// Drums go to the GM drum channel, every other instrument gets a channel of its own, wrapping
+// around when there are more than 15
+pub fn assign_channels(instruments: &[Instrument]) -> Vec<u8> {
+    let melodic: Vec<u8> = (0..16).filter(|&c| c != DRUM_CHANNEL).collect();
+    let mut next = 0;
+    instruments.iter()
+        .map(|instrument| match instrument {
+            Instrument::Drums => DRUM_CHANNEL,
+            Instrument::Program(_) => {
+                let channel = melodic[next % melodic.len()];
+                next += 1;
+                channel
+            }
+        })
+        .collect()
+}
+
+// Events of one track as (tick, order at that tick, bytes)
+#[derive(Default)]
+struct TrackWriter {
+    events: Vec<(u32, u8, Vec<u8>)>,
+}
+
+impl TrackWriter {
+    fn push(&mut self, tick: u32, order: u8, bytes: Vec<u8>) {
+        self.events.push((tick, order, bytes));
+    }
+
+    fn instrument(&mut self, notes: &[Note], instrument: Instrument, channel: u8) {
+        if let Instrument::Program(program) = instrument {
+            self.push(0, 1, vec![0xC0 | channel, program & 0x7F]);
+        }
+        for note in notes.iter().filter(|n| n.instrument == instrument) {
+            // a note must sound for at least a tick or its off would sort before its on
+            let end = note.start + note.duration.max(1);
+            self.push(note.start, 3, vec![0x90 | channel, note.pitch & 0x7F, note.velocity.clamp(1, 127)]);
+            self.push(end, 2, vec![0x80 | channel, note.pitch & 0x7F, 0]);
+        }
+    }
+
+    fn write(mut self, out: &mut Vec<u8>) {
+        // note offs go before note ons at the same tick so repeated notes retrigger
+        self.events.sort_by_key(|(tick, order, _)| (*tick, *order));
+        let end = self.events.last().map(|(tick, _, _)| *tick).unwrap_or(0);
+        self.events.push((end, u8::MAX, vec![0xFF, 0x2F, 0x00]));
+
+        let mut data = Vec::new();
+        let mut last = 0;
+        for (tick, _, bytes) in self.events {
+            write_vlq(&mut data, tick - last);
+            data.extend_from_slice(&bytes);
+            last = tick;
+        }
+        out.extend_from_slice(b"MTrk");
+        out.extend_from_slice(&(data.len() as u32).to_be_bytes());
+        out.extend_from_slice(&data);
+    }
+}
+
+fn write_vlq(out: &mut Vec<u8>, value: u32) {
+    let mut groups = vec![(value & 0x7F) as u8];
+    let mut rest = value >> 7;
+    while rest > 0 {
+        groups.push((rest & 0x7F) as u8 | 0x80);
+        rest >>= 7;
+    }
+    out.extend(groups.into_iter().rev());
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn done(&self) -> bool {
+        self.pos >= self.bytes.len()
+    }
+
+    fn take(&mut self, len: usize) -> anyhow::Result<&'a [u8]> {
+        let Some(slice) = self.bytes.get(self.pos..self.pos + len) else {
+            bail!("MIDI data ends unexpectedly");
+        };
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn u8(&mut self) -> anyhow::Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> anyhow::Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_be_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    fn vlq(&mut self) -> anyhow::Result<u32> {
+        let mut value = 0u32;
+        for _ in 0..4 {
+            let byte = self.u8()?;
+            value = (value << 7) | (byte & 0x7F) as u32;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        bail!("variable-length quantity is longer than 4 bytes")
+    }
+}
+
+fn read_track(chunk: &[u8], score: &mut Score) -> anyhow::Result<()> {
+    let mut reader = Reader { bytes: chunk, pos: 0 };
+    let mut tick = 0u32;
+    let mut running = None;
+    let mut programs = [0u8; 16];
+    // notes waiting for their note off, per (channel, pitch), oldest first
+    let mut open: HashMap<(u8, u8), VecDeque<Note>> = HashMap::new();
+
+    while !reader.done() {
+        let delta = reader.vlq()?;
+        let Some(next) = tick.checked_add(delta) else {
+            bail!("track runs past tick {}", u32::MAX);
+        };
+        tick = next;
+        let mut status = reader.u8()?;
+        let first = if status & 0x80 == 0 {
+            // running status, the byte we read is already the first data byte
+            let Some(previous) = running else {
+                bail!("MIDI data byte without a status");
+            };
+            let data = status;
+            status = previous;
+            Some(data)
+        } else {
+            None
+        };
+
+        match status {
+            0xFF => {
+                let kind = reader.u8()?;
+                let len = reader.vlq()? as usize;
+                let data = reader.take(len)?;
+                match kind {
+                    0x51 if len == 3 => {
+                        let us = (data[0] as u32) << 16 | (data[1] as u32) << 8 | data[2] as u32;
+                        let us = if us == 0 { DEFAULT_TEMPO_US } else { us };
+                        score.tempos.push(Tempo { tick, bpm: 60_000_000.0 / us as f64 });
+                    }
+                    // bars of no beats have no length to count bars by
+                    0x58 if len >= 2 && data[0] == 0 => bail!("time signature with 0 beats at tick {}", tick),
+                    0x58 if len >= 2 => score.time_signatures.push(TimeSignature {
+                        tick,
+                        numerator: data[0],
+                        denominator: 1u8.checked_shl(data[1] as u32).unwrap_or(4),
+                    }),
+                    0x2F => break,
+                    _ => {}
+                }
+            }
+            0xF0 | 0xF7 => {
+                let len = reader.vlq()? as usize;
+                reader.take(len)?;
+            }
+            _ => {
+                running = Some(status);
+                let channel = status & 0x0F;
+                let mut first = first;
+                let mut next = |reader: &mut Reader<'_>| -> anyhow::Result<u8> {
+                    match first.take() {
+                        Some(byte) => Ok(byte),
+                        None => reader.u8(),
+                    }
+                };
+                match status & 0xF0 {
+                    0x80 | 0x90 => {
+                        let pitch = next(&mut reader)?;
+                        let velocity = next(&mut reader)?;
+                        if status & 0xF0 == 0x90 && velocity > 0 {
+                            let instrument = if channel == DRUM_CHANNEL {
+                                Instrument::Drums
+                            } else {
+                                Instrument::Program(programs[channel as usize])
+                            };
+                            open.entry((channel, pitch)).or_default()
+                                .push_back(Note { start: tick, duration: 0, pitch, velocity, instrument });
+                        } else if let Some(mut note) = open.get_mut(&(channel, pitch)).and_then(|q| q.pop_front()) {
+                            note.duration = tick - note.start;
+                            score.notes.push(note);
+                        }
+                    }
+                    0xC0 => programs[channel as usize] = next(&mut reader)?,
+                    0xD0 => {
+                        next(&mut reader)?;
+                    }
+                    0xA0 | 0xB0 | 0xE0 => {
+                        next(&mut reader)?;
+                        next(&mut reader)?;
+                    }
+                    _ => bail!("invalid MIDI status byte {:#04x}", status),
+                }
+            }
+        }
+    }
+
+    // notes still held at the end of the track stop there
+    for mut note in open.into_values().flatten() {
+        note.duration = tick - note.start;
+        score.notes.push(note);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(start: u32, duration: u32, pitch: u8, instrument: Instrument) -> Note {
+        Note { start, duration, pitch, velocity: 90, instrument }
+    }
+
+    fn example() -> Score {
+        let mut score = Score::new(480);
+        score.tempos.push(Tempo { tick: 0, bpm: 120.0 });
+        score.time_signatures.push(TimeSignature { tick: 0, numerator: 3, denominator: 4 });
+        score.notes = vec![
+            note(0, 480, 60, Instrument::Program(0)),
+            note(0, 480, 64, Instrument::Program(0)),
+            // repeated pitch right after the previous one ends
+            note(480, 240, 60, Instrument::Program(0)),
+            note(0, 120, 36, Instrument::Drums),
+            note(960, 1440, 40, Instrument::Program(33)),
+        ];
+        score.sort();
+        score
+    }
+
+    #[test]
+    fn format_1_round_trip() {
+        let score = example();
+        let bytes = score.to_smf(SmfFormat::MultiTrack);
+        assert_eq!(&bytes[8..10], &[0, 1]);
+        // conductor plus piano, bass and drums
+        assert_eq!(u16::from_be_bytes([bytes[10], bytes[11]]), 4);
+        assert_eq!(Score::from_smf(&bytes).unwrap(), score);
+    }
+
+    #[test]
+    fn format_0_round_trip() {
+        let score = example();
+        let bytes = score.to_smf(SmfFormat::Single);
+        assert_eq!(&bytes[8..12], &[0, 0, 0, 1]);
+        assert_eq!(Score::from_smf(&bytes).unwrap(), score);
+    }
+
+    #[test]
+    fn running_status_and_zero_velocity_note_off() {
+        #[rustfmt::skip]
+        let track = [
+            0x00, 0xC0, 0x05,
+            0x00, 0x90, 0x3C, 0x40,
+            0x00, 0x40, 0x50, // running status note on
+            0x83, 0x60, 0x3C, 0x00, // 480 ticks later, note on with velocity 0
+            0x00, 0x40, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0MTrk".to_vec();
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+
+        let score = Score::from_smf(&bytes).unwrap();
+        let instrument = Instrument::Program(5);
+        assert_eq!(score.notes, vec![
+            Note { start: 0, duration: 480, pitch: 60, velocity: 64, instrument },
+            Note { start: 0, duration: 480, pitch: 64, velocity: 80, instrument },
+        ]);
+    }
+
+    #[test]
+    fn rejects_time_signatures_without_beats() {
+        #[rustfmt::skip]
+        let track = [
+            0x00, 0xFF, 0x58, 0x04, 0x00, 0x02, 0x18, 0x08,
+            0x00, 0x90, 0x3C, 0x40,
+            0x83, 0x60, 0x80, 0x3C, 0x00,
+            0x00, 0xFF, 0x2F, 0x00,
+        ];
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0MTrk".to_vec();
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+        assert!(Score::from_smf(&bytes).is_err());
+    }
+
+    #[test]
+    fn rejects_tracks_longer_than_the_tick_range() {
+        // 17 deltas of 0x0FFF_FFFF ticks add up to more than u32::MAX
+        let mut track = Vec::new();
+        for _ in 0..17 {
+            track.extend_from_slice(&[0xFF, 0xFF, 0xFF, 0x7F, 0xFF, 0x01, 0x00]);
+        }
+        track.extend_from_slice(&[0x00, 0xFF, 0x2F, 0x00]);
+        let mut bytes = b"MThd\0\0\0\x06\0\0\0\x01\x01\xE0MTrk".to_vec();
+        bytes.extend_from_slice(&(track.len() as u32).to_be_bytes());
+        bytes.extend_from_slice(&track);
+        let error = Score::from_smf(&bytes).unwrap_err();
+        assert!(format!("{:#}", error).contains("past tick"), "{:#}", error);
+    }
+
+    #[test]
+    fn resampling_stops_at_the_last_tick() {
+        let mut score = Score::new(96);
+        score.notes.push(Note { start: u32::MAX / 2, duration: 96, pitch: 60, velocity: 64, instrument: Instrument::Program(0) });
+        let score = score.resample(480);
+        assert_eq!(score.notes[0].start, u32::MAX);
+        assert_eq!(score.end(), u32::MAX);
+    }
+
+    #[test]
+    fn vlq() {
+        for (value, expected) in [(0, vec![0x00]), (0x7F, vec![0x7F]), (0x80, vec![0x81, 0x00]), (0x0FFF_FFFF, vec![0xFF, 0xFF, 0xFF, 0x7F])] {
+            let mut out = Vec::new();
+            write_vlq(&mut out, value);
+            assert_eq!(out, expected);
+            assert_eq!(Reader { bytes: &out, pos: 0 }.vlq().unwrap(), value);
+        }
+    }
+}
@@ -1,63 +1,84 @@
-use std::{path::Path, sync::Arc};
+use std::{convert::Infallible, pin::pin, sync::Arc, time::{Duration, Instant}};
 
 use axum::{
     Router,
-    extract::{FromRef, State, Json},
+    extract::{FromRef, Path, State, Json, rejection::JsonRejection},
+    http::{HeaderMap, StatusCode, header},
     response::{
-        Sse,
-        sse::{Event, KeepAlive}
+        IntoResponse, Response, Sse,
+        sse::KeepAlive
     },
-    routing::post
-};
-use serde::Deserialize;
-use futures::Stream;
-use ort::{
-    session::{RunOptions, Session, builder::GraphOptimizationLevel},
-    value::TensorRef
+    routing::{get, post}
 };
+use serde::{Deserialize, Serialize};
+use futures::{Stream, StreamExt, future::Either, stream};
 use tokenizers::Tokenizer;
-use tokio::{net::TcpListener, sync::Mutex};
-use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
-
-
-// Max number of generated tokens
-const GEN_TOKENS: usize = 1;
-// Sample from the k most likely next tokens at each step
-const TOP_K: usize = 20;
-
-pub async fn create() -> anyhow::Result<()> {
-    // Initialize tracing to recieve debug messages from "ort"
-    tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::try_from_default_env().unwrap_or_else(|_| "info,ort=debug".into()))
-        .with(tracing_subscriber::fmt::layer())
-        .init();
-
-    // Load model
-    let mut session = Session::builder()?
-    .with_optimization_level(GraphOptimizationLevel::Level3)?
-    .with_intra_threads(4)?
-    .commit_from_file(
-            Path::new(env!("MODEL_STORE_ROOT"))
-            .parent()
-            .unwrap()
-            .join("TODO: add model filename")
-        )?;
-
-    // Load the tokenizer and encode the prompt into a sequence of tokens
-    let tokenizer = Tokenizer::from_file(
-        Path::new(env!("MODEL_STORE_ROOT"))
-            .parent()
-            .unwrap()
-            .join("tokenizer.json")
-    ).unwrap();
-
-    let app_state = AppState {
-        session: Arc::new(Mutex::new(session)),
-        tokenizer: Arc::new(tokenizer)
-    };
+use tokio::net::TcpListener;
+
+use crate::{
+    abc::{self, ABC_CONTENT_TYPE},
+    audio::{self, AudioParams, AudioRequest},
+    conditioning::{Conditioning, ConditioningFields, Key},
+    config::Config,
+    constraints::{ConstraintParams, Constraints},
+    continuation,
+    decoder::DecodeState,
+    error::ApiError,
+    events::{self, Artifact, CandidateSummary, GenerationEvent, OUTPUT_FORMATS, PROTOCOL_VERSION, Timings},
+    format::{Accept, NOTES_CONTENT_TYPE, OutputFormat},
+    manifest::{Manifest, Modality},
+    mastering::{Mastering, MasteringParams},
+    jobs::{self, JobStore},
+    midi::{MIDI_CONTENT_TYPE, Score, SmfFormat},
+    music::{self, Continuation},
+    musicxml::{self, MUSICXML_CONTENT_TYPE},
+    notation::Notation,
+    postprocess::{PostProcess, PostProcessParams},
+    registry::{Model, ModelInfo, ModelRegistry},
+    sampler::{Sampler, SamplingParams},
+    synth::{Preview, PreviewParams}
+};
+
+
+// Number of generated tokens when the request doesn't ask for a specific amount
+const DEFAULT_NEW_TOKENS: usize = 256;
+// Hard ceiling on generated tokens, regardless of what the request asks for
+const MAX_NEW_TOKENS: usize = 2048;
+// Most variations a single request can ask for
+const MAX_VARIATIONS: usize = 8;
+// Longest prompt accepted, checked before tokenizing
+const MAX_PROMPT_BYTES: usize = 64 * 1024;
+
+// Loads the models described by the config
+pub fn load(config: &Config) -> anyhow::Result<AppState> {
+    Ok(AppState {
+        registry: Arc::new(ModelRegistry::load(config)?),
+        jobs: Arc::new(JobStore::default()),
+    })
+}
 
-    let app = Router::new().route("/generate", post(generate)).with_state(app_state).into_make_service();
-    let listener = TcpListener::bind("127.0.0.1:8000").await?;
+pub async fn serve(config: &Config, app_state: AppState) -> anyhow::Result<()> {
+    if config.reload_interval_secs > 0 {
+        let interval = Duration::from_secs(config.reload_interval_secs);
+        tokio::spawn(Arc::clone(&app_state.registry).watch(interval));
+    }
+
+    let app = Router::new()
+        .route("/generate", post(generate))
+        .route("/continue", post(continuation::continue_midi))
+        .route("/health", get(health))
+        .route("/ready", get(ready))
+        .route("/version", get(version))
+        .route("/jobs", post(jobs::create))
+        .route("/jobs/{id}", get(jobs::status).delete(jobs::cancel))
+        .route("/jobs/{id}/events", get(jobs::events))
+        .route("/jobs/{id}/result", get(jobs::result))
+        .route("/jobs/{id}/preview", get(jobs::preview))
+        .route("/models", get(list_models))
+        .route("/models/{name}/reload", post(reload_model))
+        .with_state(app_state)
+        .into_make_service();
+    let listener = TcpListener::bind(config.bind_addr()).await?;
     tracing::info!("Listening on {}", listener.local_addr()?);
 
     axum::serve(listener, app).await?;
@@ -65,81 +86,693 @@ pub async fn create() -> anyhow::Result<()> {
     Ok(())
 }
 
+// Runs a prompt to completion without going through HTTP and returns the index and the bytes of
+// the artifact and preview of each candidate that succeeded, in candidate order
+pub async fn generate_offline(app_state: &AppState, body: PromptRequest) -> anyhow::Result<Vec<(usize, Vec<u8>, Option<Vec<u8>>)>> {
+    let generation = prepare(&app_state.registry, body, &Accept::default())?;
+    let completed = run_to_completion(generation_events(events::request_id(), generation)).await?;
+    for candidate in completed.candidates.iter().filter(|c| c.ok) {
+        tracing::info!("candidate {} mean logprob {:?}", candidate.candidate, candidate.mean_logprob);
+    }
+    completed.results.into_iter()
+        .map(|result| {
+            let bytes = result.artifact.bytes().ok_or_else(|| anyhow::anyhow!("artifact has no data"))?;
+            Ok((result.candidate, bytes, result.preview.and_then(|preview| preview.bytes())))
+        })
+        .collect()
+}
+
+// Human readable description of a model's graph and tokenizer
+pub fn inspect(app_state: &AppState, name: Option<&str>) -> anyhow::Result<String> {
+    let Some(model) = app_state.registry.get(name) else {
+        anyhow::bail!("unknown model {}", name.unwrap_or_default());
+    };
+
+    let mut out = format!("model: {}\n", model.name);
+    out.push_str("inputs:\n");
+    for (name, ty) in &model.inputs {
+        out.push_str(&format!("  {}: {}\n", name, ty));
+    }
+    out.push_str("outputs:\n");
+    for (name, ty) in &model.outputs {
+        out.push_str(&format!("  {}: {}\n", name, ty));
+    }
+    if let Some(decoder) = &model.decoder {
+        out.push_str(&format!("key/value cache: {}\n", if decoder.graph.has_cache() { "yes" } else { "no" }));
+    }
+
+    let manifest = &model.manifest;
+    out.push_str(&format!("modality: {:?}\n", manifest.modality));
+    if let Some((inputs, outputs)) = manifest.decoder() {
+        out.push_str(&format!("input layout: {:?}\n", inputs.layout));
+        out.push_str(&format!("logits layout: {:?}\n", outputs.layout));
+    }
+    if let Some(audio) = &manifest.audio {
+        out.push_str(&format!("generator: {:?}\n", audio.generator));
+        out.push_str(&format!("sample rate: {} Hz, {} channels\n", audio.sample_rate, audio.channels));
+        if let Some(encoder) = &audio.text_encoder {
+            out.push_str(&format!("text encoder: {}\n", encoder.model.display()));
+        }
+        out.push_str(&format!("vocoder: {}\n", audio.vocoder.model.display()));
+    }
+    out.push_str(&format!(
+        "bos: {:?}, eos: {:?}, pad: {:?}\n",
+        manifest.tokens.bos, manifest.tokens.eos, manifest.tokens.pad
+    ));
+    match manifest.context_length {
+        Some(len) => out.push_str(&format!("context length: {}\n", len)),
+        None => out.push_str("context length: unknown\n"),
+    }
+
+    let tokenizer = &model.tokenizer;
+    out.push_str(&format!("vocab size: {}\n", tokenizer.get_vocab_size(true)));
+    out.push_str("special tokens:\n");
+    let mut special: Vec<(u32, String)> = tokenizer
+        .get_added_tokens_decoder()
+        .into_iter()
+        .filter(|(_, token)| token.special)
+        .map(|(id, token)| (id, token.content))
+        .collect();
+    special.sort_unstable();
+    for (id, content) in special {
+        out.push_str(&format!("  {id}: {content}\n"));
+    }
+    Ok(out)
+}
+
 #[derive(Clone)]
-struct AppState {
-    session: Arc<Mutex<Session>>,
-    tokenizer: Arc<Tokenizer>
+pub struct AppState {
+    registry: Arc<ModelRegistry>,
+    jobs: Arc<JobStore>,
 }
 
+// Samples up to `gen_tokens` tokens after the prompt, masking what the constraints forbid
+// before each pick. With a bar limit, generation also stops at the bar token that would start
+// one bar more than that.
 fn generate_stream(
-    tokenizer: Arc<Tokenizer>,
-    session: Arc<Mutex<Session>>,
-    mut tokens: Vec<i64>,
-    gen_tokens: usize
-) -> impl Stream<Item = ort::Result<Event>> + Send {
+    model: Arc<Model>,
+    tokens: Vec<i64>,
+    gen_tokens: usize,
+    mut sampler: Sampler,
+    mut constraints: Option<Constraints>,
+    bar_limit: Option<u32>
+) -> impl Stream<Item = ort::Result<(i64, f32)>> + Send {
     async_stream_lite::try_async_stream(|yielder| async move {
-        for _ in 0..gen_tokens {
-            let input = TensorRef::from_array_view((vec![1, 1, tokens.len() as i64], tokens.as_slice()))?;
-            let probabilities = {
-                let mut session = session.lock().await;
-                let options = RunOptions::new()?;
-                let outputs = session.run_async(ort::inputs![input], &options)?.await?;
-                let (dim, probabilities) = outputs["output1"].try_extract_tensor()?;
-
-                // collect logits
-                let (seq_len, vocab_size) = (dim[2] as usize, dim[3] as usize);
-                let mut probabilities: Vec<(usize, f32)> = probabilities[(seq_len - 1) * vocab_size..].iter().copied().enumerate().collect();
-                probabilities.sort_unstable_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Less));
-                probabilities
-            };
+        let Some(decoder) = &model.decoder else {
+            return Err(ort::Error::new(format!("model {} has no token decoder", model.name)));
+        };
+        tracing::info!("generating with model {} and seed {}", model.name, sampler.seed());
+        let stop_tokens = stop_token_ids(&model.manifest);
+        let bar_token = model.tokenizer.token_to_id(music::BAR_TOKEN).map(|id| id as i64);
+        let mut bars = 0;
+        if let Some(constraints) = constraints.as_mut() {
+            for &token in &tokens {
+                constraints.advance(token);
+            }
+        }
+        let mut state = DecodeState::new(tokens);
+        for step in 0..gen_tokens {
+            let (next, mut logits) = decoder.scheduler.step(state).await?;
+            state = next;
 
-            let token = probabilities[0].0 as i64;
-            tokens.push(token);
+            if let Some(constraints) = &constraints {
+                constraints.process(&mut logits).map_err(|e| ort::Error::new(format!("{:#}", e)))?;
+            }
+            let Some((token, logprob)) = sampler.sample(&logits, &state.tokens) else {
+                return Err(ort::Error::new(format!("no token is allowed after step {}, the constraints mask out every logit", step)));
+            };
+            if stop_tokens.contains(&token) {
+                tracing::debug!("stop token {} reached after {} steps", token, step);
+                break;
+            }
+            if Some(token) == bar_token && let Some(limit) = bar_limit {
+                bars += 1;
+                if bars >= limit {
+                    tracing::debug!("{} bars complete after {} steps", limit, step);
+                    break;
+                }
+            }
+            state.tokens.push(token);
+            if let Some(constraints) = constraints.as_mut() {
+                constraints.advance(token);
+            }
 
-            let token_str = tokenizer.decode(&[token as _], true).unwrap();
-            yielder.r#yield(Event::default().data(token_str)).await;
+            yielder.r#yield((token, logprob)).await;
         }
 
         Ok(())
     })
 }
 
+// Wraps a generation in the event protocol, audio generations follow `audio::events`
+pub fn generation_events(request_id: String, mut generation: Generation) -> impl Stream<Item = GenerationEvent> + Send {
+    match generation.audio.take() {
+        Some(request) => Either::Left(audio::events(request_id, generation, request)),
+        None => Either::Right(token_events(request_id, generation)),
+    }
+}
+
+// The event protocol of a token generation: `started`, a `token` and `progress` per step of each
+// candidate, then each candidate's `result` or `error`, a `summary` of the candidates and `done`
+// with timing stats. Candidates step concurrently, so the scheduler batches their steps.
+fn token_events(request_id: String, generation: Generation) -> impl Stream<Item = GenerationEvent> + Send {
+    let Generation { model, tokens, gen_tokens, samplers, constraints, output_format, continuation, bar_limit, key, post_process, preview, .. } = generation;
+    async_stream_lite::async_stream(|yielder| async move {
+        let start = Instant::now();
+        let prompt_tokens = tokens.len();
+        let seeds: Vec<u64> = samplers.iter().map(Sampler::seed).collect();
+        tracing::info!("request {} started with {} candidates", request_id, seeds.len());
+        let mut guard = CancelGuard { request_id: request_id.clone(), step: 0, finished: false };
+        yielder.r#yield(GenerationEvent::Started {
+            request_id: request_id.clone(),
+            model: model.name.clone(),
+            seed: seeds.first().copied().unwrap_or_default(),
+        }).await;
+
+        let streams = samplers.into_iter().enumerate().map(|(candidate, sampler)| {
+            let stream = generate_stream(Arc::clone(&model), tokens.clone(), gen_tokens, sampler, constraints.clone(), bar_limit);
+            Box::pin(stream.map(move |item| (candidate, item)))
+        });
+        let mut stream = stream::select_all(streams);
+        let mut generated: Vec<Vec<u32>> = vec![Vec::new(); seeds.len()];
+        let mut logprobs = vec![0.0f32; seeds.len()];
+        let mut failed = vec![false; seeds.len()];
+        let total_steps = gen_tokens * seeds.len();
+        let mut steps = 0;
+        let mut first_token = None;
+        while let Some((candidate, item)) = stream.next().await {
+            match item {
+                Ok((id, logprob)) => {
+                    first_token.get_or_insert_with(|| start.elapsed());
+                    let step = generated[candidate].len();
+                    generated[candidate].push(id as u32);
+                    logprobs[candidate] += logprob;
+                    steps += 1;
+                    guard.step = steps;
+                    let text = model.tokenizer.decode(&[id as u32], true).unwrap_or_default();
+                    yielder.r#yield(GenerationEvent::Token { candidate, id, text, step, logprob }).await;
+                    yielder.r#yield(GenerationEvent::Progress {
+                        fraction: steps as f32 / total_steps as f32,
+                    }).await;
+                }
+                Err(e) => {
+                    tracing::error!("request {} candidate {} failed: {}", request_id, candidate, e);
+                    failed[candidate] = true;
+                    yielder.r#yield(GenerationEvent::Error {
+                        candidate: Some(candidate),
+                        code: "inference_failed",
+                        message: e.to_string(),
+                    }).await;
+                }
+            }
+        }
+
+        if failed.contains(&false) {
+            // a stop token can end the generation before the last step
+            yielder.r#yield(GenerationEvent::Progress { fraction: 1.0 }).await;
+        }
+        for (candidate, (tokens, failed)) in generated.iter().zip(failed.iter_mut()).enumerate() {
+            if *failed {
+                continue;
+            }
+            let notes = Notes {
+                continuation: continuation.as_ref(),
+                key,
+                post_process: post_process.as_ref(),
+                preview: preview.is_some(),
+                seed: seeds[candidate],
+            };
+            let rendered = match render(&model, tokens, output_format, &notes) {
+                Ok((artifact, score)) => play(preview.as_ref(), score).await.map(|preview| (artifact, preview)),
+                Err(e) => Err(e),
+            };
+            match rendered {
+                Ok((artifact, preview)) => yielder.r#yield(GenerationEvent::Result { candidate, artifact, preview }).await,
+                Err(e) => {
+                    *failed = true;
+                    yielder.r#yield(GenerationEvent::Error {
+                        candidate: Some(candidate),
+                        code: "decode_failed",
+                        message: format!("{:#}", e),
+                    }).await;
+                }
+            }
+        }
+
+        let candidates = generated.iter().zip(&logprobs).zip(&failed).zip(&seeds).enumerate()
+            .map(|(candidate, (((tokens, &logprob), &failed), &seed))| CandidateSummary {
+                candidate,
+                seed,
+                generated_tokens: tokens.len(),
+                mean_logprob: (!tokens.is_empty()).then(|| logprob / tokens.len() as f32),
+                ok: !failed,
+            })
+            .collect();
+        yielder.r#yield(GenerationEvent::Summary { candidates }).await;
+
+        let elapsed = start.elapsed();
+        let timings = Timings {
+            prompt_tokens,
+            generated_tokens: steps,
+            elapsed_ms: elapsed.as_millis() as u64,
+            time_to_first_token_ms: first_token.map(|t| t.as_millis() as u64),
+            tokens_per_second: steps as f32 / elapsed.as_secs_f32().max(f32::EPSILON),
+        };
+        guard.finished = true;
+        tracing::info!("request {} done, {} tokens in {} ms", request_id, timings.generated_tokens, timings.elapsed_ms);
+        yielder.r#yield(GenerationEvent::Done { timings }).await;
+    })
+}
+
+// How a candidate's decoded notes are turned into the formats built from them
+struct Notes<'a> {
+    continuation: Option<&'a Continuation>,
+    key: Option<Key>,
+    post_process: Option<&'a PostProcess>,
+    // whether the score is handed back to be played as a preview
+    preview: bool,
+    // the candidate's, for humanizing
+    seed: u64,
+}
+
+// Turns the generated tokens into the artifact the request asked for, and its score when the
+// request asked for a preview. A continuation's MIDI holds only the new bars, decoded after the
+// material they follow so they land on the right beat. Post processing runs on those bars before
+// they are encoded.
+fn render(model: &Model, generated: &[u32], format: OutputFormat, notes: &Notes) -> anyhow::Result<(Artifact, Option<Score>)> {
+    match format {
+        OutputFormat::Text => {
+            let text = model.tokenizer.decode(generated, true)
+                .map_err(|e| anyhow::anyhow!("could not decode output: {}", e))?;
+            Ok((Artifact::text(text), None))
+        }
+        OutputFormat::Midi | OutputFormat::Midi0 | OutputFormat::MusicXml | OutputFormat::Abc | OutputFormat::Notes => {
+            let mut names: Vec<String> = notes.continuation.map(|c| c.prefix.clone()).unwrap_or_default();
+            names.extend(generated.iter().filter_map(|&id| model.tokenizer.id_to_token(id)));
+            let mut score = music::decode(names.iter().map(String::as_str), &model.manifest.music);
+            if let Some(continuation) = notes.continuation {
+                score = score.window(continuation.start, continuation.end);
+            }
+            if let Some(post_process) = notes.post_process {
+                post_process.apply(&mut score, notes.seed);
+            }
+            let artifact = match format {
+                OutputFormat::Midi0 => Artifact::binary(MIDI_CONTENT_TYPE, &score.to_smf(SmfFormat::Single)),
+                OutputFormat::MusicXml => Artifact::binary(MUSICXML_CONTENT_TYPE, &musicxml::encode(&Notation::new(&score, notes.key))),
+                OutputFormat::Abc => Artifact::binary(ABC_CONTENT_TYPE, &abc::encode(&Notation::new(&score, notes.key))),
+                OutputFormat::Notes => Artifact::binary(NOTES_CONTENT_TYPE, &score.to_json()),
+                _ => Artifact::binary(MIDI_CONTENT_TYPE, &score.to_smf(SmfFormat::MultiTrack)),
+            };
+            Ok((artifact, notes.preview.then_some(score)))
+        }
+        OutputFormat::Wav | OutputFormat::Flac => anyhow::bail!("model {} renders no audio", model.name),
+    }
+}
+
+// Synthesizes a candidate's preview on the blocking pool, a long score takes seconds to play
+async fn play(preview: Option<&Preview>, score: Option<Score>) -> anyhow::Result<Option<Artifact>> {
+    let (Some(preview), Some(score)) = (preview.cloned(), score) else {
+        return Ok(None);
+    };
+    Ok(Some(tokio::task::spawn_blocking(move || preview.render(&score)).await?))
+}
+
+// Artifacts of the candidates that succeeded, in candidate order, and how every candidate did
+struct Completed {
+    results: Vec<Variation>,
+    candidates: Vec<CandidateSummary>,
+}
+
+// Drains a generation and returns its artifacts, or the last error when no candidate succeeded
+async fn run_to_completion(events: impl Stream<Item = GenerationEvent>) -> Result<Completed, ApiError> {
+    let mut events = pin!(events);
+    let mut completed = Completed { results: Vec::new(), candidates: Vec::new() };
+    let mut error = String::from("generation ended without a result");
+    while let Some(event) = events.next().await {
+        match event {
+            GenerationEvent::Result { candidate, artifact, preview } => completed.results.push(Variation { candidate, artifact, preview }),
+            GenerationEvent::Error { message, .. } => error = message,
+            GenerationEvent::Summary { candidates } => completed.candidates = candidates,
+            _ => {}
+        }
+    }
+    if completed.results.is_empty() {
+        return Err(ApiError::Internal(error));
+    }
+    completed.results.sort_by_key(|result| result.candidate);
+    Ok(completed)
+}
+
+// Dropping a generation's stream, when its client disconnects or its job is cancelled, stops it
+// at the next await between decoding steps. This logs how far it got.
+pub struct CancelGuard {
+    pub request_id: String,
+    pub step: usize,
+    pub finished: bool,
+}
+
+impl Drop for CancelGuard {
+    fn drop(&mut self) {
+        if !self.finished {
+            tracing::info!("request {} cancelled after {} steps", self.request_id, self.step);
+        }
+    }
+}
+
 #[derive(Deserialize)]
-struct PromptRequest {
-    prompt: String,
+pub struct PromptRequest {
+    // may be empty when conditioning fields are given
+    #[serde(default)]
+    pub prompt: String,
+    // Name of the model to generate with, the server's default model when missing
+    pub model: Option<String>,
+    pub max_new_tokens: Option<usize>,
+    // number of candidates to generate, each with its own seed
+    pub num_variations: Option<usize>,
+    // negotiated from the Accept header when missing, then text for midi models and wav for
+    // audio models
+    pub output_format: Option<OutputFormat>,
+    // `false` waits for the generation and answers with the artifact itself instead of events,
+    // the default when the Accept header asks for a file format only
+    pub stream: Option<bool>,
+    #[serde(flatten)]
+    pub sampling: SamplingParams,
+    #[serde(flatten)]
+    pub conditioning: ConditioningFields,
+    #[serde(flatten)]
+    pub constraints: ConstraintParams,
+    #[serde(flatten)]
+    pub post_process: PostProcessParams,
+    #[serde(flatten)]
+    pub preview: PreviewParams,
+    #[serde(flatten)]
+    pub audio: AudioParams,
+    #[serde(flatten)]
+    pub mastering: MasteringParams,
 }
 
-impl FromRef<AppState> for Arc<Mutex<Session>> {
+// A validated request, ready to run
+pub struct Generation {
+    pub model: Arc<Model>,
+    pub tokens: Vec<i64>,
+    pub gen_tokens: usize,
+    // one per variation
+    pub samplers: Vec<Sampler>,
+    pub constraints: Option<Constraints>,
+    pub output_format: OutputFormat,
+    pub continuation: Option<Continuation>,
+    // stops generation at the bar token that would start one bar more than this
+    pub bar_limit: Option<u32>,
+    // key the notation formats are written in, estimated from the notes when missing
+    pub key: Option<Key>,
+    // runs on the decoded notes of every candidate
+    pub post_process: Option<PostProcess>,
+    // a WAV rendering of every candidate's notes
+    pub preview: Option<Preview>,
+    // only for audio models
+    pub audio: Option<AudioRequest>,
+    // runs on the waveform of every candidate
+    pub mastering: Option<Mastering>,
+}
+
+// Resolves the requested model and validates the request against it. Conditioning fields the
+// model has tokens for follow the BOS token, the others are described at the end of the prompt.
+// The number of tokens to generate is capped to what fits in the context window. An explicit
+// output format wins over the Accept header.
+pub fn prepare(registry: &ModelRegistry, body: PromptRequest, accept: &Accept) -> Result<Generation, ApiError> {
+    let model = resolve_model(registry, body.model.as_deref())?;
+    let conditioning = Conditioning::parse(body.conditioning)?;
+    let constraints = Constraints::new(body.constraints, &conditioning, &model)?;
+
+    if body.prompt.trim().is_empty() && conditioning.is_empty() {
+        return Err(ApiError::EmptyPrompt);
+    }
+    if body.prompt.len() > MAX_PROMPT_BYTES {
+        return Err(ApiError::PromptTooLong { len: body.prompt.len(), max: MAX_PROMPT_BYTES });
+    }
+
+    let output_format = match body.output_format {
+        Some(format) => format,
+        None => accept.choose(model.manifest.modality)?.unwrap_or(OutputFormat::default_for(model.manifest.modality)),
+    };
+    check_format(&model, output_format)?;
+    let post_process = PostProcess::new(body.post_process, &model, output_format)?;
+    let preview = Preview::new(body.preview, &model, output_format, registry.soundfont())?;
+    let mastering = Mastering::new(body.mastering, &model)?;
+
+    let (conditioning_ids, description) = conditioning.apply(&model)?;
+    let prompt = match (body.prompt.trim_end(), description.as_str()) {
+        (prompt, "") => prompt.to_string(),
+        ("", description) => description.to_string(),
+        (prompt, description) => format!("{}\n{}", prompt, description),
+    };
+    let mut tokens = encode_prompt(&model.tokenizer, &model.manifest, &prompt)
+        .map_err(|e| ApiError::Internal(format!("{:#}", e)))?;
+    let after_bos = usize::from(model.manifest.tokens.bos.is_some());
+    tokens.splice(after_bos..after_bos, conditioning_ids);
+
+    // unless the prompt ends by opening a bar, the first generated bar token opens the first bar
+    let bar_limit = conditioning.bars.filter(|_| model.manifest.modality == Modality::Midi).map(|bars| {
+        let bar_token = model.tokenizer.token_to_id(music::BAR_TOKEN).map(|id| id as i64);
+        if tokens.last().is_some_and(|&last| Some(last) == bar_token) { bars } else { bars + 1 }
+    });
+
+    // an audio request asks for a duration rather than a number of tokens
+    let audio = audio::request(&model, body.audio, output_format, &prompt, tokens.len())?;
+    let gen_tokens = match &audio {
+        Some(audio) => audio.frames,
+        None => fit_context(&model, tokens.len(), body.max_new_tokens)?,
+    };
+    Ok(Generation {
+        model,
+        tokens,
+        gen_tokens,
+        samplers: samplers(body.sampling, body.num_variations)?,
+        constraints,
+        output_format,
+        continuation: None,
+        bar_limit,
+        key: conditioning.key,
+        post_process,
+        preview,
+        audio,
+        mastering,
+    })
+}
+
+pub fn resolve_model(registry: &ModelRegistry, name: Option<&str>) -> Result<Arc<Model>, ApiError> {
+    match (registry.get(name), name) {
+        (Some(model), _) => Ok(model),
+        (None, Some(name)) => Err(ApiError::UnknownModel(name.to_string())),
+        (None, None) => Err(ApiError::ModelUnavailable),
+    }
+}
+
+// A sampler for each requested variation, seeded from the requested seed
+pub fn samplers(params: SamplingParams, num_variations: Option<usize>) -> Result<Vec<Sampler>, ApiError> {
+    let count = num_variations.unwrap_or(1);
+    if count == 0 || count > MAX_VARIATIONS {
+        return Err(ApiError::InvalidRequest(format!("num_variations must be between 1 and {}", MAX_VARIATIONS)));
+    }
+    if !(0.0..).contains(&params.temperature) {
+        return Err(ApiError::InvalidRequest(String::from("temperature must be at least 0")));
+    }
+    if params.repetition_penalty.is_nan() || params.repetition_penalty <= 0.0 {
+        return Err(ApiError::InvalidRequest(String::from("repetition_penalty must be above 0")));
+    }
+    for (name, p) in [("top_p", params.top_p), ("min_p", params.min_p)] {
+        if !(0.0..=1.0).contains(&p) {
+            return Err(ApiError::InvalidRequest(format!("{} must be between 0 and 1", name)));
+        }
+    }
+    Ok(Sampler::variations(params, count))
+}
+
+pub fn check_format(model: &Model, format: OutputFormat) -> Result<(), ApiError> {
+    match model.manifest.modality {
+        _ if format.produced_by(model.manifest.modality) => Ok(()),
+        Modality::Midi => Err(ApiError::InvalidRequest(format!(
+            "model {} does not produce audio, its output formats are text, midi, midi0, musicxml, abc and notes",
+            model.name
+        ))),
+        Modality::Audio => Err(ApiError::InvalidRequest(format!(
+            "model {} produces audio, its output formats are wav and flac",
+            model.name
+        ))),
+    }
+}
+
+// Number of tokens to generate after a prompt of `prompt_len` tokens, capped to what fits in the
+// model's context window
+pub fn fit_context(model: &Model, prompt_len: usize, max_new_tokens: Option<usize>) -> Result<usize, ApiError> {
+    let gen_tokens = max_new_tokens.unwrap_or(DEFAULT_NEW_TOKENS).min(MAX_NEW_TOKENS);
+    match model.manifest.context_length {
+        Some(context_length) if prompt_len >= context_length => {
+            Err(ApiError::ContextOverflow { tokens: prompt_len, context_length })
+        }
+        Some(context_length) => Ok(gen_tokens.min(context_length - prompt_len)),
+        None => Ok(gen_tokens),
+    }
+}
+
+pub fn encode_prompt(tokenizer: &Tokenizer, manifest: &Manifest, prompt: &str) -> anyhow::Result<Vec<i64>> {
+    let encoding = tokenizer
+        .encode(prompt, true)
+        .map_err(|e| anyhow::anyhow!("tokenizer error: {}", e))?;
+    let mut tokens: Vec<i64> = encoding.get_ids().iter().map(|&id| id as i64).collect();
+
+    // not every tokenizer's post-processor adds the BOS token the model was trained with
+    if let Some(bos) = manifest.tokens.bos.map(|id| id as i64)
+        && tokens.first() != Some(&bos) {
+        tokens.insert(0, bos);
+    }
+    Ok(tokens)
+}
+
+fn stop_token_ids(manifest: &Manifest) -> Vec<i64> {
+    manifest.tokens.eos.iter().map(|&id| id as i64).collect()
+}
+
+impl FromRef<AppState> for Arc<ModelRegistry> {
     fn from_ref(input: &AppState) -> Self {
-        Arc::clone(&input.session)
+        Arc::clone(&input.registry)
     }
 }
 
-impl FromRef<AppState> for Arc<Tokenizer> {
+impl FromRef<AppState> for Arc<JobStore> {
     fn from_ref(input: &AppState) -> Self {
-        Arc::clone(&input.tokenizer)
+        Arc::clone(&input.jobs)
     }
 }
 
 async fn generate(
-    State(session): State<Arc<Mutex<Session>>>,
-    State(tokenizer): State<Arc<Tokenizer>>,
-    Json(body): Json<PromptRequest>
-)-> Sse<impl Stream<Item = ort::Result<Event>>> {
-    let encoding = tokenizer
-        .encode(body.prompt.clone(), true)
-        .map_err(|e| {
-            tracing::error!("tokenizer error: {}", e);
-            axum::http::StatusCode::INTERNAL_SERVER_ERROR
-        });
+    State(registry): State<Arc<ModelRegistry>>,
+    headers: HeaderMap,
+    body: Result<Json<PromptRequest>, JsonRejection>
+)-> Result<Response, ApiError> {
+    let Json(body) = body?;
+    let accept = Accept::from_headers(&headers);
+    let stream = body.stream.unwrap_or(!accept.wants_file());
+    respond(prepare(&registry, body, &accept)?, stream).await
+}
 
-    let tokens: Vec<i64> = encoding
-        .unwrap()
-        .get_ids()
-        .iter()
-        .map(|&id| id as i64)
-        .collect();
-    Sse::new(generate_stream(tokenizer, session, tokens, GEN_TOKENS)).keep_alive(KeepAlive::new())
+// Streams the generation's events, or waits for it and answers with the artifact itself. Several
+// variations come back as JSON, with their artifacts inline and the summary to rank them by. A
+// single artifact is the whole answer, previews need a stream, a job or variations.
+pub async fn respond(generation: Generation, stream: bool) -> Result<Response, ApiError> {
+    let request_id = events::request_id();
+    if !stream {
+        let extension = generation.output_format.extension();
+        let variations = generation.samplers.len() > 1;
+        let mut completed = run_to_completion(generation_events(request_id.clone(), generation)).await?;
+        if variations {
+            return Ok(Json(Variations {
+                request_id,
+                candidates: completed.candidates,
+                results: completed.results,
+            }).into_response());
+        }
+        let Variation { artifact, .. } = completed.results.remove(0);
+        let bytes = artifact.bytes().unwrap_or_default();
+        let disposition = format!("attachment; filename=\"{}.{}\"", request_id, extension);
+        let headers = [(header::CONTENT_TYPE, artifact.content_type), (header::CONTENT_DISPOSITION, disposition)];
+        return Ok((headers, bytes).into_response());
+    }
+
+    let stream = generation_events(request_id, generation)
+        .enumerate()
+        .map(|(id, event)| Ok::<_, Infallible>(event.to_sse(id)));
+    Ok(Sse::new(stream).keep_alive(KeepAlive::new()).into_response())
 }
 
+#[derive(Serialize)]
+struct Variations {
+    request_id: String,
+    candidates: Vec<CandidateSummary>,
+    results: Vec<Variation>,
+}
 
+#[derive(Serialize)]
+struct Variation {
+    candidate: usize,
+    artifact: Artifact,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview: Option<Artifact>,
+}
+
+#[derive(Serialize)]
+struct Health {
+    status: &'static str,
+}
+
+#[derive(Serialize)]
+struct Readiness {
+    ready: bool,
+    default_model: String,
+    models: Vec<ModelInfo>,
+}
+
+#[derive(Serialize)]
+struct Version {
+    server: &'static str,
+    protocol: u32,
+    output_formats: &'static [&'static str],
+    default_model: Option<ModelVersion>,
+}
+
+#[derive(Serialize)]
+struct ModelVersion {
+    name: String,
+    hash: String,
+}
+
+// Liveness, answers as soon as the server accepts connections
+async fn health() -> Json<Health> {
+    Json(Health { status: "ok" })
+}
+
+// Ready once the default model is loaded and can take requests
+async fn ready(State(registry): State<Arc<ModelRegistry>>) -> (StatusCode, Json<Readiness>) {
+    let ready = registry.get(None).is_some();
+    let status = if ready { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(Readiness {
+        ready,
+        default_model: registry.default_name().to_string(),
+        models: registry.list(),
+    }))
+}
+
+// What clients need to decide whether they can talk to this server
+async fn version(State(registry): State<Arc<ModelRegistry>>) -> Json<Version> {
+    Json(Version {
+        server: env!("CARGO_PKG_VERSION"),
+        protocol: PROTOCOL_VERSION,
+        output_formats: OUTPUT_FORMATS,
+        default_model: registry.get(None).map(|m| ModelVersion { name: m.name.clone(), hash: m.hash.clone() }),
+    })
+}
+
+async fn list_models(State(registry): State<Arc<ModelRegistry>>) -> Json<Vec<ModelInfo>> {
+    Json(registry.list())
+}
+
+// Swaps in a fresh copy of the model from disk, streams already running finish on the old one
+async fn reload_model(
+    State(registry): State<Arc<ModelRegistry>>,
+    Path(name): Path<String>
+) -> StatusCode {
+    if registry.get(Some(&name)).is_none() {
+        return StatusCode::NOT_FOUND;
+    }
+    match tokio::task::spawn_blocking(move || registry.reload(&name)).await {
+        Ok(Ok(())) => StatusCode::NO_CONTENT,
+        Ok(Err(e)) => {
+            tracing::error!("reload failed: {:#}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+        Err(e) => {
+            tracing::error!("reload panicked: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use crate::{
+    manifest::{MusicSpec, TokenScheme},
+    midi::{Instrument, Note, Score, Tempo, TimeSignature}
+};
+
+
+// Velocity of notes decoded before any velocity token
+const DEFAULT_VELOCITY: u8 = 100;
+// miditok marks the drum kit as program -1
+pub const DRUM_PROGRAM: i32 = -1;
+pub const BAR_TOKEN: &str = "Bar_None";
+
+// Where generated tokens sit relative to uploaded material they continue or fill in
+pub struct Continuation {
+    // tokens of the material before the new one, decoded together with the generated tokens
+    pub prefix: Vec<String>,
+    // ticks of the new material in the decoded score
+    pub start: u32,
+    pub end: u32,
+}
+
+// One token of a REMI or MIDI-like vocabulary. Times are already converted to ticks.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum MusicToken {
+    Bar,
+    Position(u32),
+    TimeShift(u32),
+    Duration(u32),
+    Pitch(u8),
+    PitchDrum(u8),
+    NoteOn(u8),
+    NoteOff(u8),
+    Velocity(u8),
+    Tempo(f64),
+    TimeSig(u8, u8),
+    Program(Instrument),
+}
+
+impl MusicToken {
+    // Parses miditok token names like "Pitch_60", "Duration_1.0.8" or "TimeSig_3/4". Special and
+    // unknown tokens give `None`.
+    pub fn parse(name: &str, spec: &MusicSpec) -> Option<Self> {
+        let (kind, value) = name.split_once('_')?;
+        let token = match kind {
+            "Bar" => Self::Bar,
+            "Position" => Self::Position(value.parse().ok()?),
+            "TimeShift" => Self::TimeShift(parse_ticks(value, spec)?),
+            "Duration" => Self::Duration(parse_ticks(value, spec)?),
+            "Pitch" => Self::Pitch(parse_pitch(value)?),
+            "PitchDrum" => Self::PitchDrum(parse_pitch(value)?),
+            "NoteOn" => Self::NoteOn(parse_pitch(value)?),
+            "NoteOff" => Self::NoteOff(parse_pitch(value)?),
+            "Velocity" => Self::Velocity(value.parse::<u8>().ok()?.min(127)),
+            "Tempo" => Self::Tempo(value.parse().ok().filter(|&bpm: &f64| bpm > 0.0)?),
+            "TimeSig" => {
+                let (numerator, denominator) = value.split_once('/')?;
+                Self::TimeSig(numerator.parse().ok()?, denominator.parse().ok().filter(|d: &u8| d.is_power_of_two())?)
+            }
+            "Program" => match value.parse::<i32>().ok()? {
+                DRUM_PROGRAM => Self::Program(Instrument::Drums),
+                program => Self::Program(Instrument::Program(u8::try_from(program).ok().filter(|&p| p < 128)?)),
+            },
+            _ => return None,
+        };
+        Some(token)
+    }
+}
+
+// Values the model has tokens for. Encoding snaps velocities, tempos and times to the nearest
+// one since only a few are in a typical vocabulary.
+#[derive(Default)]
+pub struct Vocabulary {
+    velocities: Vec<(f64, String)>,
+    tempos: Vec<(f64, String)>,
+    durations: Vec<(f64, String)>,
+    time_shifts: Vec<(f64, String)>,
+}
+
+impl Vocabulary {
+    pub fn new<'a>(names: impl IntoIterator<Item = &'a str>, spec: &MusicSpec) -> Self {
+        let mut vocab = Self::default();
+        for name in names {
+            let (list, value) = match MusicToken::parse(name, spec) {
+                Some(MusicToken::Velocity(v)) => (&mut vocab.velocities, v as f64),
+                Some(MusicToken::Tempo(bpm)) => (&mut vocab.tempos, bpm),
+                Some(MusicToken::Duration(ticks)) => (&mut vocab.durations, ticks as f64),
+                Some(MusicToken::TimeShift(ticks)) if ticks > 0 => (&mut vocab.time_shifts, ticks as f64),
+                _ => continue,
+            };
+            list.push((value, name.to_string()));
+        }
+        for list in [&mut vocab.velocities, &mut vocab.tempos, &mut vocab.durations, &mut vocab.time_shifts] {
+            list.sort_by(|a, b| a.0.total_cmp(&b.0));
+        }
+        vocab
+    }
+
+    fn nearest(list: &[(f64, String)], value: f64) -> Option<&str> {
+        list.iter()
+            .min_by(|a, b| (a.0 - value).abs().total_cmp(&(b.0 - value).abs()))
+            .map(|(_, name)| name.as_str())
+    }
+
+    // Time shifts adding up to as much of `ticks` as the vocabulary can express, largest first,
+    // and the ticks they cover
+    fn time_shifts(&self, ticks: u32) -> (Vec<String>, u32) {
+        let mut out = Vec::new();
+        let mut covered = 0;
+        while let Some((value, name)) = self.time_shifts.iter().rev().find(|(v, _)| covered + *v as u32 <= ticks) {
+            out.push(name.clone());
+            covered += *value as u32;
+        }
+        (out, covered)
+    }
+}
+
+// Tokenizes a score at the spec's resolution, bar by bar for the first `bars` bars. The tokens
+// end at the start of the bar after them, so a model fed them goes on from there.
+pub fn encode(score: &Score, bars: u32, spec: &MusicSpec, vocab: &Vocabulary) -> Vec<String> {
+    let score = score.resample(spec.ticks_per_beat);
+    let bar_ticks = score.bar_ticks();
+    let end = bars * bar_ticks;
+    let unit = (spec.ticks_per_beat as u32 / spec.positions_per_beat.max(1)).max(1);
+    let quantize = |tick: u32| (tick + unit / 2) / unit * unit;
+
+    let mut notes: Vec<Note> = score.notes.iter()
+        .map(|n| Note { start: quantize(n.start), duration: quantize(n.duration).max(unit), ..*n })
+        .filter(|n| n.start < end)
+        .collect();
+    notes.sort_by_key(|n| (n.start, n.instrument, n.pitch));
+    let tempos: Vec<Tempo> = score.tempos.iter().map(|t| Tempo { tick: quantize(t.tick), ..*t }).collect();
+
+    let time_signature = score.time_signatures.first()
+        .map(|sig| format!("TimeSig_{}/{}", sig.numerator, sig.denominator));
+
+    let mut out = Vec::new();
+    match spec.scheme {
+        TokenScheme::Remi => {
+            let mut instrument = None;
+            let mut notes = notes.iter().peekable();
+            let mut tempos = tempos.iter().peekable();
+            // the last bar token only marks where the material ends
+            for bar in 0..=bars {
+                let bar_start = bar * bar_ticks;
+                out.push(BAR_TOKEN.to_string());
+                if bar == 0 {
+                    out.extend(time_signature.clone());
+                }
+                let mut position = None;
+                while let Some(note) = notes.next_if(|n| n.start < bar_start + bar_ticks) {
+                    if position != Some(note.start) {
+                        out.push(format!("Position_{}", (note.start - bar_start) / unit));
+                        // tempo changes between notes only take effect here, the last one wins
+                        let mut tempo = None;
+                        while let Some(next) = tempos.next_if(|t| t.tick <= note.start) {
+                            tempo = Some(next);
+                        }
+                        if let Some(tempo) = tempo {
+                            out.extend(Vocabulary::nearest(&vocab.tempos, tempo.bpm).map(String::from));
+                        }
+                        position = Some(note.start);
+                    }
+                    push_program(&mut out, &mut instrument, note.instrument);
+                    out.push(format!("Pitch_{}", note.pitch));
+                    out.extend(Vocabulary::nearest(&vocab.velocities, note.velocity as f64).map(String::from));
+                    out.extend(Vocabulary::nearest(&vocab.durations, note.duration as f64).map(String::from));
+                }
+            }
+        }
+        TokenScheme::MidiLike => {
+            out.extend(time_signature);
+            encode_midi_like(&notes, &tempos, end, vocab, &mut out);
+        }
+    }
+    out
+}
+
+fn push_program(out: &mut Vec<String>, current: &mut Option<Instrument>, instrument: Instrument) {
+    if *current != Some(instrument) {
+        let program = match instrument {
+            Instrument::Program(p) => p as i32,
+            Instrument::Drums => DRUM_PROGRAM,
+        };
+        out.push(format!("Program_{}", program));
+        *current = Some(instrument);
+    }
+}
+
+fn encode_midi_like(notes: &[Note], tempos: &[Tempo], end: u32, vocab: &Vocabulary, out: &mut Vec<String>) {
+    // (tick, order, instrument, token), tempo first, then note offs, then note ons with their velocity
+    let mut events: Vec<(u32, u8, Option<Instrument>, String)> = Vec::new();
+    for tempo in tempos.iter().filter(|t| t.tick < end) {
+        if let Some(name) = Vocabulary::nearest(&vocab.tempos, tempo.bpm) {
+            events.push((tempo.tick, 0, None, name.to_string()));
+        }
+    }
+    for note in notes {
+        events.push((note.start, 2, Some(note.instrument), format!("NoteOn_{}", note.pitch)));
+        if let Some(name) = Vocabulary::nearest(&vocab.velocities, note.velocity as f64) {
+            events.push((note.start, 3, None, name.to_string()));
+        }
+        events.push(((note.start + note.duration).min(end), 1, Some(note.instrument), format!("NoteOff_{}", note.pitch)));
+    }
+    // stable, so each velocity stays right behind its note on
+    events.sort_by_key(|(tick, order, _, _)| (*tick, (*order).min(2)));
+
+    let mut instrument = None;
+    let mut time = 0;
+    for (tick, _, event_instrument, name) in events {
+        let (shifts, covered) = vocab.time_shifts(tick - time);
+        out.extend(shifts);
+        time += covered;
+        if let Some(event_instrument) = event_instrument {
+            push_program(out, &mut instrument, event_instrument);
+        }
+        out.push(name);
+    }
+    out.extend(vocab.time_shifts(end - time).0);
+}
+
+// "beats.positions.resolution", as miditok writes durations and time shifts, or a plain count
+// of positions
+fn parse_ticks(value: &str, spec: &MusicSpec) -> Option<u32> {
+    let tpb = spec.ticks_per_beat as u64;
+    let parts: Vec<u64> = value.split('.').map(|p| p.parse().ok()).collect::<Option<_>>()?;
+    let ticks = match parts[..] {
+        [beats, positions, resolution] if resolution > 0 => (beats * resolution + positions) * tpb / resolution,
+        [positions] => positions * tpb / spec.positions_per_beat.max(1) as u64,
+        _ => return None,
+    };
+    u32::try_from(ticks).ok()
+}
+
+fn parse_pitch(value: &str) -> Option<u8> {
+    value.parse::<u8>().ok().filter(|&p| p < 128)
+}
+
+// Builds a score from token names, in generation order. Tokens that are out of place, like a
+// duration with no pitch before it, are skipped rather than failing the whole sequence.
+pub fn decode<'a>(names: impl IntoIterator<Item = &'a str>, spec: &MusicSpec) -> Score {
+    let mut score = Score::new(spec.ticks_per_beat);
+    let tpb = spec.ticks_per_beat as u32;
+    let mut tick = 0u32;
+    let mut bar_start: Option<u32> = None;
+    let mut bar_ticks = 4 * tpb;
+    let mut instrument = Instrument::Program(0);
+    let mut velocity = DEFAULT_VELOCITY;
+    // REMI note waiting for its duration
+    let mut pending: Option<Note> = None;
+    // MIDI-like notes waiting for their note off
+    let mut open: Vec<Note> = Vec::new();
+    let mut previous = None;
+
+    for token in names.into_iter().filter_map(|name| MusicToken::parse(name, spec)) {
+        match token {
+            MusicToken::Bar => {
+                let start = bar_start.map_or(tick, |start| start + bar_ticks);
+                bar_start = Some(start);
+                tick = start;
+            }
+            MusicToken::Position(position) => {
+                tick = bar_start.unwrap_or(0) + position * tpb / spec.positions_per_beat.max(1);
+            }
+            MusicToken::TimeShift(ticks) => tick += ticks,
+            MusicToken::Duration(ticks) => {
+                if let Some(mut note) = pending.take() {
+                    note.duration = ticks.max(1);
+                    score.notes.push(note);
+                }
+            }
+            MusicToken::Pitch(pitch) | MusicToken::PitchDrum(pitch) => {
+                let instrument = if matches!(token, MusicToken::PitchDrum(_)) { Instrument::Drums } else { instrument };
+                pending = Some(Note { start: tick, duration: 0, pitch, velocity, instrument });
+            }
+            MusicToken::NoteOn(pitch) => {
+                open.push(Note { start: tick, duration: 0, pitch, velocity, instrument });
+            }
+            MusicToken::NoteOff(pitch) => {
+                if let Some(i) = open.iter().position(|n| n.pitch == pitch && n.instrument == instrument) {
+                    let mut note = open.remove(i);
+                    // a position token can move back before the note started
+                    note.duration = tick.saturating_sub(note.start).max(1);
+                    score.notes.push(note);
+                }
+            }
+            MusicToken::Velocity(value) => {
+                velocity = value;
+                // REMI puts the velocity after the pitch, MIDI-like after the note on
+                if let Some(note) = pending.as_mut() {
+                    note.velocity = value;
+                } else if matches!(previous, Some(MusicToken::NoteOn(_))) && let Some(note) = open.last_mut() {
+                    note.velocity = value;
+                }
+            }
+            MusicToken::Tempo(bpm) => {
+                score.tempos.retain(|t| t.tick != tick);
+                score.tempos.push(Tempo { tick, bpm });
+            }
+            MusicToken::TimeSig(numerator, denominator) => {
+                let signature = TimeSignature { tick, numerator, denominator };
+                bar_ticks = signature.bar_ticks(spec.ticks_per_beat);
+                score.time_signatures.retain(|t| t.tick != tick);
+                score.time_signatures.push(signature);
+            }
+            MusicToken::Program(program) => instrument = program,
+        }
+        previous = Some(token);
+    }
+
+    // notes still held when the sequence ended stop where it ended
+    for mut note in open {
+        note.duration = tick.saturating_sub(note.start).max(1);
+        score.notes.push(note);
+    }
+    score.sort();
+    score
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{manifest::TokenScheme, midi::SmfFormat};
+
+    fn spec(scheme: TokenScheme) -> MusicSpec {
+        MusicSpec { scheme, positions_per_beat: 8, ticks_per_beat: 480, ..MusicSpec::default() }
+    }
+
+    // miditok's default REMI vocabulary, up to two beats of duration and time shift
+    fn vocab(spec: &MusicSpec) -> Vocabulary {
+        let mut names: Vec<String> = (1..=16).map(|p| format!("Duration_{}.{}.8", p / 8, p % 8)).collect();
+        names.extend((1..=16).map(|p| format!("TimeShift_{}.{}.8", p / 8, p % 8)));
+        names.extend((0..32).map(|i| format!("Velocity_{}", i * 4 + 3)));
+        names.extend(["Tempo_90.0", "Tempo_120.0", "Tempo_150.0"].map(String::from));
+        Vocabulary::new(names.iter().map(String::as_str), spec)
+    }
+
+    fn example() -> Score {
+        let mut score = Score::new(480);
+        score.tempos.push(Tempo { tick: 0, bpm: 120.0 });
+        score.time_signatures.push(TimeSignature { tick: 0, numerator: 4, denominator: 4 });
+        score.notes = vec![
+            note(0, 480, 60, 79, Instrument::Program(0)),
+            note(0, 960, 64, 79, Instrument::Program(0)),
+            note(240, 120, 36, 103, Instrument::Drums),
+            note(1920, 240, 67, 63, Instrument::Program(0)),
+            note(2400, 480, 40, 63, Instrument::Program(33)),
+        ];
+        score
+    }
+
+    fn note(start: u32, duration: u32, pitch: u8, velocity: u8, instrument: Instrument) -> Note {
+        Note { start, duration, pitch, velocity, instrument }
+    }
+
+    #[test]
+    fn parses_token_names() {
+        let spec = spec(TokenScheme::Remi);
+        assert_eq!(MusicToken::parse("Bar_None", &spec), Some(MusicToken::Bar));
+        assert_eq!(MusicToken::parse("Duration_1.4.8", &spec), Some(MusicToken::Duration(720)));
+        assert_eq!(MusicToken::parse("TimeShift_2", &spec), Some(MusicToken::TimeShift(120)));
+        assert_eq!(MusicToken::parse("Program_-1", &spec), Some(MusicToken::Program(Instrument::Drums)));
+        assert_eq!(MusicToken::parse("TimeSig_6/8", &spec), Some(MusicToken::TimeSig(6, 8)));
+        assert_eq!(MusicToken::parse("Pitch_128", &spec), None);
+        assert_eq!(MusicToken::parse("EOS_None", &spec), None);
+        assert_eq!(MusicToken::parse("hello", &spec), None);
+    }
+
+    #[test]
+    fn remi_through_midi() {
+        let spec = spec(TokenScheme::Remi);
+        let tokens = [
+            "BOS_None", "Bar_None", "TimeSig_3/4", "Tempo_90.0", "Position_0", "Program_0",
+            "Pitch_60", "Velocity_80", "Duration_1.0.8",
+            "Pitch_64", "Velocity_80", "Duration_1.0.8",
+            "Position_8", "Program_-1", "Pitch_36", "Velocity_100", "Duration_0.2.8",
+            "Bar_None", "Position_4", "Program_33", "Pitch_40", "Velocity_70", "Duration_2.0.8",
+            // a duration without a pitch is dropped
+            "Duration_1.0.8", "EOS_None",
+        ];
+        let score = decode(tokens, &spec);
+
+        assert_eq!(score.tempos, vec![Tempo { tick: 0, bpm: 90.0 }]);
+        assert_eq!(score.time_signatures, vec![TimeSignature { tick: 0, numerator: 3, denominator: 4 }]);
+        assert_eq!(score.notes, vec![
+            note(0, 480, 60, 80, Instrument::Program(0)),
+            note(0, 480, 64, 80, Instrument::Program(0)),
+            note(480, 120, 36, 100, Instrument::Drums),
+            // second bar of 3/4 starts at beat 3, position 4 is half a beat in
+            note(1680, 960, 40, 70, Instrument::Program(33)),
+        ]);
+
+        for format in [SmfFormat::Single, SmfFormat::MultiTrack] {
+            let mut parsed = Score::from_smf(&score.to_smf(format)).unwrap();
+            // tempo goes through microseconds per quarter note
+            assert!((parsed.tempos[0].bpm - 90.0).abs() < 1e-3);
+            parsed.tempos = score.tempos.clone();
+            assert_eq!(parsed, score);
+        }
+    }
+
+    #[test]
+    fn remi_encode_round_trip() {
+        let spec = spec(TokenScheme::Remi);
+        let score = example();
+        let tokens = encode(&score, 2, &spec, &vocab(&spec));
+        assert_eq!(&tokens[..5], ["Bar_None", "TimeSig_4/4", "Position_0", "Tempo_120.0", "Program_0"]);
+        // ends at the start of the third bar
+        assert_eq!(tokens.last().map(String::as_str), Some(BAR_TOKEN));
+        assert_eq!(decode(tokens.iter().map(String::as_str), &spec), score);
+    }
+
+    #[test]
+    fn midi_like_encode_round_trip() {
+        let spec = spec(TokenScheme::MidiLike);
+        let score = example();
+        let tokens = encode(&score, 2, &spec, &vocab(&spec));
+        let decoded = decode(tokens.iter().map(String::as_str), &spec);
+        assert_eq!(decoded, score);
+    }
+
+    #[test]
+    fn encode_snaps_to_the_vocabulary() {
+        let spec = spec(TokenScheme::Remi);
+        let mut score = Score::new(96);
+        score.notes = vec![note(1, 50, 60, 82, Instrument::Program(0))];
+        let tokens = encode(&score, 1, &spec, &vocab(&spec));
+        assert_eq!(tokens, ["Bar_None", "Position_0", "Program_0", "Pitch_60", "Velocity_83", "Duration_0.4.8", "Bar_None"]);
+    }
+
+    #[test]
+    fn midi_like_through_midi() {
+        let spec = spec(TokenScheme::MidiLike);
+        let tokens = [
+            "Program_5", "NoteOn_60", "Velocity_90", "TimeShift_0.4.8",
+            "NoteOn_67", "Velocity_60", "TimeShift_0.4.8",
+            "NoteOff_60", "NoteOff_67",
+            // held until the end of the sequence
+            "NoteOn_72", "TimeShift_1.0.8",
+        ];
+        let score = decode(tokens, &spec);
+        let instrument = Instrument::Program(5);
+        assert_eq!(score.notes, vec![
+            note(0, 480, 60, 90, instrument),
+            note(240, 240, 67, 60, instrument),
+            note(480, 480, 72, 60, instrument),
+        ]);
+        assert_eq!(Score::from_smf(&score.to_smf(SmfFormat::MultiTrack)).unwrap(), score);
+    }
+
+    #[test]
+    fn notes_closed_before_they_start_are_one_tick() {
+        let spec = spec(TokenScheme::MidiLike);
+        let tokens = [
+            "Bar_None", "Position_8", "Program_0", "NoteOn_60", "NoteOn_64",
+            // back to the start of the bar
+            "Position_0", "NoteOff_60",
+        ];
+        let score = decode(tokens, &spec);
+        let instrument = Instrument::Program(0);
+        assert_eq!(score.notes, vec![
+            note(480, 1, 60, DEFAULT_VELOCITY, instrument),
+            note(480, 1, 64, DEFAULT_VELOCITY, instrument),
+        ]);
+    }
+}
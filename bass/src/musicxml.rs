@@ -0,0 +1,162 @@
+use std::fmt::Write;
+
+use crate::{
+    midi::Instrument,
+    notation::{Event, Item, Notation, Tone}
+};
+
+
+pub const MUSICXML_CONTENT_TYPE: &str = "application/vnd.recordare.musicxml+xml";
+
+// Velocity MusicXML's dynamics attribute counts as 100 percent, forte
+const FORTE_VELOCITY: f32 = 90.0;
+// Below this average pitch a part is written in the bass clef
+const BASS_CLEF_BELOW: f32 = 60.0;
+
+// Writes the laid out score as an uncompressed partwise MusicXML document with one part per
+// instrument. Divisions are the score's ticks per beat.
+pub fn encode(notation: &Notation) -> Vec<u8> {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    out.push_str("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n");
+    out.push_str("<score-partwise version=\"4.0\">\n  <part-list>\n");
+    for (i, part) in notation.parts.iter().enumerate() {
+        let id = format!("P{}", i + 1);
+        let _ = writeln!(out, "    <score-part id=\"{}\">", id);
+        let _ = writeln!(out, "      <part-name>{}</part-name>", part.name);
+        let _ = writeln!(out, "      <score-instrument id=\"{}-I1\"><instrument-name>{}</instrument-name></score-instrument>", id, part.name);
+        let _ = write!(out, "      <midi-instrument id=\"{}-I1\"><midi-channel>{}</midi-channel>", id, part.channel + 1);
+        if let Instrument::Program(program) = part.instrument {
+            let _ = write!(out, "<midi-program>{}</midi-program>", program + 1);
+        }
+        out.push_str("</midi-instrument>\n    </score-part>\n");
+    }
+    out.push_str("  </part-list>\n");
+
+    for (i, part) in notation.parts.iter().enumerate() {
+        let _ = writeln!(out, "  <part id=\"P{}\">", i + 1);
+        let drums = part.instrument == Instrument::Drums;
+        let clef = match (drums, part.mean_pitch()) {
+            (true, _) => "<clef><sign>percussion</sign></clef>",
+            (false, Some(pitch)) if pitch < BASS_CLEF_BELOW => "<clef><sign>F</sign><line>4</line></clef>",
+            (false, _) => "<clef><sign>G</sign><line>2</line></clef>",
+        };
+
+        for (number, (measure, voices)) in notation.measures.iter().zip(&part.measures).enumerate() {
+            let end = measure.start + measure.len;
+            let _ = writeln!(out, "    <measure number=\"{}\">", number + 1);
+            if number == 0 || measure.signature.is_some() {
+                out.push_str("      <attributes>\n");
+                if number == 0 {
+                    let _ = writeln!(out, "        <divisions>{}</divisions>", notation.ticks_per_beat);
+                    let _ = writeln!(
+                        out,
+                        "        <key><fifths>{}</fifths><mode>{}</mode></key>",
+                        notation.key.fifths(), notation.key.mode.name()
+                    );
+                }
+                if let Some(signature) = measure.signature {
+                    let _ = writeln!(
+                        out,
+                        "        <time><beats>{}</beats><beat-type>{}</beat-type></time>",
+                        signature.numerator, signature.denominator
+                    );
+                }
+                if number == 0 {
+                    let _ = writeln!(out, "        {}", clef);
+                }
+                out.push_str("      </attributes>\n");
+            }
+            // tempo marks go above the first part only
+            for tempo in notation.tempos.iter().filter(|t| i == 0 && t.tick >= measure.start && t.tick < end) {
+                out.push_str("      <direction placement=\"above\">\n");
+                let _ = writeln!(
+                    out,
+                    "        <direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>{}</per-minute></metronome></direction-type>",
+                    tempo.bpm.round()
+                );
+                if tempo.tick > measure.start {
+                    let _ = writeln!(out, "        <offset>{}</offset>", tempo.tick - measure.start);
+                }
+                let _ = writeln!(out, "        <sound tempo=\"{}\"/>", (tempo.bpm * 100.0).round() / 100.0);
+                out.push_str("      </direction>\n");
+            }
+
+            for (voice, events) in voices.iter().enumerate() {
+                if voice > 0 {
+                    let _ = writeln!(out, "      <backup><duration>{}</duration></backup>", measure.len);
+                }
+                for event in events {
+                    write_event(&mut out, notation, event, voice + 1, drums);
+                }
+            }
+            out.push_str("    </measure>\n");
+        }
+        out.push_str("  </part>\n");
+    }
+    out.push_str("</score-partwise>\n");
+    out.into_bytes()
+}
+
+fn write_event(out: &mut String, notation: &Notation, event: &Event, voice: usize, drums: bool) {
+    match &event.item {
+        Item::Space => {
+            let _ = writeln!(out, "      <forward><duration>{}</duration><voice>{}</voice></forward>", event.duration, voice);
+        }
+        Item::Rest => {
+            let _ = write!(out, "      <note><rest/><duration>{}</duration><voice>{}</voice>", event.duration, voice);
+            write_value(out, event);
+            out.push_str("</note>\n");
+        }
+        Item::Chord(tones) => {
+            for (i, tone) in tones.iter().enumerate() {
+                write_tone(out, notation, event, tone, i > 0, voice, drums);
+            }
+        }
+    }
+}
+
+fn write_tone(out: &mut String, notation: &Notation, event: &Event, tone: &Tone, chord: bool, voice: usize, drums: bool) {
+    let dynamics = (tone.velocity as f32 / FORTE_VELOCITY * 100.0).round();
+    let _ = write!(out, "      <note dynamics=\"{}\">", dynamics);
+    if chord {
+        out.push_str("<chord/>");
+    }
+    let (step, alter, octave) = notation.spell(tone.pitch);
+    if drums {
+        let _ = write!(out, "<unpitched><display-step>{}</display-step><display-octave>{}</display-octave></unpitched>", step, octave);
+    } else if alter != 0 {
+        let _ = write!(out, "<pitch><step>{}</step><alter>{}</alter><octave>{}</octave></pitch>", step, alter, octave);
+    } else {
+        let _ = write!(out, "<pitch><step>{}</step><octave>{}</octave></pitch>", step, octave);
+    }
+    let _ = write!(out, "<duration>{}</duration>", event.duration);
+    if tone.tie_stop {
+        out.push_str("<tie type=\"stop\"/>");
+    }
+    if tone.tie_start {
+        out.push_str("<tie type=\"start\"/>");
+    }
+    let _ = write!(out, "<voice>{}</voice>", voice);
+    write_value(out, event);
+    if tone.tie_stop || tone.tie_start {
+        out.push_str("<notations>");
+        if tone.tie_stop {
+            out.push_str("<tied type=\"stop\"/>");
+        }
+        if tone.tie_start {
+            out.push_str("<tied type=\"start\"/>");
+        }
+        out.push_str("</notations>");
+    }
+    out.push_str("</note>\n");
+}
+
+fn write_value(out: &mut String, event: &Event) {
+    if let Some(value) = event.value {
+        let _ = write!(out, "<type>{}</type>", value.name);
+        if value.dotted {
+            out.push_str("<dot/>");
+        }
+    }
+}
@@ -0,0 +1,323 @@
+use crate::{
+    conditioning::{GM_PROGRAMS, Key},
+    midi::{self, Instrument, Score, Tempo, TimeSignature}
+};
+
+
+// Shortest note value notes are quantized to, in notes per whole note
+const GRID_DIVISION: u32 = 16;
+// Note values, as MusicXML names them, with their length in quarters as a fraction
+const NOTE_VALUES: [(&str, u32, u32); 7] = [
+    ("whole", 4, 1), ("half", 2, 1), ("quarter", 1, 1), ("eighth", 1, 2),
+    ("16th", 1, 4), ("32nd", 1, 8), ("64th", 1, 16),
+];
+const SHARP_STEPS: [(char, i8); 12] = [
+    ('C', 0), ('C', 1), ('D', 0), ('D', 1), ('E', 0), ('F', 0),
+    ('F', 1), ('G', 0), ('G', 1), ('A', 0), ('A', 1), ('B', 0),
+];
+const FLAT_STEPS: [(char, i8); 12] = [
+    ('C', 0), ('D', -1), ('D', 0), ('E', -1), ('E', 0), ('F', 0),
+    ('G', -1), ('G', 0), ('A', -1), ('A', 0), ('B', -1), ('B', 0),
+];
+// Steps a key signature alters, in the order sharps and flats are added
+const SHARP_ORDER: [char; 7] = ['F', 'C', 'G', 'D', 'A', 'E', 'B'];
+const FLAT_ORDER: [char; 7] = ['B', 'E', 'A', 'D', 'G', 'C', 'F'];
+
+// A score laid out for notation: quantized, in a key, cut into bars, and each instrument's notes
+// split into voices of written note values, tied where a note takes several
+pub struct Notation {
+    pub ticks_per_beat: u16,
+    pub key: Key,
+    pub tempos: Vec<Tempo>,
+    pub measures: Vec<Measure>,
+    pub parts: Vec<Part>,
+}
+
+// A bar, with the time signature it starts when that changes
+pub struct Measure {
+    pub start: u32,
+    pub len: u32,
+    pub signature: Option<TimeSignature>,
+}
+
+pub struct Part {
+    pub instrument: Instrument,
+    pub name: &'static str,
+    pub channel: u8,
+    // per measure, the voices in it, the first one filled with rests
+    pub measures: Vec<Vec<Vec<Event>>>,
+}
+
+impl Part {
+    pub fn mean_pitch(&self) -> Option<f32> {
+        let pitches: Vec<u8> = self.measures.iter().flatten().flatten()
+            .flat_map(|e| match &e.item {
+                Item::Chord(tones) => tones.iter().map(|t| t.pitch).collect(),
+                _ => Vec::new(),
+            })
+            .collect();
+        (!pitches.is_empty()).then(|| pitches.iter().map(|&p| p as f32).sum::<f32>() / pitches.len() as f32)
+    }
+}
+
+// Something a voice does for a written note value
+pub struct Event {
+    pub duration: u32,
+    pub value: Option<NoteValue>,
+    pub item: Item,
+}
+
+pub enum Item {
+    Chord(Vec<Tone>),
+    // silence in the first voice
+    Rest,
+    // silence in another voice, not written as a rest
+    Space,
+}
+
+#[derive(Clone, Copy)]
+pub struct Tone {
+    pub pitch: u8,
+    pub velocity: u8,
+    pub tie_stop: bool,
+    pub tie_start: bool,
+}
+
+#[derive(Clone, Copy)]
+pub struct NoteValue {
+    pub name: &'static str,
+    pub dotted: bool,
+}
+
+impl Notation {
+    // Quantizes the score to sixteenth notes and lays it out. The key is estimated from the notes
+    // when it isn't given.
+    pub fn new(score: &Score, key: Option<Key>) -> Self {
+        let mut score = score.clone();
+        let ticks_per_beat = score.ticks_per_beat.max(1);
+        let whole = ticks_per_beat as u32 * 4;
+        let grid = if whole.is_multiple_of(GRID_DIVISION) { whole / GRID_DIVISION } else { 1 };
+        score.quantize(grid, 1.0);
+
+        let key = key.unwrap_or_else(|| {
+            let mut weights = [0.0; 12];
+            for note in score.notes.iter().filter(|n| n.instrument != Instrument::Drums) {
+                weights[note.pitch as usize % 12] += note.duration as f64;
+            }
+            Key::estimate(weights)
+        });
+        let measures = measures(&score);
+
+        let mut instruments = score.instruments();
+        if instruments.is_empty() {
+            instruments.push(Instrument::Program(0));
+        }
+        let channels = midi::assign_channels(&instruments);
+        let parts = instruments.iter().zip(channels)
+            .map(|(&instrument, channel)| Part {
+                instrument,
+                name: match instrument {
+                    Instrument::Program(program) => GM_PROGRAMS[program as usize & 0x7F],
+                    Instrument::Drums => "Drums",
+                },
+                channel,
+                measures: measures.iter().map(|m| lay_out(&score, m, instrument, ticks_per_beat)).collect(),
+            })
+            .collect();
+        Self { ticks_per_beat, key, tempos: score.tempos.clone(), measures, parts }
+    }
+
+    // The written step, alteration and octave of a pitch, with sharps in sharp keys and flats in
+    // flat keys. Octave 4 starts at middle C.
+    pub fn spell(&self, pitch: u8) -> (char, i8, i32) {
+        let steps = if self.key.fifths() < 0 { &FLAT_STEPS } else { &SHARP_STEPS };
+        let (step, alter) = steps[pitch as usize % 12];
+        (step, alter, pitch as i32 / 12 - 1)
+    }
+
+    // How the key signature alters a step
+    pub fn signature_alter(&self, step: char) -> i8 {
+        let fifths = self.key.fifths();
+        if fifths > 0 && SHARP_ORDER[..fifths as usize].contains(&step) {
+            1
+        } else if fifths < 0 && FLAT_ORDER[..fifths.unsigned_abs() as usize].contains(&step) {
+            -1
+        } else {
+            0
+        }
+    }
+}
+
+// Bars from tick 0 until every note has ended, at least one
+fn measures(score: &Score) -> Vec<Measure> {
+    let end = score.end().max(1);
+    let mut measures = Vec::new();
+    let mut last: Option<TimeSignature> = None;
+    let mut tick = 0;
+    while tick < end {
+        let signature = score.time_signatures.iter().rev()
+            .find(|t| t.tick <= tick)
+            .map(|t| TimeSignature { tick, ..*t })
+            .unwrap_or(TimeSignature { tick, numerator: 4, denominator: 4 });
+        let len = signature.bar_ticks(score.ticks_per_beat).max(1);
+        let changed = last.is_none_or(|l| (l.numerator, l.denominator) != (signature.numerator, signature.denominator));
+        measures.push(Measure { start: tick, len, signature: changed.then_some(signature) });
+        last = Some(signature);
+        tick += len;
+    }
+    measures
+}
+
+// One instrument's voices in a measure. Notes are cut at the barlines and tied, grouped into
+// chords of the same start and end, and the chords go to the first voice with room for them.
+fn lay_out(score: &Score, measure: &Measure, instrument: Instrument, ticks_per_beat: u16) -> Vec<Vec<Event>> {
+    let end = measure.start + measure.len;
+    let mut chords: Vec<(u32, u32, Vec<Tone>)> = Vec::new();
+    for note in score.notes.iter().filter(|n| n.instrument == instrument) {
+        let note_end = note.start + note.duration.max(1);
+        if note.start >= end || note_end <= measure.start {
+            continue;
+        }
+        let (start, stop) = (note.start.max(measure.start), note_end.min(end));
+        let tone = Tone {
+            pitch: note.pitch,
+            velocity: note.velocity,
+            tie_stop: note.start < measure.start,
+            tie_start: note_end > end,
+        };
+        match chords.iter_mut().find(|(s, e, _)| (*s, *e) == (start, stop)) {
+            Some((_, _, tones)) if tones.iter().any(|t| t.pitch == tone.pitch) => {}
+            Some((_, _, tones)) => tones.push(tone),
+            None => chords.push((start, stop, vec![tone])),
+        }
+    }
+    // notes tied over from the last bar take their voice first, so it is likely the same one
+    chords.sort_by_key(|(start, end, tones)| (*start, !tones.iter().any(|t| t.tie_stop), *end));
+
+    let mut voices: Vec<Vec<(u32, u32, Vec<Tone>)>> = Vec::new();
+    for (start, stop, mut tones) in chords {
+        tones.sort_by_key(|t| t.pitch);
+        match voices.iter_mut().find(|v| v.last().is_none_or(|(_, e, _)| *e <= start)) {
+            Some(voice) => voice.push((start, stop, tones)),
+            None => voices.push(vec![(start, stop, tones)]),
+        }
+    }
+    if voices.is_empty() {
+        voices.push(Vec::new());
+    }
+
+    voices.into_iter().enumerate()
+        .map(|(voice, chords)| {
+            let silence = || if voice == 0 { Item::Rest } else { Item::Space };
+            let mut events = Vec::new();
+            let mut cursor = measure.start;
+            for (start, stop, tones) in chords {
+                if start > cursor {
+                    push_split(&mut events, start - cursor, ticks_per_beat, |_, _| silence());
+                }
+                push_split(&mut events, stop - start, ticks_per_beat, |first, last| {
+                    Item::Chord(tones.iter()
+                        .map(|t| Tone { tie_stop: t.tie_stop || !first, tie_start: t.tie_start || !last, ..*t })
+                        .collect())
+                });
+                cursor = stop;
+            }
+            if end > cursor {
+                push_split(&mut events, end - cursor, ticks_per_beat, |_, _| silence());
+            }
+            events
+        })
+        .collect()
+}
+
+// Writes a duration as the longest note values that fit, one after the other. `item` makes what
+// each piece holds from whether it is the first and the last piece.
+fn push_split(events: &mut Vec<Event>, mut duration: u32, ticks_per_beat: u16, item: impl Fn(bool, bool) -> Item) {
+    let mut first = true;
+    while duration > 0 {
+        let (len, value) = note_values(ticks_per_beat)
+            .find(|&(len, _)| len <= duration)
+            .map_or((duration, None), |(len, value)| (len, Some(value)));
+        duration -= len;
+        events.push(Event { duration: len, value, item: item(first, duration == 0) });
+        first = false;
+    }
+}
+
+// The note values a tick resolution can write, longest first, dotted ones before their plain value
+fn note_values(ticks_per_beat: u16) -> impl Iterator<Item = (u32, NoteValue)> {
+    let ticks_per_beat = ticks_per_beat as u32;
+    NOTE_VALUES.into_iter()
+        .filter(move |(_, quarters, fraction)| (ticks_per_beat * quarters).is_multiple_of(*fraction))
+        .flat_map(move |(name, quarters, fraction)| {
+            let plain = ticks_per_beat * quarters / fraction;
+            let dotted = plain.is_multiple_of(2).then_some((plain + plain / 2, NoteValue { name, dotted: true }));
+            dotted.into_iter().chain([(plain, NoteValue { name, dotted: false })])
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{abc, conditioning::Mode, midi::Note, musicxml};
+
+    fn note(start: u32, duration: u32, pitch: u8, instrument: Instrument) -> Note {
+        Note { start, duration, pitch, velocity: 90, instrument }
+    }
+
+    // Two bars of 3/4 in D minor, slightly off the grid, with a chord, a note tied across the
+    // barline, a B flat then a B natural in the same bar, a second voice, a tempo change and drums
+    fn example() -> Score {
+        let mut score = Score::new(480);
+        score.tempos.push(Tempo { tick: 0, bpm: 90.0 });
+        score.tempos.push(Tempo { tick: 1440, bpm: 100.0 });
+        score.time_signatures.push(TimeSignature { tick: 0, numerator: 3, denominator: 4 });
+        let piano = Instrument::Program(0);
+        score.notes = vec![
+            note(5, 470, 62, piano),
+            note(0, 480, 65, piano),
+            note(0, 480, 69, piano),
+            note(482, 240, 70, piano),
+            note(720, 240, 71, piano),
+            note(960, 960, 74, piano),
+            note(1440, 235, 69, piano),
+            note(1680, 240, 67, piano),
+            note(1920, 960, 50, piano),
+            note(0, 120, 36, Instrument::Drums),
+            note(1440, 120, 38, Instrument::Drums),
+        ];
+        score.sort();
+        score
+    }
+
+    #[test]
+    fn quantizes_to_sixteenths() {
+        let notation = Notation::new(&example(), None);
+        let Item::Chord(tones) = &notation.parts[0].measures[0][0][0].item else {
+            panic!("the first event is not a chord");
+        };
+        assert_eq!(tones.iter().map(|t| t.pitch).collect::<Vec<_>>(), vec![62, 65, 69]);
+        assert_eq!(notation.parts[0].measures[0][0][0].duration, 480);
+    }
+
+    #[test]
+    fn estimates_the_key() {
+        let notation = Notation::new(&example(), None);
+        assert_eq!(notation.key, Key { tonic: 2, mode: Mode::Minor });
+        assert_eq!(notation.key.fifths(), -1);
+    }
+
+    #[test]
+    fn musicxml_matches_reference() {
+        let notation = Notation::new(&example(), Some(Key { tonic: 2, mode: Mode::Minor }));
+        let xml = String::from_utf8(musicxml::encode(&notation)).unwrap();
+        assert_eq!(xml, include_str!("../tests/data/example.musicxml"));
+    }
+
+    #[test]
+    fn abc_matches_reference() {
+        let notation = Notation::new(&example(), Some(Key { tonic: 2, mode: Mode::Minor }));
+        let abc = String::from_utf8(abc::encode(&notation)).unwrap();
+        assert_eq!(abc, include_str!("../tests/data/example.abc"));
+    }
+}
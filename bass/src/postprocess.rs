@@ -0,0 +1,334 @@
+use clap::Args;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::Deserialize;
+
+use crate::{
+    error::ApiError,
+    format::OutputFormat,
+    manifest::Modality,
+    midi::{Instrument, Score},
+    registry::Model
+};
+
+
+const MAX_TRANSPOSE: i8 = 48;
+const MAX_HUMANIZE_MS: f32 = 100.0;
+const MAX_HUMANIZE_VELOCITY: u8 = 64;
+
+// Clean-up of the decoded notes before they are encoded, in the order the request lists it
+#[derive(Deserialize, Args, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PostProcessParams {
+    /// Operation on the decoded notes, e.g. "quantize:grid=4,strength=0.8" or "legato", applied
+    /// in the order given
+    #[arg(long = "post-process", value_parser = Operation::parse)]
+    pub post_process: Vec<Operation>,
+}
+
+#[derive(Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "op", rename_all = "snake_case", deny_unknown_fields)]
+pub enum Operation {
+    // moves notes toward a grid of `grid` subdivisions of a beat, all the way at strength 1.0
+    Quantize {
+        grid: u32,
+        #[serde(default = "full_strength")]
+        strength: f32,
+    },
+    // delays every second subdivision, `amount` is where it lands between two, 0.5 is straight
+    Swing {
+        #[serde(default = "eighths")]
+        grid: u32,
+        amount: f32,
+    },
+    // random timing and velocity offsets up to these amounts, seeded by the candidate's seed
+    // unless the operation has one
+    Humanize {
+        #[serde(default)]
+        timing_ms: f32,
+        #[serde(default)]
+        velocity: u8,
+        seed: Option<u64>,
+    },
+    Transpose {
+        semitones: i8,
+    },
+    // maps velocities onto [min, max] through a power curve, below 1.0 lifts soft notes
+    Velocity {
+        #[serde(default = "softest")]
+        min: u8,
+        #[serde(default = "loudest")]
+        max: u8,
+        #[serde(default = "linear")]
+        curve: f32,
+    },
+    // holds every note until the next onset of its instrument
+    Legato,
+    // shortens every note to `factor` of its length
+    Staccato {
+        factor: f32,
+    },
+    // moves notes outside [low, high] by octaves until they are in it
+    Fold {
+        low: u8,
+        high: u8,
+    },
+}
+
+fn full_strength() -> f32 {
+    1.0
+}
+
+fn eighths() -> u32 {
+    2
+}
+
+fn softest() -> u8 {
+    1
+}
+
+fn loudest() -> u8 {
+    127
+}
+
+fn linear() -> f32 {
+    1.0
+}
+
+impl Operation {
+    // "name" or "name:key=value,key=value", the fields as in a request
+    fn parse(text: &str) -> Result<Self, String> {
+        let (name, fields) = text.split_once(':').unwrap_or((text, ""));
+        let mut object = serde_json::Map::new();
+        object.insert(String::from("op"), serde_json::Value::from(name.trim()));
+        for field in fields.split(',').filter(|f| !f.trim().is_empty()) {
+            let Some((key, value)) = field.split_once('=') else {
+                return Err(format!("expected key=value, found {:?}", field));
+            };
+            let value = serde_json::from_str(value.trim()).unwrap_or_else(|_| serde_json::Value::from(value.trim()));
+            object.insert(key.trim().to_string(), value);
+        }
+        serde_json::from_value(serde_json::Value::Object(object)).map_err(|e| e.to_string())
+    }
+
+    fn check(&self) -> Result<(), String> {
+        match *self {
+            Self::Quantize { grid, strength } => {
+                if grid == 0 {
+                    return Err(String::from("quantize grid must be at least 1"));
+                }
+                if !(0.0..=1.0).contains(&strength) {
+                    return Err(String::from("quantize strength must be between 0 and 1"));
+                }
+            }
+            Self::Swing { grid, amount } => {
+                if grid == 0 {
+                    return Err(String::from("swing grid must be at least 1"));
+                }
+                if !(0.5..=0.75).contains(&amount) {
+                    return Err(String::from("swing amount must be between 0.5 and 0.75"));
+                }
+            }
+            Self::Humanize { timing_ms, velocity, .. } => {
+                if !(0.0..=MAX_HUMANIZE_MS).contains(&timing_ms) {
+                    return Err(format!("humanize timing_ms must be between 0 and {}", MAX_HUMANIZE_MS));
+                }
+                if velocity > MAX_HUMANIZE_VELOCITY {
+                    return Err(format!("humanize velocity must be at most {}", MAX_HUMANIZE_VELOCITY));
+                }
+            }
+            Self::Transpose { semitones } => {
+                if semitones.unsigned_abs() > MAX_TRANSPOSE as u8 {
+                    return Err(format!("transpose semitones must be between -{0} and {0}", MAX_TRANSPOSE));
+                }
+            }
+            Self::Velocity { min, max, curve } => {
+                if min == 0 || min > max || max > 127 {
+                    return Err(String::from("velocity must satisfy 1 <= min <= max <= 127"));
+                }
+                if !(0.1..=10.0).contains(&curve) {
+                    return Err(String::from("velocity curve must be between 0.1 and 10"));
+                }
+            }
+            Self::Legato => {}
+            Self::Staccato { factor } => {
+                if !(factor > 0.0 && factor <= 1.0) {
+                    return Err(String::from("staccato factor must be above 0 and at most 1"));
+                }
+            }
+            Self::Fold { low, high } => {
+                if high > 127 || low as u16 + 11 > high as u16 {
+                    return Err(String::from("fold needs low + 11 <= high <= 127, an octave at least"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn apply(&self, score: &mut Score, seed: u64) {
+        let ticks_per_beat = score.ticks_per_beat.max(1) as u32;
+        match *self {
+            Self::Quantize { grid, strength } => score.quantize((ticks_per_beat / grid).max(1), strength),
+            Self::Swing { grid, amount } => {
+                let step = (ticks_per_beat / grid).max(1);
+                let pair = 2 * step;
+                let split = (pair as f32 * amount).round() as u32;
+                // the first subdivision of each pair stretches to `split`, the second fills the rest
+                let warp = |tick: u32| {
+                    let (base, offset) = (tick - tick % pair, tick % pair);
+                    base + if offset < step {
+                        offset * split / step
+                    } else {
+                        split + (offset - step) * (pair - split) / step
+                    }
+                };
+                for note in &mut score.notes {
+                    let end = warp(note.start + note.duration);
+                    note.start = warp(note.start);
+                    note.duration = end.saturating_sub(note.start).max(1);
+                }
+            }
+            Self::Humanize { timing_ms, velocity, seed: own_seed } => {
+                let mut rng = StdRng::seed_from_u64(own_seed.unwrap_or(seed));
+                let tempos: Vec<f64> = score.notes.iter().map(|n| score.bpm_at(n.start)).collect();
+                for (note, bpm) in score.notes.iter_mut().zip(tempos) {
+                    if timing_ms > 0.0 {
+                        let ticks_per_ms = ticks_per_beat as f64 * bpm / 60_000.0;
+                        let offset = rng.random_range(-timing_ms..=timing_ms) as f64 * ticks_per_ms;
+                        note.start = (note.start as f64 + offset).round().max(0.0) as u32;
+                    }
+                    if velocity > 0 {
+                        let offset = rng.random_range(-(velocity as i16)..=velocity as i16);
+                        note.velocity = (note.velocity as i16 + offset).clamp(1, 127) as u8;
+                    }
+                }
+            }
+            Self::Transpose { semitones } => {
+                for note in score.notes.iter_mut().filter(|n| n.instrument != Instrument::Drums) {
+                    note.pitch = (note.pitch as i16 + semitones as i16).clamp(0, 127) as u8;
+                }
+            }
+            Self::Velocity { min, max, curve } => {
+                for note in &mut score.notes {
+                    let level = (note.velocity as f32 / 127.0).powf(curve);
+                    note.velocity = (min as f32 + (max - min) as f32 * level).round() as u8;
+                }
+            }
+            Self::Legato => {
+                score.sort();
+                for instrument in score.instruments() {
+                    let starts: Vec<u32> = score.notes.iter().filter(|n| n.instrument == instrument).map(|n| n.start).collect();
+                    for note in score.notes.iter_mut().filter(|n| n.instrument == instrument) {
+                        if let Some(&next) = starts.get(starts.partition_point(|&s| s <= note.start)) {
+                            note.duration = next - note.start;
+                        }
+                    }
+                }
+            }
+            Self::Staccato { factor } => {
+                for note in &mut score.notes {
+                    note.duration = ((note.duration as f32 * factor).round() as u32).max(1);
+                }
+            }
+            Self::Fold { low, high } => {
+                for note in score.notes.iter_mut().filter(|n| n.instrument != Instrument::Drums) {
+                    while note.pitch < low {
+                        note.pitch += 12;
+                    }
+                    while note.pitch > high {
+                        note.pitch -= 12;
+                    }
+                }
+            }
+        }
+        score.sort();
+    }
+}
+
+// The operations a request asked for, checked against the model and output format
+#[derive(Clone, Debug)]
+pub struct PostProcess {
+    operations: Vec<Operation>,
+}
+
+impl PostProcess {
+    // `None` when the request has no operations
+    pub fn new(params: PostProcessParams, model: &Model, format: OutputFormat) -> Result<Option<Self>, ApiError> {
+        if params.post_process.is_empty() {
+            return Ok(None);
+        }
+        if model.manifest.modality != Modality::Midi {
+            return Err(ApiError::InvalidRequest(format!(
+                "model {} does not generate MIDI, post_process needs a MIDI model", model.name
+            )));
+        }
+        if format == OutputFormat::Text {
+            return Err(ApiError::InvalidRequest(String::from("post_process works on notes, text output has none")));
+        }
+        for operation in &params.post_process {
+            operation.check().map_err(ApiError::InvalidRequest)?;
+        }
+        Ok(Some(Self { operations: params.post_process }))
+    }
+
+    // Runs the operations in order. `seed` seeds humanizing that has no seed of its own.
+    pub fn apply(&self, score: &mut Score, seed: u64) {
+        for operation in &self.operations {
+            operation.apply(score, seed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::Note;
+
+    fn score(notes: &[(u32, u32, u8)]) -> Score {
+        let mut score = Score::new(480);
+        score.notes = notes.iter()
+            .map(|&(start, duration, pitch)| Note { start, duration, pitch, velocity: 80, instrument: Instrument::Program(0) })
+            .collect();
+        score
+    }
+
+    #[test]
+    fn parses_the_command_line_form() {
+        assert_eq!(Operation::parse("quantize:grid=4,strength=0.5"), Ok(Operation::Quantize { grid: 4, strength: 0.5 }));
+        assert_eq!(Operation::parse("legato"), Ok(Operation::Legato));
+        assert!(Operation::parse("transpose:octaves=1").is_err());
+    }
+
+    #[test]
+    fn swing_delays_offbeats() {
+        let mut s = score(&[(0, 240, 60), (240, 240, 62), (480, 240, 64)]);
+        Operation::Swing { grid: 2, amount: 2.0 / 3.0 }.apply(&mut s, 0);
+        let starts: Vec<(u32, u32)> = s.notes.iter().map(|n| (n.start, n.duration)).collect();
+        assert_eq!(starts, vec![(0, 320), (320, 160), (480, 320)]);
+    }
+
+    #[test]
+    fn operations_run_in_order() {
+        let post_process = PostProcess {
+            operations: vec![
+                Operation::Quantize { grid: 4, strength: 1.0 },
+                Operation::Legato,
+                Operation::Transpose { semitones: 13 },
+                Operation::Fold { low: 60, high: 72 },
+            ],
+        };
+        let mut s = score(&[(10, 50, 58), (235, 100, 71)]);
+        post_process.apply(&mut s, 0);
+        let notes: Vec<(u32, u32, u8)> = s.notes.iter().map(|n| (n.start, n.duration, n.pitch)).collect();
+        assert_eq!(notes, vec![(0, 240, 71), (240, 120, 72)]);
+    }
+
+    #[test]
+    fn humanize_is_seeded() {
+        let operation = Operation::Humanize { timing_ms: 20.0, velocity: 10, seed: None };
+        let (mut a, mut b) = (score(&[(480, 240, 60)]), score(&[(480, 240, 60)]));
+        operation.apply(&mut a, 7);
+        operation.apply(&mut b, 7);
+        assert_eq!(a, b);
+        assert!(a.notes[0].start.abs_diff(480) <= 20);
+    }
+}
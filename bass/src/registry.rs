@@ -0,0 +1,361 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, RwLock},
+    time::{Duration, SystemTime}
+};
+
+use anyhow::Context;
+use ort::{session::Session, value::ValueType};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokenizers::Tokenizer;
+
+use crate::{
+    audio::AudioPipeline,
+    config::Config,
+    decoder::DecoderGraph,
+    manifest::{GeneratorKind, Manifest, Modality},
+    scheduler::Scheduler,
+    soundfont::SoundFont
+};
+
+
+// Name of the model loaded from `model_path` when no model store is configured
+const DEFAULT_MODEL: &str = "default";
+// File names looked up in every directory of the model store
+const STORE_MODEL_FILE: &str = "model.onnx";
+const STORE_TOKENIZER_FILE: &str = "tokenizer.json";
+
+// Files a model is loaded from
+#[derive(Clone, Debug)]
+struct ModelSource {
+    model_path: PathBuf,
+    tokenizer_path: PathBuf,
+}
+
+impl ModelSource {
+    // Latest modification time of any file the model depends on
+    fn modified(&self) -> Option<SystemTime> {
+        let manifest_path = self.model_path.with_extension("toml");
+        [&self.model_path, &self.tokenizer_path, &manifest_path]
+            .into_iter()
+            .filter_map(|p| fs::metadata(p).and_then(|m| m.modified()).ok())
+            .max()
+    }
+}
+
+// A token decoder and the scheduler batching its steps
+pub struct Decoder {
+    pub scheduler: Scheduler,
+    pub graph: Arc<DecoderGraph>,
+}
+
+impl Decoder {
+    fn new(name: &str, manifest: &Manifest, sessions: Vec<Session>, config: &Config) -> anyhow::Result<Self> {
+        let graph = Arc::new(DecoderGraph::new(manifest, &sessions[0])?);
+        if !graph.has_cache() {
+            tracing::info!("model {} exports no key/value cache, decoding will re-run the full sequence", name);
+        }
+        if !graph.batchable() && config.max_batch_size > 1 {
+            tracing::info!("model {} has no dynamic batch axis, steps will run one request at a time", name);
+        }
+        let scheduler = Scheduler::new(Arc::clone(&graph), sessions, config.max_batch_size);
+        Ok(Self { scheduler, graph })
+    }
+}
+
+// A loaded model. Requests hold on to the `Arc` they started with, so swapping a model in the
+// registry never pulls it out from under a running generation.
+pub struct Model {
+    pub name: String,
+    // sha256 of the ONNX file, lets clients tell two builds of a model apart
+    pub hash: String,
+    pub tokenizer: Tokenizer,
+    pub manifest: Manifest,
+    // every model has one except diffusion audio models
+    pub decoder: Option<Decoder>,
+    // only for audio models
+    pub audio: Option<AudioPipeline>,
+    // (name, type) of every graph input and output, for inspection
+    pub inputs: Vec<(String, ValueType)>,
+    pub outputs: Vec<(String, ValueType)>,
+    source: ModelSource,
+    modified: Option<SystemTime>,
+}
+
+impl Model {
+    fn load(name: &str, source: ModelSource, config: &Config) -> anyhow::Result<Self> {
+        let modified = source.modified();
+        let hash = file_hash(&source.model_path)?;
+        let sessions = load_sessions(&source.model_path, config)?;
+        let session = &sessions[0];
+
+        // Load the tokenizer used to encode prompts into a sequence of tokens
+        let tokenizer = Tokenizer::from_file(&source.tokenizer_path)
+            .map_err(|e| anyhow::anyhow!("could not load tokenizer {}: {}", source.tokenizer_path.display(), e))?;
+
+        let manifest = Manifest::load(&source.model_path, session, &tokenizer)?;
+        let inputs = session.inputs.iter().map(|i| (i.name.clone(), i.input_type.clone())).collect();
+        let outputs = session.outputs.iter().map(|o| (o.name.clone(), o.output_type.clone())).collect();
+
+        // the model file of a diffusion model is its denoiser rather than a token decoder
+        let (decoder, denoiser) = match manifest.audio.as_ref().map(|a| a.generator) {
+            Some(GeneratorKind::Diffusion) => (None, sessions),
+            _ => (Some(Decoder::new(name, &manifest, sessions, config)?), Vec::new()),
+        };
+        let audio = match &manifest.audio {
+            Some(spec) => Some(AudioPipeline::load(&source.model_path, spec, denoiser, config)?),
+            None => None,
+        };
+
+        Ok(Self {
+            name: name.to_string(),
+            hash,
+            tokenizer,
+            manifest,
+            decoder,
+            audio,
+            inputs,
+            outputs,
+            source,
+            modified,
+        })
+    }
+}
+
+#[derive(Serialize)]
+pub struct ModelInfo {
+    name: String,
+    hash: String,
+    modality: Modality,
+    default: bool,
+    path: PathBuf,
+}
+
+pub struct ModelRegistry {
+    config: Config,
+    default: String,
+    models: RwLock<HashMap<String, Arc<Model>>>,
+    // modification time of the files each model last failed to load from, so a broken model is
+    // only tried again once its files change
+    failed: Mutex<HashMap<String, Option<SystemTime>>>,
+    // shared by every model's previews, loaded once at startup
+    soundfont: Option<Arc<SoundFont>>,
+}
+
+impl ModelRegistry {
+    // Loads every model in the store, or the single model from `model_path` when there is no store
+    pub fn load(config: &Config) -> anyhow::Result<Self> {
+        let sources = discover(config)?;
+        if sources.is_empty() {
+            anyhow::bail!("no models found in {}", config.model_store.as_deref().unwrap_or(Path::new("")).display());
+        }
+
+        // a broken model is left out like `refresh` leaves it out, unless it is the one asked for
+        let mut models = HashMap::new();
+        let mut failed = HashMap::new();
+        for (name, source) in sources {
+            tracing::info!("loading model {} from {}", name, source.model_path.display());
+            let modified = source.modified();
+            match Model::load(&name, source, config) {
+                Ok(model) => {
+                    models.insert(name, Arc::new(model));
+                }
+                Err(e) if config.default_model.as_ref() == Some(&name) => {
+                    return Err(e.context(format!("could not load default model {}", name)));
+                }
+                Err(e) => {
+                    tracing::error!("could not load model {}, skipping it until its files change: {:#}", name, e);
+                    failed.insert(name, modified);
+                }
+            }
+        }
+        if models.is_empty() {
+            anyhow::bail!("no model could be loaded, {} failed", failed.len());
+        }
+
+        let default = match &config.default_model {
+            Some(name) if models.contains_key(name) => name.clone(),
+            Some(name) => anyhow::bail!("default_model {} is not in the model store", name),
+            None if models.contains_key(DEFAULT_MODEL) => DEFAULT_MODEL.to_string(),
+            None => {
+                let mut names: Vec<&String> = models.keys().collect();
+                names.sort();
+                names[0].clone()
+            }
+        };
+
+        let soundfont = match &config.soundfont {
+            Some(path) => {
+                tracing::info!("loading soundfont {}", path.display());
+                Some(Arc::new(SoundFont::load(path)?))
+            }
+            None => None,
+        };
+
+        Ok(Self {
+            config: config.clone(),
+            default,
+            models: RwLock::new(models),
+            failed: Mutex::new(failed),
+            soundfont,
+        })
+    }
+
+    // Looks up a model by name, `None` selects the default model
+    pub fn get(&self, name: Option<&str>) -> Option<Arc<Model>> {
+        let name = name.unwrap_or(&self.default);
+        self.models.read().unwrap().get(name).cloned()
+    }
+
+    pub fn default_name(&self) -> &str {
+        &self.default
+    }
+
+    pub fn soundfont(&self) -> Option<Arc<SoundFont>> {
+        self.soundfont.clone()
+    }
+
+    pub fn list(&self) -> Vec<ModelInfo> {
+        let mut models: Vec<ModelInfo> = self.models.read().unwrap()
+            .values()
+            .map(|m| ModelInfo {
+                name: m.name.clone(),
+                hash: m.hash.clone(),
+                modality: m.manifest.modality,
+                default: m.name == self.default,
+                path: m.source.model_path.clone(),
+            })
+            .collect();
+        models.sort_by(|a, b| a.name.cmp(&b.name));
+        models
+    }
+
+    // Loads a model from disk again and swaps it in once it loaded successfully
+    pub fn reload(&self, name: &str) -> anyhow::Result<()> {
+        let Some(source) = self.get(Some(name)).map(|m| m.source.clone()) else {
+            anyhow::bail!("unknown model {}", name);
+        };
+        let model = Model::load(name, source, &self.config)?;
+        self.models.write().unwrap().insert(name.to_string(), Arc::new(model));
+        tracing::info!("reloaded model {}", name);
+        Ok(())
+    }
+
+    // Reloads models whose files changed and picks up models added to or removed from the store
+    pub fn refresh(&self) {
+        let sources = match discover(&self.config) {
+            Ok(sources) => sources,
+            Err(e) => {
+                tracing::error!("could not scan model store: {:#}", e);
+                return;
+            }
+        };
+
+        let current: HashMap<String, Option<SystemTime>> = self.models.read().unwrap()
+            .iter()
+            .map(|(name, m)| (name.clone(), m.modified))
+            .collect();
+
+        let mut failed = self.failed.lock().unwrap();
+        failed.retain(|name, _| sources.iter().any(|(n, _)| n == name));
+        for (name, source) in &sources {
+            let modified = source.modified();
+            let changed = match current.get(name) {
+                Some(loaded) => modified != *loaded,
+                None => true,
+            };
+            if !changed || failed.get(name) == Some(&modified) {
+                continue;
+            }
+            match Model::load(name, source.clone(), &self.config) {
+                Ok(model) => {
+                    tracing::info!("loaded model {} after a file change", name);
+                    failed.remove(name);
+                    self.models.write().unwrap().insert(name.clone(), Arc::new(model));
+                }
+                Err(e) => {
+                    tracing::error!("could not reload model {}, keeping the previous one until its files change: {:#}", name, e);
+                    failed.insert(name.clone(), modified);
+                }
+            }
+        }
+
+        let mut models = self.models.write().unwrap();
+        models.retain(|name, _| {
+            let keep = name == &self.default || sources.iter().any(|(n, _)| n == name);
+            if !keep {
+                tracing::info!("model {} was removed from the store", name);
+            }
+            keep
+        });
+    }
+
+    // Polls the model files and refreshes the registry, runs until the server shuts down
+    pub async fn watch(self: Arc<Self>, interval: Duration) {
+        let mut ticker = tokio::time::interval(interval);
+        ticker.tick().await;
+        loop {
+            ticker.tick().await;
+            let registry = Arc::clone(&self);
+            if let Err(e) = tokio::task::spawn_blocking(move || registry.refresh()).await {
+                tracing::error!("model refresh panicked: {}", e);
+            }
+        }
+    }
+}
+
+// `sessions_per_model` sessions of the graph at `path`
+pub fn load_sessions(path: &Path, config: &Config) -> anyhow::Result<Vec<Session>> {
+    (0..config.sessions_per_model)
+        .map(|_| {
+            Session::builder()?
+            .with_optimization_level(config.optimization_level.into())?
+            .with_intra_threads(config.intra_threads)?
+            .with_inter_threads(config.inter_threads)?
+            .commit_from_file(path)
+            .with_context(|| format!("could not load model {}", path.display()))
+        })
+        .collect()
+}
+
+fn file_hash(path: &Path) -> anyhow::Result<String> {
+    let mut file = File::open(path).with_context(|| format!("could not open {}", path.display()))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+// Every `<store>/<name>/model.onnx`, or `model_path` alone when no store is configured
+fn discover(config: &Config) -> anyhow::Result<Vec<(String, ModelSource)>> {
+    let Some(store) = &config.model_store else {
+        return Ok(vec![(DEFAULT_MODEL.to_string(), ModelSource {
+            model_path: config.model_path.clone(),
+            tokenizer_path: config.tokenizer_path.clone(),
+        })]);
+    };
+
+    let mut sources = Vec::new();
+    let entries = fs::read_dir(store)
+        .with_context(|| format!("could not read model store {}", store.display()))?;
+    for entry in entries {
+        let dir = entry?.path();
+        let model_path = dir.join(STORE_MODEL_FILE);
+        if !model_path.is_file() {
+            continue;
+        }
+        // models without their own tokenizer share the configured one
+        let tokenizer_path = Some(dir.join(STORE_TOKENIZER_FILE))
+            .filter(|p| p.is_file())
+            .unwrap_or_else(|| config.tokenizer_path.clone());
+        let Some(name) = dir.file_name().and_then(|n| n.to_str()) else {
+            continue;
+        };
+        sources.push((name.to_string(), ModelSource { model_path, tokenizer_path }));
+    }
+    sources.sort_by(|a, b| a.0.cmp(&b.0));
+    Ok(sources)
+}
@@ -0,0 +1,331 @@
+use std::collections::HashMap;
+
+use clap::Args;
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::Deserialize;
+
+
+// Sample from the k most likely next tokens at each step
+const TOP_K: usize = 20;
+
+#[derive(Deserialize, Args, Clone, Debug)]
+#[serde(default)]
+pub struct SamplingParams {
+    /// Softmax temperature, 0.0 means greedy decoding
+    #[arg(long, default_value_t = 1.0)]
+    pub temperature: f32,
+    /// Sample among the k most likely tokens, 0 disables top-k filtering
+    #[arg(long, default_value_t = TOP_K)]
+    pub top_k: usize,
+    /// Nucleus probability mass, 1.0 disables top-p filtering
+    #[arg(long, default_value_t = 1.0)]
+    pub top_p: f32,
+    /// Minimum probability relative to the most likely token, 0.0 disables min-p filtering
+    #[arg(long, default_value_t = 0.0)]
+    pub min_p: f32,
+    /// Divides the logits of tokens already generated, 1.0 disables the penalty
+    #[arg(long, default_value_t = 1.0)]
+    pub repetition_penalty: f32,
+    /// Subtracted from a token's logit once per previous occurrence, 0.0 disables the penalty
+    #[arg(long, default_value_t = 0.0)]
+    pub frequency_penalty: f32,
+    /// Fixed seed to reproduce a take, a random one is picked when missing
+    #[arg(long)]
+    pub seed: Option<u64>,
+}
+
+impl Default for SamplingParams {
+    fn default() -> Self {
+        Self {
+            temperature: 1.0,
+            top_k: TOP_K,
+            top_p: 1.0,
+            min_p: 0.0,
+            repetition_penalty: 1.0,
+            frequency_penalty: 0.0,
+            seed: None,
+        }
+    }
+}
+
+// A (token id, logit) pair still in the running to be sampled
+pub type Candidate = (usize, f32);
+
+// One step of the sampling chain. Candidates arrive sorted by descending logit
+// and must stay sorted.
+pub trait Strategy: Send {
+    fn apply(&self, candidates: &mut Vec<Candidate>);
+}
+
+pub struct Temperature(pub f32);
+pub struct TopK(pub usize);
+pub struct TopP(pub f32);
+pub struct MinP(pub f32);
+
+impl Strategy for Temperature {
+    fn apply(&self, candidates: &mut Vec<Candidate>) {
+        for (_, logit) in candidates.iter_mut() {
+            *logit /= self.0;
+        }
+    }
+}
+
+impl Strategy for TopK {
+    fn apply(&self, candidates: &mut Vec<Candidate>) {
+        candidates.truncate(self.0.max(1));
+    }
+}
+
+impl Strategy for TopP {
+    fn apply(&self, candidates: &mut Vec<Candidate>) {
+        let probs = softmax(candidates);
+        let mut cumulative = 0.0;
+        let mut keep = candidates.len();
+        for (i, p) in probs.iter().enumerate() {
+            cumulative += p;
+            if cumulative >= self.0 {
+                keep = i + 1;
+                break;
+            }
+        }
+        candidates.truncate(keep);
+    }
+}
+
+impl Strategy for MinP {
+    fn apply(&self, candidates: &mut Vec<Candidate>) {
+        let probs = softmax(candidates);
+        let threshold = probs.first().copied().unwrap_or(0.0) * self.0;
+        let keep = probs.iter().take_while(|&&p| p >= threshold).count();
+        candidates.truncate(keep.max(1));
+    }
+}
+
+pub struct Sampler {
+    params: SamplingParams,
+    strategies: Vec<Box<dyn Strategy>>,
+    rng: StdRng,
+    seed: u64,
+}
+
+impl Sampler {
+    pub fn new(params: SamplingParams) -> Self {
+        let seed = params.seed.unwrap_or_else(rand::random);
+
+        let mut strategies: Vec<Box<dyn Strategy>> = Vec::new();
+        if params.temperature > 0.0 {
+            strategies.push(Box::new(Temperature(params.temperature)));
+            if params.top_k > 0 {
+                strategies.push(Box::new(TopK(params.top_k)));
+            }
+            if params.top_p < 1.0 {
+                strategies.push(Box::new(TopP(params.top_p)));
+            }
+            if params.min_p > 0.0 {
+                strategies.push(Box::new(MinP(params.min_p)));
+            }
+        } else {
+            // greedy, keep only the most likely token
+            strategies.push(Box::new(TopK(1)));
+        }
+
+        Self {
+            params,
+            strategies,
+            rng: StdRng::seed_from_u64(seed),
+            seed,
+        }
+    }
+
+    // One sampler per variation of a request, seeded one after the other from the requested seed
+    pub fn variations(params: SamplingParams, count: usize) -> Vec<Self> {
+        let seed = params.seed.unwrap_or_else(rand::random);
+        (0..count as u64)
+            .map(|i| Self::new(SamplingParams { seed: Some(seed.wrapping_add(i)), ..params.clone() }))
+            .collect()
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    // Picks the next token from the logits of the last position, given the tokens generated so far.
+    // Returns the token with its log-probability under the given logits, before penalties and
+    // filtering, or `None` when every logit is masked and no token is left to pick.
+    pub fn sample(&mut self, logits: &[f32], history: &[i64]) -> Option<(i64, f32)> {
+        let token = self.pick(logits, history)?;
+        Some((token, log_softmax_at(logits, token as usize)))
+    }
+
+    fn pick(&mut self, logits: &[f32], history: &[i64]) -> Option<i64> {
+        let mut logits = logits.to_vec();
+        self.apply_penalties(&mut logits, history);
+
+        // tokens masked by constraints are out of the running
+        let mut candidates: Vec<Candidate> = logits.into_iter()
+            .enumerate()
+            .filter(|c| c.1 > f32::NEG_INFINITY)
+            .collect();
+        candidates.sort_unstable_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Less));
+        if candidates.is_empty() {
+            return None;
+        }
+        for strategy in &self.strategies {
+            strategy.apply(&mut candidates);
+        }
+
+        let probs = softmax(&candidates);
+        let mut target: f32 = self.rng.random();
+        for (candidate, p) in candidates.iter().zip(probs) {
+            target -= p;
+            if target <= 0.0 {
+                return Some(candidate.0 as i64);
+            }
+        }
+        // rounding left some probability mass over, fall back to the least likely survivor
+        candidates.last().map(|c| c.0 as i64)
+    }
+
+    fn apply_penalties(&self, logits: &mut [f32], history: &[i64]) {
+        let (repetition, frequency) = (self.params.repetition_penalty, self.params.frequency_penalty);
+        if repetition == 1.0 && frequency == 0.0 {
+            return;
+        }
+
+        let mut counts: HashMap<usize, usize> = HashMap::new();
+        for &token in history {
+            *counts.entry(token as usize).or_default() += 1;
+        }
+
+        for (token, count) in counts {
+            let Some(logit) = logits.get_mut(token) else { continue };
+            // CTRL-style repetition penalty, pushes the logit towards "less likely" regardless of sign
+            if *logit > 0.0 {
+                *logit /= repetition;
+            } else {
+                *logit *= repetition;
+            }
+            *logit -= frequency * count as f32;
+        }
+    }
+}
+
+fn softmax(candidates: &[Candidate]) -> Vec<f32> {
+    let max = candidates.first().map(|c| c.1).unwrap_or(0.0);
+    let exps: Vec<f32> = candidates.iter().map(|c| (c.1 - max).exp()).collect();
+    let sum: f32 = exps.iter().sum();
+    exps.into_iter().map(|e| e / sum).collect()
+}
+
+fn log_softmax_at(logits: &[f32], index: usize) -> f32 {
+    let max = logits.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+    let log_sum = logits.iter().map(|l| (l - max).exp()).sum::<f32>().ln() + max;
+    logits.get(index).map(|l| l - log_sum).unwrap_or(f32::NEG_INFINITY)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LOGITS: [f32; 5] = [2.0, 1.0, 0.5, -1.0, 3.0];
+
+    fn sampler(params: SamplingParams) -> Sampler {
+        Sampler::new(SamplingParams { seed: Some(7), ..params })
+    }
+
+    // Sorted like the sampling chain hands them over
+    fn candidates() -> Vec<Candidate> {
+        let mut candidates: Vec<Candidate> = LOGITS.into_iter().enumerate().collect();
+        candidates.sort_unstable_by(|a, b| b.1.total_cmp(&a.1));
+        candidates
+    }
+
+    fn kept(strategy: impl Strategy) -> Vec<usize> {
+        let mut candidates = candidates();
+        strategy.apply(&mut candidates);
+        candidates.into_iter().map(|c| c.0).collect()
+    }
+
+    #[test]
+    fn same_seed_samples_same_tokens() {
+        let take = |seed| {
+            let mut sampler = Sampler::new(SamplingParams { seed: Some(seed), ..SamplingParams::default() });
+            (0..64).map(|_| sampler.sample(&LOGITS, &[]).unwrap().0).collect::<Vec<_>>()
+        };
+        assert_eq!(take(7), take(7));
+        assert_ne!(take(7), take(8));
+        let seeds: Vec<u64> = Sampler::variations(SamplingParams { seed: Some(7), ..SamplingParams::default() }, 3)
+            .iter()
+            .map(Sampler::seed)
+            .collect();
+        assert_eq!(seeds, vec![7, 8, 9]);
+    }
+
+    #[test]
+    fn zero_temperature_is_greedy() {
+        let mut sampler = sampler(SamplingParams { temperature: 0.0, ..SamplingParams::default() });
+        let (token, logprob) = sampler.sample(&LOGITS, &[]).unwrap();
+        assert_eq!(token, 4);
+        assert!((logprob - log_softmax_at(&LOGITS, 4)).abs() < 1e-6);
+    }
+
+    #[test]
+    fn temperature_scales_logits() {
+        let mut candidates = candidates();
+        Temperature(0.5).apply(&mut candidates);
+        assert_eq!(candidates[0], (4, 6.0));
+        assert_eq!(candidates[4], (3, -2.0));
+    }
+
+    #[test]
+    fn top_k_keeps_the_k_most_likely() {
+        assert_eq!(kept(TopK(2)), vec![4, 0]);
+        // 0 would leave nothing to sample
+        assert_eq!(kept(TopK(0)), vec![4]);
+    }
+
+    #[test]
+    fn top_p_keeps_the_smallest_set_over_the_mass() {
+        // softmax of [3, 2, 1, 0.5, -1] is about [0.63, 0.23, 0.09, 0.05, 0.01]
+        assert_eq!(kept(TopP(0.5)), vec![4]);
+        assert_eq!(kept(TopP(0.8)), vec![4, 0]);
+        assert_eq!(kept(TopP(0.9)), vec![4, 0, 1]);
+        assert_eq!(kept(TopP(1.0)).len(), 5);
+    }
+
+    #[test]
+    fn min_p_drops_tokens_below_a_fraction_of_the_best() {
+        // e^-1 and e^-2 of the best token's probability
+        assert_eq!(kept(MinP(0.3)), vec![4, 0]);
+        assert_eq!(kept(MinP(0.1)), vec![4, 0, 1]);
+        assert_eq!(kept(MinP(1.0)), vec![4]);
+    }
+
+    #[test]
+    fn penalties_push_repeated_tokens_down() {
+        let sampler = sampler(SamplingParams { repetition_penalty: 2.0, frequency_penalty: 0.5, ..SamplingParams::default() });
+        let mut logits = LOGITS;
+        sampler.apply_penalties(&mut logits, &[0, 0, 3, 99]);
+        // 2 / 2 - 2 * 0.5, -1 * 2 - 0.5, tokens outside the vocabulary are ignored
+        assert_eq!(logits, [0.0, 1.0, 0.5, -2.5, 3.0]);
+    }
+
+    #[test]
+    fn masked_tokens_are_never_sampled() {
+        let mut logits = LOGITS;
+        logits[4] = f32::NEG_INFINITY;
+        logits[0] = f32::NEG_INFINITY;
+        let mut sampler = sampler(SamplingParams::default());
+        for _ in 0..64 {
+            let (token, _) = sampler.sample(&logits, &[]).unwrap();
+            assert!([1, 2, 3].contains(&token));
+        }
+    }
+
+    #[test]
+    fn nothing_is_sampled_when_every_token_is_masked() {
+        let mut sampler = sampler(SamplingParams::default());
+        assert_eq!(sampler.sample(&[f32::NEG_INFINITY; 5], &[]), None);
+        assert_eq!(sampler.sample(&[], &[]), None);
+    }
+}
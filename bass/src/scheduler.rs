@@ -0,0 +1,129 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use ort::session::Session;
+use tokio::sync::{mpsc, oneshot};
+
+use crate::decoder::{DecodeState, DecoderGraph};
+
+
+type StepResult = ort::Result<(DecodeState, Vec<f32>)>;
+
+// One decoding step a request is waiting on
+struct StepJob {
+    state: DecodeState,
+    reply: oneshot::Sender<StepResult>,
+}
+
+// Runs decoding steps for every request on a model. Steps that are queued at the same time and
+// share a batch key are merged into one batched run, padded to the longest row when the graph
+// allows it, and each batch goes to the next idle session of the pool. A request only ever has one step queued, so taking batches from the
+// front of the queue interleaves requests fairly.
+pub struct Scheduler {
+    jobs: mpsc::UnboundedSender<StepJob>,
+}
+
+impl Scheduler {
+    pub fn new(graph: Arc<DecoderGraph>, sessions: Vec<Session>, max_batch_size: usize) -> Self {
+        let max_batch_size = if graph.batchable() { max_batch_size.max(1) } else { 1 };
+        let (jobs, rx) = mpsc::unbounded_channel();
+        tokio::spawn(run(graph, sessions, max_batch_size, rx));
+        Self { jobs }
+    }
+
+    // Feeds the state's unseen tokens through the model and returns the state with the logits
+    // of its last position
+    pub async fn step(&self, state: DecodeState) -> StepResult {
+        let (reply, rx) = oneshot::channel();
+        if self.jobs.send(StepJob { state, reply }).is_err() {
+            return Err(ort::Error::new("scheduler stopped"));
+        }
+        rx.await.unwrap_or_else(|_| Err(ort::Error::new("scheduler dropped the step")))
+    }
+}
+
+async fn run(
+    graph: Arc<DecoderGraph>,
+    sessions: Vec<Session>,
+    max_batch_size: usize,
+    mut jobs: mpsc::UnboundedReceiver<StepJob>
+) {
+    let (idle_tx, mut idle) = mpsc::unbounded_channel();
+    for session in sessions {
+        let _ = idle_tx.send(session);
+    }
+
+    let mut queue: VecDeque<StepJob> = VecDeque::new();
+    // set once every sender is gone, the queue still drains but `recv` would return `None` forever
+    let mut closed = false;
+    loop {
+        if queue.is_empty() {
+            // the model was dropped once every sender is gone
+            if closed {
+                break;
+            }
+            let Some(job) = jobs.recv().await else { break };
+            queue.push_back(job);
+        }
+
+        tokio::select! {
+            job = jobs.recv(), if !closed => match job {
+                Some(job) => queue.push_back(job),
+                None => closed = true,
+            },
+            Some(mut session) = idle.recv() => {
+                // pick up everything that arrived while waiting for the session
+                while let Ok(job) = jobs.try_recv() {
+                    queue.push_back(job);
+                }
+                let batch = take_batch(&mut queue, &graph, max_batch_size);
+                let graph = Arc::clone(&graph);
+                let idle_tx = idle_tx.clone();
+                tokio::spawn(async move {
+                    run_batch(&graph, &mut session, batch).await;
+                    let _ = idle_tx.send(session);
+                });
+            }
+        }
+    }
+}
+
+// Takes the oldest job and every other queued job with the same batch key, up to the batch size.
+// Jobs whose request went away, e.g. because its client disconnected, are dropped unrun.
+fn take_batch(queue: &mut VecDeque<StepJob>, graph: &DecoderGraph, max_batch_size: usize) -> Vec<StepJob> {
+    queue.retain(|job| !job.reply.is_closed());
+    let Some(first) = queue.pop_front() else { return Vec::new() };
+    let key = first.state.batch_key(graph);
+    let mut batch = vec![first];
+
+    let mut i = 0;
+    while i < queue.len() && batch.len() < max_batch_size {
+        if queue[i].state.batch_key(graph) == key {
+            batch.extend(queue.remove(i));
+        } else {
+            i += 1;
+        }
+    }
+    batch
+}
+
+async fn run_batch(graph: &DecoderGraph, session: &mut Session, batch: Vec<StepJob>) {
+    if batch.is_empty() {
+        return;
+    }
+    let (mut states, replies): (Vec<DecodeState>, Vec<_>) = batch.into_iter().map(|j| (j.state, j.reply)).unzip();
+
+    match graph.step(session, &mut states).await {
+        Ok(logits) => {
+            for ((state, logits), reply) in states.into_iter().zip(logits).zip(replies) {
+                let _ = reply.send(Ok((state, logits)));
+            }
+        }
+        Err(e) => {
+            tracing::error!("batch of {} failed: {}", replies.len(), e);
+            let message = e.to_string();
+            for reply in replies {
+                let _ = reply.send(Err(ort::Error::new(message.clone())));
+            }
+        }
+    }
+}
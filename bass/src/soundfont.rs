@@ -0,0 +1,291 @@
+use std::{fs, ops::RangeInclusive, path::Path};
+
+use anyhow::{Context, bail};
+
+
+// Generator operators of the SoundFont 2.04 spec that the synth understands
+const START_OFFSET: u16 = 0;
+const END_OFFSET: u16 = 1;
+const LOOP_START_OFFSET: u16 = 2;
+const LOOP_END_OFFSET: u16 = 3;
+const START_COARSE_OFFSET: u16 = 4;
+const END_COARSE_OFFSET: u16 = 12;
+const ATTACK: u16 = 34;
+const DECAY: u16 = 36;
+const SUSTAIN: u16 = 37;
+const RELEASE: u16 = 38;
+const INSTRUMENT: u16 = 41;
+const KEY_RANGE: u16 = 43;
+const VELOCITY_RANGE: u16 = 44;
+const LOOP_START_COARSE_OFFSET: u16 = 45;
+const ATTENUATION: u16 = 48;
+const LOOP_END_COARSE_OFFSET: u16 = 50;
+const COARSE_TUNE: u16 = 51;
+const FINE_TUNE: u16 = 52;
+const SAMPLE_ID: u16 = 53;
+const SAMPLE_MODES: u16 = 54;
+const ROOT_KEY: u16 = 58;
+
+// Envelope times are timecents, -12000 is the spec's default of about a millisecond
+const DEFAULT_TIMECENTS: i16 = -12000;
+// Bank General MIDI drum kits live in
+pub const DRUM_BANK: u16 = 128;
+
+// A sample zone ready to play, flattened from a preset zone and the instrument zone under it
+#[derive(Clone, Debug)]
+pub struct Region {
+    pub keys: RangeInclusive<u8>,
+    pub velocities: RangeInclusive<u8>,
+    // sample positions in `SoundFont::samples`, the end is exclusive
+    pub start: usize,
+    pub end: usize,
+    pub loop_start: usize,
+    pub loop_end: usize,
+    pub looped: bool,
+    pub sample_rate: u32,
+    pub root_key: u8,
+    // detune from the root key, including the sample's own pitch correction
+    pub tune_cents: f32,
+    pub gain: f32,
+    // seconds, and the sustain level as a fraction of full volume
+    pub attack: f32,
+    pub decay: f32,
+    pub sustain: f32,
+    pub release: f32,
+}
+
+#[derive(Clone, Debug)]
+struct Preset {
+    bank: u16,
+    program: u16,
+    regions: Vec<Region>,
+}
+
+// An SF2 file's presets and its 16-bit sample pool
+pub struct SoundFont {
+    pub samples: Vec<i16>,
+    presets: Vec<Preset>,
+}
+
+// A zone's generators, `None` where the zone leaves the default
+#[derive(Clone, Copy)]
+struct Generators([Option<[u8; 2]>; 64]);
+
+impl Generators {
+    fn new() -> Self {
+        Self([None; 64])
+    }
+
+    fn set(&mut self, operator: u16, amount: [u8; 2]) {
+        if let Some(slot) = self.0.get_mut(operator as usize) {
+            *slot = Some(amount);
+        }
+    }
+
+    // local values override the global zone's
+    fn over(mut self, global: &Generators) -> Self {
+        for (slot, default) in self.0.iter_mut().zip(global.0) {
+            *slot = slot.or(default);
+        }
+        self
+    }
+
+    fn get(&self, operator: u16) -> Option<i16> {
+        self.0[operator as usize].map(i16::from_le_bytes)
+    }
+
+    fn range(&self, operator: u16) -> RangeInclusive<u8> {
+        self.0[operator as usize].map_or(0..=127, |[lo, hi]| lo..=hi)
+    }
+}
+
+impl SoundFont {
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        let bytes = fs::read(path).with_context(|| format!("could not read soundfont {}", path.display()))?;
+        Self::parse(&bytes).with_context(|| format!("invalid soundfont {}", path.display()))
+    }
+
+    pub fn parse(bytes: &[u8]) -> anyhow::Result<Self> {
+        let Some((b"RIFF", body)) = chunks(bytes).next() else {
+            bail!("not a RIFF file");
+        };
+        if body.get(..4) != Some(&b"sfbk"[..]) {
+            bail!("not a SoundFont 2 file");
+        }
+        let (mut smpl, mut pdta) = (None, None);
+        for (id, data) in chunks(&body[4..]) {
+            match (id, data.get(..4)) {
+                (b"LIST", Some(b"sdta")) => smpl = chunks(&data[4..]).find(|(id, _)| *id == b"smpl").map(|(_, d)| d),
+                (b"LIST", Some(b"pdta")) => pdta = Some(&data[4..]),
+                _ => {}
+            }
+        }
+        let (Some(smpl), Some(pdta)) = (smpl, pdta) else {
+            bail!("missing sample data or preset data");
+        };
+        let samples: Vec<i16> = smpl.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+
+        let mut tables = std::collections::HashMap::new();
+        for (id, data) in chunks(pdta) {
+            tables.insert(id, data);
+        }
+        let table = |id: &[u8; 4], size: usize| -> anyhow::Result<Vec<&[u8]>> {
+            let data = tables.get(id).with_context(|| format!("missing {} chunk", String::from_utf8_lossy(id)))?;
+            // every table ends with a terminal record
+            if data.len() < size || data.len() % size != 0 {
+                bail!("malformed {} chunk", String::from_utf8_lossy(id));
+            }
+            Ok(data.chunks_exact(size).collect())
+        };
+        let phdr = table(b"phdr", 38)?;
+        let pbag = table(b"pbag", 4)?;
+        let pgen = table(b"pgen", 4)?;
+        let inst = table(b"inst", 22)?;
+        let ibag = table(b"ibag", 4)?;
+        let igen = table(b"igen", 4)?;
+        let shdr = table(b"shdr", 46)?;
+
+        let zones = |bags: &[&[u8]], gens: &[&[u8]], first: usize, last: usize| -> anyhow::Result<Vec<Generators>> {
+            let mut zones = Vec::new();
+            for bag in first..last {
+                let from = u16_at(bags.get(bag).context("bag index out of range")?, 0) as usize;
+                let to = u16_at(bags.get(bag + 1).context("bag index out of range")?, 0) as usize;
+                let mut generators = Generators::new();
+                for generator in gens.get(from..to).context("generator index out of range")? {
+                    generators.set(u16_at(generator, 0), [generator[2], generator[3]]);
+                }
+                zones.push(generators);
+            }
+            Ok(zones)
+        };
+
+        let mut instruments = Vec::new();
+        for pair in inst.windows(2) {
+            let mut zones = zones(&ibag, &igen, u16_at(pair[0], 20) as usize, u16_at(pair[1], 20) as usize)?;
+            // a first zone without a sample holds the instrument's defaults
+            let global = match zones.first() {
+                Some(zone) if zone.get(SAMPLE_ID).is_none() => zones.remove(0),
+                _ => Generators::new(),
+            };
+            instruments.push(zones.into_iter()
+                .filter(|z| z.get(SAMPLE_ID).is_some())
+                .map(|z| z.over(&global))
+                .collect::<Vec<_>>());
+        }
+
+        let mut presets = Vec::new();
+        for pair in phdr.windows(2) {
+            let mut zones = zones(&pbag, &pgen, u16_at(pair[0], 24) as usize, u16_at(pair[1], 24) as usize)?;
+            let global = match zones.first() {
+                Some(zone) if zone.get(INSTRUMENT).is_none() => zones.remove(0),
+                _ => Generators::new(),
+            };
+            let mut regions = Vec::new();
+            for zone in zones.into_iter().map(|z| z.over(&global)) {
+                let Some(instrument) = zone.get(INSTRUMENT).and_then(|i| instruments.get(i as u16 as usize)) else {
+                    continue;
+                };
+                for local in instrument {
+                    let Some(header) = local.get(SAMPLE_ID).and_then(|s| shdr.get(s as u16 as usize)) else {
+                        continue;
+                    };
+                    if let Some(region) = region(&zone, local, header, samples.len()) {
+                        regions.push(region);
+                    }
+                }
+            }
+            presets.push(Preset { bank: u16_at(pair[0], 22), program: u16_at(pair[0], 20), regions });
+        }
+        Ok(Self { samples, presets })
+    }
+
+    // The regions that sound for a key at a velocity. Drums come from bank 128, programs missing
+    // from the file fall back to its first preset of the same kind.
+    pub fn regions(&self, bank: u16, program: u16, key: u8, velocity: u8) -> impl Iterator<Item = &Region> {
+        let preset = self.presets.iter().find(|p| p.bank == bank && p.program == program)
+            .or_else(|| self.presets.iter().find(|p| (p.bank == DRUM_BANK) == (bank == DRUM_BANK)))
+            .or(self.presets.first());
+        preset.into_iter()
+            .flat_map(|p| &p.regions)
+            .filter(move |r| r.keys.contains(&key) && r.velocities.contains(&velocity))
+    }
+}
+
+// Combines a preset zone with one of its instrument's zones and the sample it plays. Preset
+// level tuning and attenuation add to the instrument's, key and velocity ranges intersect.
+fn region(preset: &Generators, instrument: &Generators, header: &[u8], pool: usize) -> Option<Region> {
+    let keys = intersect(preset.range(KEY_RANGE), instrument.range(KEY_RANGE))?;
+    let velocities = intersect(preset.range(VELOCITY_RANGE), instrument.range(VELOCITY_RANGE))?;
+    let offset = |fine: u16, coarse: u16| {
+        instrument.get(fine).unwrap_or(0) as i64 + instrument.get(coarse).unwrap_or(0) as i64 * 32768
+    };
+    let at = |base: u32, offset: i64| (base as i64 + offset).clamp(0, pool as i64) as usize;
+    let start = at(u32_at(header, 20), offset(START_OFFSET, START_COARSE_OFFSET));
+    let end = at(u32_at(header, 24), offset(END_OFFSET, END_COARSE_OFFSET));
+    let loop_start = at(u32_at(header, 28), offset(LOOP_START_OFFSET, LOOP_START_COARSE_OFFSET));
+    let loop_end = at(u32_at(header, 32), offset(LOOP_END_OFFSET, LOOP_END_COARSE_OFFSET));
+    let sample_rate = u32_at(header, 36);
+    if start >= end || sample_rate == 0 {
+        return None;
+    }
+
+    let original_key = header[40];
+    let root_key = match instrument.get(ROOT_KEY) {
+        Some(key @ 0..=127) => key as u8,
+        _ if original_key <= 127 => original_key,
+        _ => 60,
+    };
+    let sum = |operator: u16| preset.get(operator).unwrap_or(0) as f32 + instrument.get(operator).unwrap_or(0) as f32;
+    let tune_cents = sum(COARSE_TUNE) * 100.0 + sum(FINE_TUNE) + header[41] as i8 as f32;
+    // attenuation and sustain are in centibels
+    let gain = 10f32.powf(-sum(ATTENUATION).max(0.0) / 200.0);
+    let sustain = 10f32.powf(-sum(SUSTAIN).clamp(0.0, 1440.0) / 200.0);
+    let seconds = |operator: u16| {
+        let timecents = instrument.get(operator).unwrap_or(DEFAULT_TIMECENTS) as f32 + preset.get(operator).unwrap_or(0) as f32;
+        2f32.powf(timecents.clamp(-12000.0, 8000.0) / 1200.0)
+    };
+    let looped = matches!(instrument.get(SAMPLE_MODES), Some(1 | 3)) && loop_start < loop_end && loop_end <= end;
+
+    Some(Region {
+        keys,
+        velocities,
+        start,
+        end,
+        loop_start,
+        loop_end,
+        looped,
+        sample_rate,
+        root_key,
+        tune_cents,
+        gain,
+        attack: seconds(ATTACK),
+        decay: seconds(DECAY),
+        sustain,
+        release: seconds(RELEASE),
+    })
+}
+
+fn intersect(a: RangeInclusive<u8>, b: RangeInclusive<u8>) -> Option<RangeInclusive<u8>> {
+    let range = *a.start().max(b.start())..=*a.end().min(b.end());
+    (!range.is_empty()).then_some(range)
+}
+
+// (id, data) of every chunk in a RIFF body, stopping at the first truncated one
+fn chunks(mut bytes: &[u8]) -> impl Iterator<Item = (&[u8; 4], &[u8])> {
+    std::iter::from_fn(move || {
+        let id: &[u8; 4] = bytes.get(..4)?.try_into().ok()?;
+        let len = u32_at(bytes.get(..8)?, 4) as usize;
+        let data = bytes.get(8..8 + len)?;
+        // chunks are padded to an even length
+        bytes = bytes.get(8 + len + len % 2..).unwrap_or_default();
+        Some((id, data))
+    })
+}
+
+fn u16_at(bytes: &[u8], at: usize) -> u16 {
+    u16::from_le_bytes([bytes[at], bytes[at + 1]])
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+}
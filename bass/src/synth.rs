@@ -0,0 +1,422 @@
+use std::{f32::consts::TAU, sync::Arc};
+
+use clap::{Args, ValueEnum};
+use rand::{Rng, SeedableRng, rngs::StdRng};
+use serde::Deserialize;
+
+use crate::{
+    error::ApiError,
+    events::Artifact,
+    format::OutputFormat,
+    manifest::Modality,
+    midi::{Instrument, Score},
+    registry::Model,
+    soundfont::{DRUM_BANK, Region, SoundFont},
+    wav::{self, SampleFormat, WAV_CONTENT_TYPE}
+};
+
+
+const SAMPLE_RATE: u32 = 44_100;
+// Notes starting later than this are left out of a preview
+const MAX_PREVIEW_SECS: f64 = 600.0;
+// Level of a single note at full velocity, leaves room for chords before the mix is turned down
+const VOICE_GAIN: f32 = 0.25;
+// Loudest sample of the mix, louder mixes are scaled down as a whole
+const PEAK: f32 = 0.9;
+
+// An audio rendering of the notes, returned next to the requested format
+#[derive(Deserialize, Args, Clone, Debug, Default)]
+#[serde(default)]
+pub struct PreviewParams {
+    /// Also render the notes to a WAV preview with this sound
+    #[arg(long, value_enum)]
+    pub preview: Option<Preset>,
+}
+
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Preset {
+    // the server's SoundFont when it has one, otherwise a patch per General MIDI family
+    Auto,
+    Sine,
+    Triangle,
+    Square,
+    Sawtooth,
+    Piano,
+    Organ,
+    Pluck,
+    // the server's SoundFont, an error when none is configured
+    Soundfont,
+}
+
+impl Preset {
+    // The oscillator patch a program is played with
+    fn patch(self, program: u8) -> Patch {
+        let (wave, attack, decay, sustain, release) = match self {
+            Self::Sine => (Wave::Sine, 0.01, 0.3, 0.8, 0.15),
+            Self::Triangle => (Wave::Triangle, 0.01, 0.3, 0.8, 0.15),
+            Self::Square => (Wave::Square, 0.01, 0.3, 0.7, 0.1),
+            Self::Sawtooth => (Wave::Sawtooth, 0.01, 0.3, 0.7, 0.1),
+            Self::Piano => (Wave::Piano, 0.003, 1.2, 0.0, 0.3),
+            Self::Organ => (Wave::Organ, 0.01, 0.1, 1.0, 0.05),
+            Self::Pluck => (Wave::Sawtooth, 0.002, 0.25, 0.0, 0.1),
+            Self::Auto | Self::Soundfont => return Self::family(program).patch(program),
+        };
+        Patch { wave, envelope: Envelope { attack, decay, sustain, release } }
+    }
+
+    // Closest patch to a General MIDI family, families are eight programs each
+    fn family(program: u8) -> Self {
+        match program / 8 {
+            0 | 1 => Self::Piano,
+            2 => Self::Organ,
+            3 => Self::Pluck,
+            4 => Self::Triangle,
+            5..=8 => Self::Sawtooth,
+            9 => Self::Sine,
+            10 => Self::Square,
+            _ => Self::Triangle,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+enum Wave {
+    Sine,
+    Triangle,
+    Square,
+    Sawtooth,
+    // decaying partials, mostly the low ones
+    Piano,
+    // drawbars at 8', 4', 2 2/3' and 2'
+    Organ,
+}
+
+impl Wave {
+    // Value at a phase in [0, 1) of a wave advancing `step` per sample. Square and sawtooth are
+    // band limited with polyBLEP, additive waves drop partials above Nyquist.
+    fn at(self, phase: f32, step: f32) -> f32 {
+        match self {
+            Self::Sine => (TAU * phase).sin(),
+            Self::Triangle => 4.0 * (phase - 0.5).abs() - 1.0,
+            Self::Square => {
+                let naive = if phase < 0.5 { 1.0 } else { -1.0 };
+                naive + blep(phase, step) - blep((phase + 0.5).fract(), step)
+            }
+            Self::Sawtooth => 2.0 * phase - 1.0 - blep(phase, step),
+            Self::Piano => partials(phase, step, &[1.0, 0.5, 0.3, 0.15, 0.1, 0.05]),
+            Self::Organ => partials(phase, step, &[1.0, 0.7, 0.5, 0.4]),
+        }
+    }
+}
+
+// Smooths the jump a naive waveform makes where its phase wraps
+fn blep(phase: f32, step: f32) -> f32 {
+    if phase < step {
+        let t = phase / step;
+        2.0 * t - t * t - 1.0
+    } else if phase > 1.0 - step {
+        let t = (phase - 1.0) / step;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+fn partials(phase: f32, step: f32, amplitudes: &[f32]) -> f32 {
+    let total: f32 = amplitudes.iter().sum();
+    amplitudes.iter().enumerate()
+        .take_while(|(n, _)| (n + 1) as f32 * step < 0.5)
+        .map(|(n, a)| a * (TAU * (n + 1) as f32 * phase).sin())
+        .sum::<f32>() / total
+}
+
+// Attack and release are linear, the decay falls toward the sustain level with `decay` as its
+// time constant
+#[derive(Clone, Copy, Debug)]
+struct Envelope {
+    attack: f32,
+    decay: f32,
+    sustain: f32,
+    release: f32,
+}
+
+impl Envelope {
+    // Level `t` seconds into a note released after `gate` seconds
+    fn at(&self, t: f32, gate: f32) -> f32 {
+        let held = |t: f32| {
+            if t < self.attack {
+                t / self.attack
+            } else {
+                self.sustain + (1.0 - self.sustain) * (-(t - self.attack) / self.decay.max(1e-3)).exp()
+            }
+        };
+        if t < gate {
+            held(t)
+        } else {
+            held(gate) * (1.0 - (t - gate) / self.release.max(1e-3)).max(0.0)
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Patch {
+    wave: Wave,
+    envelope: Envelope,
+}
+
+// A validated preview request, with the SoundFont it plays when it uses one
+#[derive(Clone)]
+pub struct Preview {
+    preset: Preset,
+    soundfont: Option<Arc<SoundFont>>,
+}
+
+impl Preview {
+    // `None` when the request asked for no preview
+    pub fn new(
+        params: PreviewParams,
+        model: &Model,
+        format: OutputFormat,
+        soundfont: Option<Arc<SoundFont>>
+    ) -> Result<Option<Self>, ApiError> {
+        let Some(preset) = params.preview else {
+            return Ok(None);
+        };
+        if model.manifest.modality != Modality::Midi {
+            return Err(ApiError::InvalidRequest(format!(
+                "model {} does not generate MIDI, preview needs a MIDI model", model.name
+            )));
+        }
+        if format == OutputFormat::Text {
+            return Err(ApiError::InvalidRequest(String::from("preview plays notes, text output has none")));
+        }
+        if preset == Preset::Soundfont && soundfont.is_none() {
+            return Err(ApiError::InvalidRequest(String::from("the server has no soundfont configured")));
+        }
+        let soundfont = soundfont.filter(|_| matches!(preset, Preset::Auto | Preset::Soundfont));
+        Ok(Some(Self { preset, soundfont }))
+    }
+
+    // Plays the score as a mono 16-bit WAV file
+    pub fn render(&self, score: &Score) -> Artifact {
+        let samples = self.play(score);
+        Artifact::binary(WAV_CONTENT_TYPE, &wav::encode(&samples, 1, SAMPLE_RATE, SampleFormat::Pcm16))
+    }
+
+    fn play(&self, score: &Score) -> Vec<f32> {
+        let rate = SAMPLE_RATE as f32;
+        let mut mix = Vec::new();
+        for (i, note) in score.notes.iter().enumerate() {
+            let start = score.seconds(note.start);
+            if start >= MAX_PREVIEW_SECS {
+                continue;
+            }
+            // a note held past the end of the preview is cut there instead of synthesized in full
+            let gate = (score.seconds(note.start + note.duration) - start).min(MAX_PREVIEW_SECS - start) as f32;
+            let gain = VOICE_GAIN * (note.velocity as f32 / 127.0).powi(2);
+            let at = (start * SAMPLE_RATE as f64) as usize;
+            match (&self.soundfont, note.instrument) {
+                (Some(font), instrument) => {
+                    let (bank, program) = match instrument {
+                        Instrument::Program(program) => (0, program as u16),
+                        Instrument::Drums => (DRUM_BANK, 0),
+                    };
+                    for region in font.regions(bank, program, note.pitch, note.velocity) {
+                        add(&mut mix, at, &sampled(font, region, note.pitch, gate, gain));
+                    }
+                }
+                (None, Instrument::Drums) => add(&mut mix, at, &drum(note.pitch, gain, i as u64)),
+                (None, Instrument::Program(program)) => {
+                    let frequency = 440.0 * 2f32.powf((note.pitch as f32 - 69.0) / 12.0);
+                    add(&mut mix, at, &oscillator(self.preset.patch(program), frequency, gate, gain));
+                }
+            }
+        }
+        mix.truncate(((MAX_PREVIEW_SECS as f32 + 5.0) * rate) as usize);
+
+        let peak = mix.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        if peak > PEAK {
+            mix.iter_mut().for_each(|s| *s *= PEAK / peak);
+        }
+        mix
+    }
+}
+
+fn add(mix: &mut Vec<f32>, at: usize, voice: &[f32]) {
+    if mix.len() < at + voice.len() {
+        mix.resize(at + voice.len(), 0.0);
+    }
+    for (out, sample) in mix[at..].iter_mut().zip(voice) {
+        *out += sample;
+    }
+}
+
+fn oscillator(patch: Patch, frequency: f32, gate: f32, gain: f32) -> Vec<f32> {
+    let rate = SAMPLE_RATE as f32;
+    let step = frequency / rate;
+    let mut phase = 0.0f32;
+    (0..((gate + patch.envelope.release) * rate) as usize)
+        .map(|i| {
+            let sample = patch.wave.at(phase, step) * patch.envelope.at(i as f32 / rate, gate) * gain;
+            phase = (phase + step).fract();
+            sample
+        })
+        .collect()
+}
+
+// A sample played back at the note's pitch with linear interpolation, looping while the note
+// sounds when the zone loops
+fn sampled(font: &SoundFont, region: &Region, key: u8, gate: f32, gain: f32) -> Vec<f32> {
+    let rate = SAMPLE_RATE as f32;
+    // SoundFont decay times run until the sustain level, about five time constants
+    let envelope = Envelope { attack: region.attack, decay: region.decay / 5.0, sustain: region.sustain, release: region.release };
+    let cents = (key as f32 - region.root_key as f32) * 100.0 + region.tune_cents;
+    let step = 2f64.powf(cents as f64 / 1200.0) * region.sample_rate as f64 / SAMPLE_RATE as f64;
+    let loop_len = (region.loop_end - region.loop_start) as f64;
+
+    let mut position = region.start as f64;
+    let mut out = Vec::new();
+    for i in 0..((gate + envelope.release) * rate) as usize {
+        if region.looped {
+            while position >= region.loop_end as f64 {
+                position -= loop_len;
+            }
+        } else if position >= (region.end - 1) as f64 {
+            break;
+        }
+        let index = position as usize;
+        let next = match index + 1 {
+            next if region.looped && next >= region.loop_end => region.loop_start,
+            next => next.min(region.end - 1),
+        };
+        let (a, b) = (font.samples[index] as f32, font.samples[next] as f32);
+        let sample = (a + (b - a) * position.fract() as f32) / 32768.0;
+        out.push(sample * region.gain * envelope.at(i as f32 / rate, gate) * gain);
+        position += step;
+    }
+    out
+}
+
+// Synthesized General MIDI percussion, one shots that ignore the note's length
+fn drum(key: u8, gain: f32, seed: u64) -> Vec<f32> {
+    let rate = SAMPLE_RATE as f32;
+    let mut rng = StdRng::seed_from_u64(seed);
+    // (length, decay time constant, tone frequency or none, share of noise)
+    let (length, decay, tone, noise): (f32, f32, Option<f32>, f32) = match key {
+        35 | 36 => (0.5, 0.15, None, 0.0),
+        37..=40 => (0.3, 0.08, Some(180.0), 0.6),
+        42 | 44 => (0.15, 0.02, None, 1.0),
+        46 => (0.5, 0.15, None, 1.0),
+        41 | 43 | 45 | 47 | 48 | 50 => (0.6, 0.2, Some(80.0 + (key - 41) as f32 * 15.0), 0.1),
+        49 | 51 | 52 | 53 | 55 | 57 | 59 => (1.5, 0.5, None, 1.0),
+        _ => (0.2, 0.05, Some(400.0), 0.5),
+    };
+    let mut phase = 0.0f32;
+    let mut last = 0.0f32;
+    (0..(length * rate) as usize)
+        .map(|i| {
+            let t = i as f32 / rate;
+            // kicks sweep down from 150 Hz
+            let frequency = tone.unwrap_or(50.0 + 100.0 * (-t / 0.03).exp());
+            phase = (phase + frequency / rate).fract();
+            // cymbals and hats get the brighter, differentiated noise
+            let white: f32 = rng.random_range(-1.0..1.0);
+            let hiss = if tone.is_none() { (white - last) * 0.5 } else { white };
+            last = white;
+            let body = if noise < 1.0 { (TAU * phase).sin() } else { 0.0 };
+            (body * (1.0 - noise) + hiss * noise) * (-t / decay).exp() * gain
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::Note;
+
+    fn score(instrument: Instrument, pitch: u8) -> Score {
+        let mut score = Score::new(480);
+        score.notes.push(Note { start: 0, duration: 480, pitch, velocity: 127, instrument });
+        score
+    }
+
+    // Rising zero crossings per second
+    fn frequency(samples: &[f32]) -> f32 {
+        let crossings = samples.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        crossings as f32 * SAMPLE_RATE as f32 / samples.len() as f32
+    }
+
+    #[test]
+    fn oscillators_play_the_note() {
+        for preset in [Preset::Sine, Preset::Square, Preset::Sawtooth] {
+            let preview = Preview { preset, soundfont: None };
+            let samples = preview.play(&score(Instrument::Program(0), 69));
+            // half a second at 120 BPM, then the release
+            assert!(samples.len() > SAMPLE_RATE as usize / 2);
+            let held = &samples[..SAMPLE_RATE as usize / 2];
+            assert!((frequency(held) - 440.0).abs() < 5.0, "{:?} plays {} Hz", preset, frequency(held));
+            assert!(held.iter().all(|s| s.abs() <= PEAK));
+        }
+    }
+
+    // A SoundFont with one preset whose single zone loops a 100 sample sine, rooted at A4
+    fn soundfont(sample_rate: u32) -> Vec<u8> {
+        fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+            let mut out = id.to_vec();
+            out.extend_from_slice(&(data.len() as u32).to_le_bytes());
+            out.extend_from_slice(data);
+            out
+        }
+        fn list(kind: &[u8], chunks: &[Vec<u8>]) -> Vec<u8> {
+            let mut data = kind.to_vec();
+            chunks.iter().for_each(|c| data.extend_from_slice(c));
+            chunk(b"LIST", &data)
+        }
+        fn record(name: &str, fields: &[u8]) -> Vec<u8> {
+            let mut out = name.as_bytes().to_vec();
+            out.resize(20, 0);
+            out.extend_from_slice(fields);
+            out
+        }
+        let le = |values: &[u32], widths: &[usize]| -> Vec<u8> {
+            values.iter().zip(widths).flat_map(|(v, &w)| v.to_le_bytes()[..w].to_vec()).collect()
+        };
+
+        let samples: Vec<u8> = (0..146)
+            .map(|i| if i < 100 { ((TAU * i as f32 / 100.0).sin() * 16000.0) as i16 } else { 0 })
+            .flat_map(i16::to_le_bytes)
+            .collect();
+        let phdr = [record("Sine", &le(&[0, 0, 0, 0, 0, 0], &[2, 2, 2, 4, 4, 4])), record("EOP", &le(&[0, 0, 1, 0, 0, 0], &[2, 2, 2, 4, 4, 4]))].concat();
+        let pbag = le(&[0, 0, 1, 0], &[2, 2, 2, 2]);
+        let pgen = le(&[41, 0, 0, 0], &[2, 2, 2, 2]);
+        let inst = [record("Sine", &le(&[0], &[2])), record("EOI", &le(&[1], &[2]))].concat();
+        let ibag = le(&[0, 0, 3, 0], &[2, 2, 2, 2]);
+        let igen = le(&[54, 1, 58, 69, 53, 0, 0, 0], &[2, 2, 2, 2, 2, 2, 2, 2]);
+        let shdr = [
+            record("Sine", &le(&[0, 100, 0, 100, sample_rate, 69, 0, 0, 1], &[4, 4, 4, 4, 4, 1, 1, 2, 2])),
+            record("EOS", &[0; 26]),
+        ].concat();
+
+        let mut body = b"sfbk".to_vec();
+        body.extend(list(b"sdta", &[chunk(b"smpl", &samples)]));
+        body.extend(list(b"pdta", &[
+            chunk(b"phdr", &phdr), chunk(b"pbag", &pbag), chunk(b"pmod", &[0; 10]), chunk(b"pgen", &pgen),
+            chunk(b"inst", &inst), chunk(b"ibag", &ibag), chunk(b"imod", &[0; 10]), chunk(b"igen", &igen),
+            chunk(b"shdr", &shdr),
+        ]));
+        chunk(b"RIFF", &body)
+    }
+
+    #[test]
+    fn soundfont_samples_are_pitched_from_their_root_key() {
+        // 100 samples per cycle at 44 kHz is 441 Hz at the root key
+        let font = Arc::new(SoundFont::parse(&soundfont(44_100)).unwrap());
+        let preview = Preview { preset: Preset::Soundfont, soundfont: Some(font) };
+        for (pitch, expected) in [(69, 441.0), (81, 882.0)] {
+            let samples = preview.play(&score(Instrument::Program(0), pitch));
+            let held = &samples[..SAMPLE_RATE as usize / 2];
+            assert!((frequency(held) - expected).abs() < 5.0, "key {} plays {} Hz", pitch, frequency(held));
+        }
+    }
+}
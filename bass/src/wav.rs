@@ -0,0 +1,83 @@
+use clap::ValueEnum;
+use serde::Deserialize;
+
+
+pub const WAV_CONTENT_TYPE: &str = "audio/wav";
+
+// WAVE_FORMAT_PCM and WAVE_FORMAT_IEEE_FLOAT
+const FORMAT_PCM: u16 = 1;
+const FORMAT_FLOAT: u16 = 3;
+
+// How samples are stored in a WAV file
+#[derive(Deserialize, ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SampleFormat {
+    // 16-bit integer PCM
+    #[default]
+    Pcm16,
+    // 24-bit integer PCM
+    Pcm24,
+    // 32-bit IEEE float, unclipped
+    Float32,
+}
+
+impl SampleFormat {
+    pub fn bits(self) -> u16 {
+        match self {
+            Self::Pcm16 => 16,
+            Self::Pcm24 => 24,
+            Self::Float32 => 32,
+        }
+    }
+}
+
+// Writes interleaved samples in [-1, 1] as a RIFF WAVE file. Integer formats clip what is out
+// of range.
+pub fn encode(samples: &[f32], channels: u16, sample_rate: u32, format: SampleFormat) -> Vec<u8> {
+    let block_align = channels * format.bits() / 8;
+    let data_len = samples.len() as u32 * (format.bits() / 8) as u32;
+    // float files carry a cbSize field and a fact chunk with the frame count
+    let float = format == SampleFormat::Float32;
+    let fmt_len: u32 = if float { 18 } else { 16 };
+    let fact_len: u32 = if float { 12 } else { 0 };
+
+    let mut out = Vec::with_capacity(44 + data_len as usize);
+    out.extend_from_slice(b"RIFF");
+    out.extend_from_slice(&(4 + 8 + fmt_len + fact_len + 8 + data_len).to_le_bytes());
+    out.extend_from_slice(b"WAVE");
+
+    out.extend_from_slice(b"fmt ");
+    out.extend_from_slice(&fmt_len.to_le_bytes());
+    out.extend_from_slice(&(if float { FORMAT_FLOAT } else { FORMAT_PCM }).to_le_bytes());
+    out.extend_from_slice(&channels.to_le_bytes());
+    out.extend_from_slice(&sample_rate.to_le_bytes());
+    out.extend_from_slice(&(sample_rate * block_align as u32).to_le_bytes());
+    out.extend_from_slice(&block_align.to_le_bytes());
+    out.extend_from_slice(&format.bits().to_le_bytes());
+    if float {
+        out.extend_from_slice(&0u16.to_le_bytes());
+        out.extend_from_slice(b"fact");
+        out.extend_from_slice(&4u32.to_le_bytes());
+        out.extend_from_slice(&(samples.len() as u32 / channels as u32).to_le_bytes());
+    }
+
+    out.extend_from_slice(b"data");
+    out.extend_from_slice(&data_len.to_le_bytes());
+    for &sample in samples {
+        match format {
+            SampleFormat::Pcm16 => out.extend_from_slice(&(quantize(sample, format) as i16).to_le_bytes()),
+            SampleFormat::Pcm24 => out.extend_from_slice(&quantize(sample, format).to_le_bytes()[..3]),
+            SampleFormat::Float32 => out.extend_from_slice(&sample.to_le_bytes()),
+        }
+    }
+    out
+}
+
+// A sample in [-1, 1] as an integer of the format's width, clipped. Float samples keep their bits.
+pub fn quantize(sample: f32, format: SampleFormat) -> i32 {
+    match format {
+        SampleFormat::Pcm16 => (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i32,
+        SampleFormat::Pcm24 => (sample.clamp(-1.0, 1.0) * 8_388_607.0).round() as i32,
+        SampleFormat::Float32 => sample.to_bits() as i32,
+    }
+}
@@ -16,21 +16,39 @@ use serde::{Serialize, Deserialize};
 use anyhow::Error;
 
 use std::{
-    fs, net::TcpStream, path::PathBuf
+    fs, path::PathBuf, time::Duration
 };
 
+// Event protocol version this plugin understands, see bass `GET /version`
+const PROTOCOL_VERSION: u32 = 1;
+
 #[derive(Debug, Serialize)]
 struct GenerationPayload<'a> {
     prompt: &'a str,
     negative_prompt: &'a str,
     #[serde(rename = "client_output_path")]
     filename: &'a str,
+    output_format: &'a str,
+    // answer with the file itself rather than an event stream
+    stream: bool,
 }
 
 #[derive(Debug, Deserialize)]
 struct GenerationResponse {
 }
 
+#[derive(Debug, Deserialize)]
+struct VersionResponse {
+    server: String,
+    protocol: u32,
+}
+
+#[derive(Debug, Deserialize)]
+struct ReadyResponse {
+    ready: bool,
+    default_model: String,
+}
+
 fn api_url(endpoint: &str) -> Result<Url, Error> {
     let mut url_base: String = env::var("API_URL").expect("API_URL must be defined");
     url_base.push_str(endpoint);
@@ -42,9 +60,22 @@ fn api_url(endpoint: &str) -> Result<Url, Error> {
 
 pub fn check_backend() -> Result<(), Error> {
     info!("checking backend connection...");
-    let port: u16 = 8000;
-    let url: String = env::var("API_URL").expect("API_URL must be defined");
-    TcpStream::connect((url, port))?;
+    let client = Client::builder().timeout(Duration::from_secs(5)).build()?;
+
+    let version: VersionResponse = client.get(api_url("version")?).send()?.error_for_status()?.json()?;
+    if version.protocol != PROTOCOL_VERSION {
+        anyhow::bail!(
+            "backend {} speaks protocol {}, this plugin needs {}",
+            version.server, version.protocol, PROTOCOL_VERSION
+        );
+    }
+
+    // /ready answers 503 with the same body while the model is unavailable
+    let ready: ReadyResponse = client.get(api_url("ready")?).send()?.json()?;
+    if !ready.ready {
+        anyhow::bail!("backend is up but model {} is not loaded", ready.default_model);
+    }
+    info!("backend {} ready with model {}", version.server, ready.default_model);
     Ok(())
 }
 
@@ -65,6 +96,8 @@ pub fn request_response_iterator(prompt: String) -> impl Iterator<Item= Result<S
             prompt: &prompt[..],
             negative_prompt: "Low quality, average quality".into(),
             filename: "",
+            output_format: "midi",
+            stream: false,
         };
         info!("built request payload.");
         tx.try_send(Ok("66.0".into()))?;